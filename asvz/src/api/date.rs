@@ -0,0 +1,44 @@
+//! Serde helpers for the dates of the api.
+//!
+//! Most dates are RFC 3339, but some endpoints leave out the colon in the offset.
+
+use chrono::{DateTime, FixedOffset, ParseError};
+use serde::{de, Deserialize, Deserializer, Serializer};
+
+pub fn parse(date: &str) -> Result<DateTime<FixedOffset>, ParseError> {
+    DateTime::parse_from_rfc3339(date)
+        .or_else(|_| DateTime::parse_from_str(date, "%Y-%m-%dT%H:%M:%S%z"))
+}
+
+pub fn serialize<S: Serializer>(date: &DateTime<FixedOffset>, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_str(&date.to_rfc3339())
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<DateTime<FixedOffset>, D::Error> {
+    let date = String::deserialize(d)?;
+    parse(&date).map_err(|err| de::Error::custom(format!("invalid date {:?}: {}", date, err)))
+}
+
+pub mod option {
+    use chrono::{DateTime, FixedOffset};
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        date: &Option<DateTime<FixedOffset>>,
+        s: S,
+    ) -> Result<S::Ok, S::Error> {
+        match date {
+            Some(date) => super::serialize(date, s),
+            None => s.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        d: D,
+    ) -> Result<Option<DateTime<FixedOffset>>, D::Error> {
+        #[derive(Deserialize)]
+        struct Wrapper(#[serde(deserialize_with = "super::deserialize")] DateTime<FixedOffset>);
+
+        Ok(Option::<Wrapper>::deserialize(d)?.map(|Wrapper(date)| date))
+    }
+}
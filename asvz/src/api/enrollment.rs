@@ -1,3 +1,4 @@
+use chrono::{DateTime, FixedOffset};
 use serde::Deserialize;
 use serde::Serialize;
 
@@ -11,5 +12,33 @@ pub struct EnrollmentData {
 #[serde(rename_all = "camelCase")]
 pub struct Data {
     pub place_number: i64,
-    pub status_id: i64,
+    pub status_id: EnrollmentStatus,
+}
+
+int_enum! {
+    EnrollmentStatus {
+        Enrolled = 2,
+    }
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MyEnrollments {
+    pub data: Vec<Enrollment>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Enrollment {
+    pub id: i64,
+    pub lesson_id: i64,
+    pub sport_name: String,
+    pub title: String,
+    #[serde(with = "crate::api::date")]
+    pub lesson_start: DateTime<FixedOffset>,
+    #[serde(with = "crate::api::date")]
+    pub lesson_end: DateTime<FixedOffset>,
+    pub location: Option<String>,
+    pub place_number: i64,
+    pub status_id: EnrollmentStatus,
 }
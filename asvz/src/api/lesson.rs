@@ -1,4 +1,3 @@
-use chrono::ParseError;
 use chrono::{DateTime, FixedOffset};
 use serde::Deserialize;
 use serde::Serialize;
@@ -10,40 +9,28 @@ pub struct LessonData {
     pub data: Data,
 }
 
-impl LessonData {
-    pub fn str_to_datetime(date: &str) -> Result<DateTime<FixedOffset>, ParseError> {
-        DateTime::parse_from_str(date, "%Y-%m-%dT%H:%M:%S%z")
-    }
-    pub fn str_to_timestamp(date: &str) -> Result<i64, ParseError> {
-        Self::str_to_datetime(date).map(|d| {
-            let timestamp = d.timestamp();
-            assert!(timestamp >= 0);
-            timestamp
-        })
-    }
-    pub fn enroll_until_timestamp(&self) -> Result<i64, ParseError> {
-        Self::str_to_timestamp(&self.data.enrollment_until)
-    }
-    pub fn enroll_from_timestamp(&self) -> Result<i64, ParseError> {
-        Self::str_to_timestamp(&self.data.enrollment_from)
-    }
-}
-
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Data {
     pub event_id: i64,
     #[serde(rename = "type")]
-    pub type_field: i64,
+    pub type_field: EventType,
     pub enrollment_enabled: bool,
-    pub enrollment_from: String,
-    pub enrollment_until: String,
-    pub cancelation_until: String,
+    #[serde(with = "crate::api::date")]
+    pub enrollment_from: DateTime<FixedOffset>,
+    #[serde(with = "crate::api::date")]
+    pub enrollment_until: DateTime<FixedOffset>,
+    #[serde(with = "crate::api::date")]
+    pub cancelation_until: DateTime<FixedOffset>,
     pub lottery_duration: i64,
-    pub starts: String,
-    pub ends: String,
-    pub cancellation_date: Value,
-    pub cancellation_reason: Value,
+    #[serde(with = "crate::api::date")]
+    pub starts: DateTime<FixedOffset>,
+    #[serde(with = "crate::api::date")]
+    pub ends: DateTime<FixedOffset>,
+    #[serde(default, with = "crate::api::date::option")]
+    pub cancellation_date: Option<DateTime<FixedOffset>>,
+    #[serde(default)]
+    pub cancellation_reason: Option<String>,
     pub participants_min: Option<i64>,
     pub participants_max: i64,
     pub participant_count: i64,
@@ -54,23 +41,25 @@ pub struct Data {
     pub sub_lessons: Vec<Value>,
     pub is_live_stream: bool,
     pub id: i64,
-    pub base_type: i64,
-    pub status: i64,
+    pub base_type: EventType,
+    pub status: LessonStatus,
     pub number: String,
     pub sport_id: i64,
     pub sport_name: String,
     pub sport_url: String,
     pub title: String,
     pub location: Option<String>,
-    pub web_registration_type: i64,
-    pub meeting_point_info: Value,
-    pub meeting_point_coordinates: Value,
+    pub web_registration_type: RegistrationType,
+    #[serde(default)]
+    pub meeting_point_info: Option<String>,
+    pub meeting_point_coordinates: Option<Coordinates>,
     pub tl_comment_active: bool,
     pub tl_comment_active_info: bool,
-    pub tl_comment: Value,
+    #[serde(default)]
+    pub tl_comment: Option<String>,
     pub language: Language,
     pub language_info: String,
-    pub level_id: i64,
+    pub level_id: Level,
     pub level_info: String,
     pub level_e: bool,
     pub level_m: bool,
@@ -80,6 +69,68 @@ pub struct Data {
     pub change_date: String,
 }
 
+impl Data {
+    /// A cancelled lesson has a cancellation date. The status codes are not known well enough
+    /// to rely on them.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancellation_date.is_some()
+    }
+
+    pub fn cancellation_reason(&self) -> Option<&str> {
+        self.cancellation_reason.as_deref()
+    }
+
+    /// Places of lottery lessons are drawn after the enrollment closes.
+    pub fn is_lottery(&self) -> bool {
+        self.lottery_duration > 0
+    }
+}
+
+int_enum! {
+    LessonStatus {
+        Active = 1,
+        Cancelled = 2,
+    }
+}
+
+int_enum! {
+    /// Used for both the type of the lesson and the type of the event it belongs to.
+    EventType {
+        Lesson = 1,
+        Course = 2,
+        Event = 3,
+    }
+}
+
+int_enum! {
+    RegistrationType {
+        Online = 1,
+        OnSite = 2,
+        NotRequired = 3,
+    }
+}
+
+int_enum! {
+    Level {
+        All = 1,
+        Beginner = 2,
+        Intermediate = 3,
+        Advanced = 4,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Coordinates {
+    LatLng {
+        #[serde(alias = "lat")]
+        latitude: f64,
+        #[serde(alias = "lng", alias = "lon")]
+        longitude: f64,
+    },
+    Other(Value),
+}
+
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Instructor {
@@ -1,3 +1,42 @@
+/// Declares an enum for an integer field of the api. Values this crate
+/// doesn't know about end up in the `Unknown` variant.
+macro_rules! int_enum {
+    ($(#[$meta:meta])* $name:ident { $($(#[$var_meta:meta])* $variant:ident = $value:literal,)* }) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+        #[serde(from = "i64", into = "i64")]
+        pub enum $name {
+            $($(#[$var_meta])* $variant,)*
+            Unknown(i64),
+        }
+
+        impl From<i64> for $name {
+            fn from(value: i64) -> Self {
+                match value {
+                    $($value => Self::$variant,)*
+                    value => Self::Unknown(value),
+                }
+            }
+        }
+
+        impl From<$name> for i64 {
+            fn from(value: $name) -> Self {
+                match value {
+                    $($name::$variant => $value,)*
+                    $name::Unknown(value) => value,
+                }
+            }
+        }
+
+        impl Default for $name {
+            fn default() -> Self {
+                Self::Unknown(0)
+            }
+        }
+    };
+}
+
+pub mod date;
 pub mod enrollment;
 pub mod lesson;
 pub mod search;
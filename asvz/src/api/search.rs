@@ -1,5 +1,6 @@
 use std::str::FromStr;
 
+use chrono::{DateTime, FixedOffset};
 use lazy_static::lazy_static;
 use regex::Regex;
 use serde::Deserialize;
@@ -52,8 +53,8 @@ pub struct Result {
     pub facility_type: Vec<i64>,
     #[serde(rename = "facility_type_name")]
     pub facility_type_name: Vec<String>,
-    #[serde(rename = "from_date")]
-    pub from_date: String,
+    #[serde(rename = "from_date", with = "crate::api::date")]
+    pub from_date: DateTime<FixedOffset>,
     #[serde(rename = "general_type")]
     pub general_type: Vec<i64>,
     #[serde(rename = "general_type_name")]
@@ -67,10 +68,10 @@ pub struct Result {
     pub niveau_short_name: String,
     #[serde(rename = "oe_enabled")]
     pub oe_enabled: bool,
-    #[serde(rename = "oe_from_date")]
-    pub oe_from_date: String,
-    #[serde(rename = "oe_to_date")]
-    pub oe_to_date: String,
+    #[serde(rename = "oe_from_date", with = "crate::api::date")]
+    pub oe_from_date: DateTime<FixedOffset>,
+    #[serde(rename = "oe_to_date", with = "crate::api::date")]
+    pub oe_to_date: DateTime<FixedOffset>,
     #[serde(rename = "places_max")]
     pub places_max: i64,
     pub searchable: bool,
@@ -78,8 +79,8 @@ pub struct Result {
     #[serde(rename = "sport_name")]
     pub sport_name: String,
     pub title: String,
-    #[serde(rename = "to_date")]
-    pub to_date: String,
+    #[serde(rename = "to_date", with = "crate::api::date")]
+    pub to_date: DateTime<FixedOffset>,
     #[serde(rename = "type")]
     pub type_field: String,
     pub url: String,
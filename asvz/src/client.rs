@@ -0,0 +1,75 @@
+use reqwest_middleware::ClientWithMiddleware;
+use url::Url;
+
+use crate::error::AsvzError;
+use crate::lesson::LessonID;
+
+/// The base urls of the ASVZ hosts.
+/// They can be changed to point the client at a staging instance or a local stand-in server.
+#[derive(Debug, Clone)]
+pub struct AsvzUrls {
+    /// The main website, used for the event and sport search.
+    pub www: Url,
+    /// The "Schalter", which hosts the lesson and enrollment api.
+    pub schalter: Url,
+    /// The login and OpenID Connect server.
+    pub auth: Url,
+}
+
+impl AsvzUrls {
+    /// The page of a lesson on the "Schalter", as it would be opened in a browser.
+    pub fn lesson_page(&self, id: &LessonID) -> Url {
+        self.schalter
+            .join(&format!("tn/lessons/{}", id.as_str()))
+            .expect("A lesson id is a valid path")
+    }
+}
+
+impl Default for AsvzUrls {
+    fn default() -> Self {
+        Self {
+            www: Url::parse("https://www.asvz.ch/").unwrap(),
+            schalter: Url::parse("https://schalter.asvz.ch/").unwrap(),
+            auth: Url::parse("https://auth.asvz.ch/").unwrap(),
+        }
+    }
+}
+
+/// A client for the ASVZ api.
+///
+/// It keeps the cookies of the http client, so a client should only be used for a single user.
+#[derive(Debug, Clone)]
+pub struct AsvzClient {
+    client: ClientWithMiddleware,
+    urls: AsvzUrls,
+}
+
+impl AsvzClient {
+    pub fn new(client: ClientWithMiddleware) -> Self {
+        Self::with_urls(client, AsvzUrls::default())
+    }
+
+    pub fn with_urls(client: ClientWithMiddleware, urls: AsvzUrls) -> Self {
+        Self { client, urls }
+    }
+
+    pub fn urls(&self) -> &AsvzUrls {
+        &self.urls
+    }
+
+    pub(crate) fn http(&self) -> &ClientWithMiddleware {
+        &self.client
+    }
+
+    pub(crate) fn www_url(&self, path: &str) -> Result<Url, AsvzError> {
+        Ok(self.urls.www.join(path)?)
+    }
+
+    pub(crate) fn schalter_url(&self, path: &str) -> Result<Url, AsvzError> {
+        Ok(self.urls.schalter.join(path)?)
+    }
+
+    pub(crate) fn auth_url(&self, path: &str) -> Result<Url, AsvzError> {
+        Ok(self.urls.auth.join(path)?)
+    }
+}
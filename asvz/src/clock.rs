@@ -0,0 +1,132 @@
+use chrono::{DateTime, DurationRound, FixedOffset, Utc};
+use reqwest::header::DATE;
+use tokio::time::Instant;
+use tracing::{instrument, trace, warn};
+
+use crate::client::AsvzClient;
+use crate::error::AsvzError;
+
+/// The offset of the ASVZ server clock to the local clock (server time - local time),
+/// known to lie between `lower` and `upper`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockOffset {
+    lower: chrono::Duration,
+    upper: chrono::Duration,
+}
+
+impl ClockOffset {
+    /// A sample of a request sent at `sent` and answered with the server date `date` at `received`.
+    /// The `Date` header is truncated to seconds, so the server time was
+    /// somewhere in `[date, date + 1s)` while the request was on its way.
+    fn from_sample(sent: DateTime<Utc>, received: DateTime<Utc>, date: DateTime<Utc>) -> Self {
+        Self {
+            lower: date - received,
+            upper: date + chrono::Duration::seconds(1) - sent,
+        }
+    }
+
+    /// Combines two measurements. If they contradict each other,
+    /// e.g. because the server clock jumped, the newer one wins.
+    fn intersect(self, newer: Self) -> Self {
+        let intersection = Self {
+            lower: self.lower.max(newer.lower),
+            upper: self.upper.min(newer.upper),
+        };
+        if intersection.lower <= intersection.upper {
+            intersection
+        } else {
+            warn!("Inconsistent clock samples {:?} and {:?}", self, newer);
+            newer
+        }
+    }
+
+    pub fn estimate(&self) -> chrono::Duration {
+        self.lower + (self.upper - self.lower) / 2
+    }
+
+    /// How far the real offset can be from the estimate.
+    pub fn uncertainty(&self) -> chrono::Duration {
+        (self.upper - self.lower) / 2
+    }
+
+    /// The local time at which the server clock shows `server_time`.
+    pub fn to_local(&self, server_time: DateTime<FixedOffset>) -> DateTime<Utc> {
+        server_time.with_timezone(&Utc) - self.estimate()
+    }
+
+    /// The instant at which the server clock shows `server_time`,
+    /// or now if that time already passed.
+    pub fn to_instant(&self, server_time: DateTime<FixedOffset>) -> Instant {
+        let now = Instant::now();
+        match (self.to_local(server_time) - Utc::now()).to_std() {
+            Ok(wait) => now + wait,
+            Err(_) => now,
+        }
+    }
+}
+
+impl Default for ClockOffset {
+    /// Assumes the clocks are in sync.
+    fn default() -> Self {
+        Self {
+            lower: chrono::Duration::zero(),
+            upper: chrono::Duration::zero(),
+        }
+    }
+}
+
+impl AsvzClient {
+    /// Estimates the offset to the server clock from the `Date` headers of `samples` requests.
+    ///
+    /// The first request gives an offset to within a second plus the round trip time.
+    /// Every further request is timed to reach the server just as its clock should
+    /// tick to the next second, so which second it reports roughly halves the uncertainty.
+    #[instrument(skip(self))]
+    pub async fn clock_offset(&self, samples: usize) -> Result<ClockOffset, AsvzError> {
+        let url = self.schalter_url("")?;
+        let mut offset: Option<ClockOffset> = None;
+        let mut round_trip = chrono::Duration::zero();
+        for _ in 0..samples {
+            if let Some(offset) = &offset {
+                let server_now = Utc::now() + offset.estimate();
+                let next_tick = server_now
+                    .duration_trunc(chrono::Duration::seconds(1))
+                    .map_err(|_| AsvzError::UnexpectedFormat)?
+                    + chrono::Duration::seconds(1);
+                let mut send_at = next_tick - offset.estimate() - round_trip / 2;
+                if send_at < Utc::now() {
+                    send_at += chrono::Duration::seconds(1);
+                }
+                if let Ok(wait) = (send_at - Utc::now()).to_std() {
+                    tokio::time::sleep(wait).await;
+                }
+            }
+
+            let sent = Utc::now();
+            let response = self.http().head(url.clone()).send().await?;
+            let received = Utc::now();
+            round_trip = received - sent;
+
+            let date = response
+                .headers()
+                .get(DATE)
+                .and_then(|date| date.to_str().ok())
+                .and_then(|date| DateTime::parse_from_rfc2822(date).ok())
+                .ok_or(AsvzError::UnexpectedFormat)?
+                .with_timezone(&Utc);
+            let sample = ClockOffset::from_sample(sent, received, date);
+            trace!("clock sample {:?}", sample);
+            offset = Some(match offset {
+                Some(offset) => offset.intersect(sample),
+                None => sample,
+            });
+        }
+        let offset = offset.unwrap_or_default();
+        trace!(
+            "clock offset {}ms +- {}ms",
+            offset.estimate().num_milliseconds(),
+            offset.uncertainty().num_milliseconds()
+        );
+        Ok(offset)
+    }
+}
@@ -0,0 +1,220 @@
+use chrono::Local;
+use reqwest::StatusCode;
+use tracing::{instrument, trace};
+
+use crate::api::enrollment::{Enrollment, EnrollmentData, MyEnrollments};
+use crate::api::lesson::LessonError;
+use crate::client::AsvzClient;
+use crate::error::AsvzError;
+use crate::lesson::LessonID;
+
+/// The outcome of a single enrollment attempt.
+#[derive(Debug)]
+pub enum EnrollmentResponse {
+    /// The data is `None` if the body of the response could not be decoded.
+    Enrolled(Option<EnrollmentData>),
+    Rejected(EnrollmentRejection),
+    TooManyRequests,
+    Unexpected(StatusCode),
+}
+
+/// Why the server refused an enrollment, read from the messages of the error body.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EnrollmentRejection {
+    Full,
+    NotOpenYet,
+    Closed,
+    AlreadyEnrolled,
+    MembershipExpired,
+    /// The user is already enrolled in a lesson at the same time.
+    OverlappingLesson,
+    /// The lesson requires skills the user doesn't have, e.g. a swimming test.
+    SkillsMissing,
+    Cancelled,
+    /// The messages of an unknown rejection, empty if the body could not be decoded.
+    Unknown(String),
+}
+
+/// The messages of the rejections in english and german, compared case-insensitively.
+/// Only whole messages are matched, other messages are `Unknown`.
+const REJECTION_MESSAGES: &[(&str, EnrollmentRejection)] = &[
+    ("Lesson is full", EnrollmentRejection::Full),
+    ("Lektion ist ausgebucht.", EnrollmentRejection::Full),
+    (
+        "Enrollment is not open yet",
+        EnrollmentRejection::NotOpenYet,
+    ),
+    (
+        "Die Anmeldung ist noch nicht geöffnet.",
+        EnrollmentRejection::NotOpenYet,
+    ),
+    ("Enrollment is closed", EnrollmentRejection::Closed),
+    (
+        "Die Anmeldung ist geschlossen.",
+        EnrollmentRejection::Closed,
+    ),
+    ("Already enrolled", EnrollmentRejection::AlreadyEnrolled),
+    (
+        "Sie sind bereits für diese Lektion angemeldet.",
+        EnrollmentRejection::AlreadyEnrolled,
+    ),
+    (
+        "Your membership has expired",
+        EnrollmentRejection::MembershipExpired,
+    ),
+    (
+        "Ihre Mitgliedschaft ist abgelaufen.",
+        EnrollmentRejection::MembershipExpired,
+    ),
+    (
+        "You are already enrolled in a lesson at the same time",
+        EnrollmentRejection::OverlappingLesson,
+    ),
+    (
+        "Sie sind bereits für eine Lektion zur gleichen Zeit angemeldet.",
+        EnrollmentRejection::OverlappingLesson,
+    ),
+    (
+        "Required skills are missing",
+        EnrollmentRejection::SkillsMissing,
+    ),
+    (
+        "Die erforderlichen Voraussetzungen fehlen.",
+        EnrollmentRejection::SkillsMissing,
+    ),
+    ("Lesson is cancelled", EnrollmentRejection::Cancelled),
+    (
+        "Die Lektion wurde abgesagt.",
+        EnrollmentRejection::Cancelled,
+    ),
+];
+
+impl EnrollmentRejection {
+    pub fn from_message(message: &str) -> Self {
+        let message = message.trim();
+        let lowercase = message.to_lowercase();
+        REJECTION_MESSAGES
+            .iter()
+            .find(|(known, _)| known.to_lowercase() == lowercase)
+            .map(|(_, rejection)| rejection.clone())
+            .unwrap_or_else(|| Self::Unknown(message.to_string()))
+    }
+
+    /// Takes the first error with a known message.
+    pub fn from_error(error: Option<LessonError>) -> Self {
+        let messages: Vec<_> = error
+            .map(|error| {
+                error
+                    .errors
+                    .into_iter()
+                    .map(|error| error.message)
+                    .collect()
+            })
+            .unwrap_or_default();
+        messages
+            .iter()
+            .map(|message| Self::from_message(message))
+            .find(|rejection| !matches!(rejection, Self::Unknown(_)))
+            .unwrap_or_else(|| Self::Unknown(messages.join(", ")))
+    }
+
+    /// Whether trying again later can succeed. Unknown rejections are retried,
+    /// they used to mean the lesson is full.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, Self::Full | Self::NotOpenYet | Self::Unknown(_))
+    }
+}
+
+/// The outcome of removing an enrollment.
+#[derive(Debug)]
+pub enum UnenrollmentResponse {
+    Unenrolled,
+    /// There was no enrollment to remove.
+    NotEnrolled,
+    /// The enrollment can no longer be cancelled, e.g. because the deadline passed.
+    Rejected,
+    TooManyRequests,
+    Unexpected(StatusCode),
+}
+
+impl AsvzClient {
+    #[instrument(skip(self, token))]
+    pub async fn enroll(
+        &self,
+        token: &str,
+        id: &LessonID,
+    ) -> Result<EnrollmentResponse, AsvzError> {
+        let url = self.schalter_url(&format!("tn-api/api/Lessons/{}/Enrollment", id.as_str()))?;
+        let response = self
+            .http()
+            .post(url)
+            .bearer_auth(token)
+            .json(&())
+            .send()
+            .await?;
+        trace!("enroll response with status code {}", response.status());
+
+        match response.status() {
+            StatusCode::CREATED => {
+                let enrollment_data = response.json::<EnrollmentData>().await.ok();
+                trace!("enrollment_data: {:?}", enrollment_data);
+                Ok(EnrollmentResponse::Enrolled(enrollment_data))
+            }
+            StatusCode::UNPROCESSABLE_ENTITY => {
+                let error = response.json::<LessonError>().await.ok();
+                trace!("enrollment rejected: {:?}", error);
+                Ok(EnrollmentResponse::Rejected(
+                    EnrollmentRejection::from_error(error),
+                ))
+            }
+            StatusCode::TOO_MANY_REQUESTS => Ok(EnrollmentResponse::TooManyRequests),
+            code => Ok(EnrollmentResponse::Unexpected(code)),
+        }
+    }
+
+    /// Removes the enrollment in a lesson. This is only possible until `cancelation_until`.
+    #[instrument(skip(self, token))]
+    pub async fn unenroll(
+        &self,
+        token: &str,
+        id: &LessonID,
+    ) -> Result<UnenrollmentResponse, AsvzError> {
+        let url = self.schalter_url(&format!("tn-api/api/Lessons/{}/Enrollment", id.as_str()))?;
+        let response = self.http().delete(url).bearer_auth(token).send().await?;
+        trace!("unenroll response with status code {}", response.status());
+
+        match response.status() {
+            StatusCode::OK | StatusCode::NO_CONTENT => Ok(UnenrollmentResponse::Unenrolled),
+            StatusCode::NOT_FOUND => Ok(UnenrollmentResponse::NotEnrolled),
+            StatusCode::UNPROCESSABLE_ENTITY => Ok(UnenrollmentResponse::Rejected),
+            StatusCode::TOO_MANY_REQUESTS => Ok(UnenrollmentResponse::TooManyRequests),
+            code => Ok(UnenrollmentResponse::Unexpected(code)),
+        }
+    }
+
+    /// Fetches the enrollments of the logged in user in lessons that haven't ended yet,
+    /// sorted by the start of the lesson.
+    #[instrument(skip(self, token))]
+    pub async fn my_enrollments(&self, token: &str) -> Result<Vec<Enrollment>, AsvzError> {
+        trace!("fetching my enrollments");
+        let url = self.schalter_url("tn-api/api/MyEnrollments")?;
+        let my_enrollments: MyEnrollments = self
+            .http()
+            .get(url)
+            .bearer_auth(token)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        let now = Local::now();
+        let mut enrollments: Vec<_> = my_enrollments
+            .data
+            .into_iter()
+            .filter(|enrollment| enrollment.lesson_end > now)
+            .collect();
+        enrollments.sort_by_key(|enrollment| enrollment.lesson_start);
+        Ok(enrollments)
+    }
+}
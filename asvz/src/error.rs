@@ -9,10 +9,33 @@ pub enum AsvzError {
     Http(#[from] reqwest::Error),
     #[error("Http error: {0}")]
     HttpMiddleware(#[from] reqwest_middleware::Error),
+    #[error("Unable to decode the response: {0}")]
+    Decode(#[from] serde_json::Error),
     #[error("Unable to connect to Lesson: {0:?}")]
     Lesson(LessonError),
     #[error("Unexpected Response from the Server")]
     UnexpectedFormat,
+    #[error("The password is wrong")]
+    WrongPassword,
+    #[error("The username is unknown")]
+    UnknownUser,
+    #[error("The username or password is wrong")]
+    WrongCredentials,
+    #[error("Unexpected page while logging in: {0}")]
+    UnexpectedLoginPage(&'static str),
+}
+
+impl AsvzError {
+    /// Whether the server rejected the access token, e.g. because it expired.
+    pub fn is_unauthorized(&self) -> bool {
+        match self {
+            Self::Http(err) => err.status() == Some(reqwest::StatusCode::UNAUTHORIZED),
+            Self::HttpMiddleware(reqwest_middleware::Error::Reqwest(err)) => {
+                err.status() == Some(reqwest::StatusCode::UNAUTHORIZED)
+            }
+            _ => false,
+        }
+    }
 }
 
 impl From<url::ParseError> for AsvzError {
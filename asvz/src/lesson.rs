@@ -1,27 +1,27 @@
 use std::collections::HashMap;
 use std::str::FromStr;
 
+use chrono::NaiveDateTime;
+
 use lazy_static::lazy_static;
 use regex::Regex;
-use reqwest_middleware::ClientWithMiddleware;
+use serde::{Deserialize, Serialize};
 use tracing::{instrument, trace, warn};
 use url::Url;
 
 use crate::api::lesson::{LessonData, LessonError};
-use crate::api::search::EventList;
+use crate::api::search::{self, EventList};
 use crate::api::sport::SportSearch;
+use crate::client::AsvzClient;
 use crate::error::AsvzError;
 
 lazy_static! {
     static ref LOCATION_URL_RE: Regex = Regex::new("/anlage/([0-9]+)-").unwrap();
     static ref SPORT_URL_RE: Regex = Regex::new("/sport/([0-9]+)-").unwrap();
-    static ref SEARCH_URL_TEMPLATE: Url =
-        Url::parse("https://www.asvz.ch/asvz_api/event_search?_format=json&limit=1").unwrap();
-    static ref SPORT_SEARCH_URL: Url =
-        Url::parse("https://asvz.ch/asvz_api/sport_search?_format=json&limit=999").unwrap();
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
 pub struct LessonID(String);
 
 impl LessonID {
@@ -44,86 +44,257 @@ impl FromStr for LessonID {
     }
 }
 
-#[instrument(skip(client))]
-pub async fn lesson_data(
-    client: &ClientWithMiddleware,
-    id: &LessonID,
-) -> Result<LessonData, AsvzError> {
-    trace!("fetching lesson data");
-    let url = format!(
-        "https://schalter.asvz.ch/tn-api/api/Lessons/{}",
-        id.as_str()
-    );
-    let response = client.get(url).send().await?;
-    let full = response.bytes().await?;
-    if let Ok(data) = serde_json::from_slice::<LessonData>(&full) {
-        Ok(data)
-    } else if let Ok(err) = serde_json::from_slice::<LessonError>(&full) {
-        Err(AsvzError::Lesson(err))
-    } else {
-        warn!(
-            "Unable to decode: {}",
-            String::from_utf8_lossy(full.as_ref())
-        );
-        Err(AsvzError::UnexpectedFormat)
+impl AsvzClient {
+    #[instrument(skip(self))]
+    pub async fn lesson_data(&self, id: &LessonID) -> Result<LessonData, AsvzError> {
+        trace!("fetching lesson data");
+        let url = self.schalter_url(&format!("tn-api/api/Lessons/{}", id.as_str()))?;
+        let response = self.http().get(url).send().await?;
+        let full = response.bytes().await?;
+        match serde_json::from_slice::<LessonData>(&full) {
+            Ok(data) => Ok(data),
+            Err(err) => {
+                if let Ok(lesson_err) = serde_json::from_slice::<LessonError>(&full) {
+                    Err(AsvzError::Lesson(lesson_err))
+                } else {
+                    warn!(
+                        "Unable to decode: {}",
+                        String::from_utf8_lossy(full.as_ref())
+                    );
+                    Err(AsvzError::Decode(err))
+                }
+            }
+        }
+    }
+
+    /// Searches the lesson that takes place `offset` weeks after the given lesson
+    /// at the same time and place.
+    // https://www.asvz.ch/asvz_api/event_search?_format=json&limit=60&f[0]=sport:122920&f[1]=facility:45613&date=2021-11-08 06:35
+    #[instrument(skip(self))]
+    pub async fn search_data(&self, id: &LessonID, offset: i64) -> Result<EventList, AsvzError> {
+        trace!("fetching search data");
+        let sport_data = self.get_sport_data().await?;
+        let lesson_data = self.lesson_data(id).await?;
+        let next_date = lesson_data.data.starts + chrono::Duration::weeks(offset);
+
+        let facility_url = match &*lesson_data.data.facilities {
+            [] => return Err(AsvzError::UnexpectedFormat),
+            [facility] => &facility.url,
+            [facility, ..] => {
+                warn!(
+                    "There are multiple facilities: {:?}",
+                    &lesson_data.data.facilities
+                );
+                &facility.url
+            }
+        };
+
+        let facility_id = &LOCATION_URL_RE
+            .captures(facility_url)
+            .ok_or(AsvzError::UnexpectedFormat)?[1];
+
+        let sport_id = sport_data
+            .get(&lesson_data.data.sport_name)
+            .ok_or(AsvzError::UnexpectedFormat)?;
+
+        let query = SearchQuery::new()
+            .sport(sport_id.clone())
+            .facility(facility_id.to_string())
+            .from(next_date.naive_local())
+            .limit(1);
+
+        self.search(&query).await
+    }
+
+    /// Fetches a single page of the event search.
+    #[instrument(skip(self))]
+    pub async fn search(&self, query: &SearchQuery) -> Result<EventList, AsvzError> {
+        trace!("searching events");
+        let mut url = self.www_url("asvz_api/event_search?_format=json")?;
+        query.append_to(&mut url);
+        let event_list = self.http().get(url).send().await?.json().await?;
+        Ok(event_list)
+    }
+
+    /// Fetches pages of the event search until `max_results` events are found,
+    /// there are no more events or the events start after the `until` of the query.
+    #[instrument(skip(self))]
+    pub async fn search_all(
+        &self,
+        query: &SearchQuery,
+        max_results: usize,
+    ) -> Result<Vec<search::Result>, AsvzError> {
+        let mut results = Vec::new();
+        let mut pages = self.search_pages(query);
+        while results.len() < max_results {
+            match pages.next().await? {
+                Some(page) => results.extend(page),
+                None => break,
+            }
+        }
+        results.truncate(max_results);
+        Ok(results)
+    }
+
+    /// Pages through the event search, for callers that filter the events
+    /// and don't know in advance how many pages they need.
+    pub fn search_pages(&self, query: &SearchQuery) -> SearchPages<'_> {
+        SearchPages {
+            client: self,
+            query: query.clone(),
+            done: false,
+        }
+    }
+
+    /// Returns a map from the sport names to their ids.
+    pub async fn get_sport_data(&self) -> Result<HashMap<String, String>, AsvzError> {
+        trace!("get_sport_data");
+        let url = self.www_url("asvz_api/sport_search?_format=json&limit=999")?;
+        let sport_search: SportSearch = self.http().get(url).send().await?.json().await?;
+        Ok(sport_search.name_id_map())
     }
 }
-// https://www.asvz.ch/asvz_api/event_search?_format=json&limit=60&f[0]=sport:122920&f[1]=facility:45613&date=2021-11-08 06:35
-#[instrument(skip(client))]
-pub async fn search_data(
-    client: &ClientWithMiddleware,
-    id: &LessonID,
+
+/// The pages of an event search, see [`AsvzClient::search_pages`].
+#[derive(Debug)]
+pub struct SearchPages<'a> {
+    client: &'a AsvzClient,
+    query: SearchQuery,
+    done: bool,
+}
+
+impl SearchPages<'_> {
+    /// Fetches the next page. Returns `None` once there are no more events
+    /// or the events start after the `until` of the query.
+    pub async fn next(&mut self) -> Result<Option<Vec<search::Result>>, AsvzError> {
+        if self.done {
+            return Ok(None);
+        }
+        let event_list = self.client.search(&self.query).await?;
+        let mut results = event_list.results;
+        self.query.offset += results.len() as i64;
+        self.done = results.is_empty() || self.query.offset >= event_list.count.total;
+        if let Some(end) = results
+            .iter()
+            .position(|result| self.query.is_after_until(result))
+        {
+            results.truncate(end);
+            self.done = true;
+        }
+        if results.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(results))
+    }
+}
+
+/// Filters for the ASVZ event search. All filters are optional,
+/// an empty query returns all upcoming events.
+#[derive(Debug, Clone)]
+pub struct SearchQuery {
+    sports: Vec<String>,
+    facilities: Vec<String>,
+    levels: Vec<String>,
+    from: Option<NaiveDateTime>,
+    until: Option<NaiveDateTime>,
+    available_only: bool,
+    limit: i64,
     offset: i64,
-) -> Result<EventList, AsvzError> {
-    trace!("fetching search data");
-    let sport_data = get_sport_data(client).await?;
-    let lesson_data = lesson_data(client, id).await?;
-    // dbg!(&lesson_data);
-    let next_date = LessonData::str_to_datetime(&lesson_data.data.starts)
-        .map_err(|_| AsvzError::UnexpectedFormat)?
-        + chrono::Duration::weeks(offset);
-
-    let facility_url = match &*lesson_data.data.facilities {
-        [] => return Err(AsvzError::UnexpectedFormat),
-        [facility] => &facility.url,
-        [facility, ..] => {
-            warn!(
-                "There are multiple facilities: {:?}",
-                &lesson_data.data.facilities
-            );
-            &facility.url
+}
+
+impl SearchQuery {
+    pub fn new() -> Self {
+        Self {
+            sports: Vec::new(),
+            facilities: Vec::new(),
+            levels: Vec::new(),
+            from: None,
+            until: None,
+            available_only: false,
+            limit: 20,
+            offset: 0,
         }
-    };
+    }
+
+    /// The id of a sport, see [`AsvzClient::get_sport_data`].
+    pub fn sport(mut self, sport_id: impl Into<String>) -> Self {
+        self.sports.push(sport_id.into());
+        self
+    }
+
+    pub fn facility(mut self, facility_id: impl Into<String>) -> Self {
+        self.facilities.push(facility_id.into());
+        self
+    }
+
+    /// The id of a level (niveau), as found in the facets of an [`EventList`].
+    pub fn level(mut self, level_id: impl Into<String>) -> Self {
+        self.levels.push(level_id.into());
+        self
+    }
+
+    /// Only events starting at or after this time (local swiss time).
+    pub fn from(mut self, from: NaiveDateTime) -> Self {
+        self.from = Some(from);
+        self
+    }
 
-    let facility_id = &LOCATION_URL_RE
-        .captures(facility_url)
-        .ok_or(AsvzError::UnexpectedFormat)?[1];
+    /// Only events starting before this time (local swiss time).
+    /// The ASVZ api has no such filter, so it is only applied by [`AsvzClient::search_all`].
+    pub fn until(mut self, until: NaiveDateTime) -> Self {
+        self.until = Some(until);
+        self
+    }
 
-    let sport_id = sport_data
-        .get(&lesson_data.data.sport_name)
-        .ok_or(AsvzError::UnexpectedFormat)?;
+    /// Only events with free places.
+    pub fn available_only(mut self, available_only: bool) -> Self {
+        self.available_only = available_only;
+        self
+    }
 
-    let mut search_url = SEARCH_URL_TEMPLATE.clone();
-    search_url
-        .query_pairs_mut()
-        .append_pair("f[0]", &format!("sport:{}", sport_id))
-        .append_pair("f[1]", &format!("facility:{}", facility_id))
-        .append_pair("date", &next_date.format("%Y-%m-%d %H:%M").to_string());
+    /// The number of events per page.
+    pub fn limit(mut self, limit: i64) -> Self {
+        self.limit = limit;
+        self
+    }
 
-    let event_list = client.get(search_url).send().await?.json().await?;
+    pub fn offset(mut self, offset: i64) -> Self {
+        self.offset = offset;
+        self
+    }
 
-    Ok(event_list)
+    fn append_to(&self, url: &mut Url) {
+        let mut pairs = url.query_pairs_mut();
+        let facets = self
+            .sports
+            .iter()
+            .map(|id| format!("sport:{}", id))
+            .chain(self.facilities.iter().map(|id| format!("facility:{}", id)))
+            .chain(self.levels.iter().map(|id| format!("niveau:{}", id)));
+        for (i, facet) in facets.enumerate() {
+            pairs.append_pair(&format!("f[{}]", i), &facet);
+        }
+        if let Some(from) = &self.from {
+            pairs.append_pair("date", &from.format("%Y-%m-%d %H:%M").to_string());
+        }
+        if self.available_only {
+            pairs.append_pair("availability", "1");
+        }
+        pairs
+            .append_pair("limit", &self.limit.to_string())
+            .append_pair("offset", &self.offset.to_string());
+    }
+
+    fn is_after_until(&self, result: &search::Result) -> bool {
+        match &self.until {
+            Some(until) => result.from_date.naive_local() >= *until,
+            None => false,
+        }
+    }
 }
 
-pub async fn get_sport_data(
-    client: &ClientWithMiddleware,
-) -> Result<HashMap<String, String>, AsvzError> {
-    trace!("get_sport_data");
-    let sport_search: SportSearch = client
-        .get(SPORT_SEARCH_URL.clone())
-        .send()
-        .await?
-        .json()
-        .await?;
-    Ok(sport_search.name_id_map())
+impl Default for SearchQuery {
+    fn default() -> Self {
+        Self::new()
+    }
 }
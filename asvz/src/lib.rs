@@ -1,4 +1,7 @@
 pub mod api;
+pub mod client;
+pub mod clock;
+pub mod enrollment;
 pub mod error;
 pub mod lesson;
 pub mod login;
@@ -1,25 +1,72 @@
 use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
 
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, TimeZone, Utc};
 use lazy_static::lazy_static;
 use regex::Regex;
 use reqwest_middleware::ClientWithMiddleware;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use tracing::{instrument, trace};
 use url::Url;
 
+use crate::client::AsvzClient;
 use crate::error::AsvzError;
 
 lazy_static! {
     static ref DUMMY_URL: Url = Url::parse("https://www.google.com/").unwrap();
-    static ref BASE_AAI_URL: Url = Url::parse("https://aai-logon.ethz.ch").unwrap();
-    static ref LOGIN_URL: Url = Url::parse("https://auth.asvz.ch/account/login").unwrap();
-    static ref EXTERNAL_LOGIN_URL: Url =
-        Url::parse("https://auth.asvz.ch/Account/ExternalLogin").unwrap();
-    static ref AUTH_URL: Url =
-        Url::parse("https://auth.asvz.ch/connect/authorize?client_id=55776bff-ef75-4c9d-9bdd-45e883ec38e0&redirect_uri=https://schalter.asvz.ch/tn/assets/oidc-login-redirect.html&response_type=id_token token&scope=openid profile tn-api tn-apiext tn-auth tn-hangfire").unwrap();
+    static ref ACTION_URL_RE: Regex =
+        Regex::new("<form .*action=\"(.+)\" method=\"post\">").unwrap();
+    static ref FORM_ERROR_RE: Regex =
+        Regex::new("<p class=\"form-element form-error\">(.+)</p>").unwrap();
+    static ref FORM_RE: Regex =
+        Regex::new("(?s)<form[^>]*action=\"([^\"]*)\"[^>]*>(.*?)</form>").unwrap();
+    static ref FORM_FIELD_RE: Regex = Regex::new("<(?:input|button)[^>]*>").unwrap();
+    static ref ATTRIBUTE_RE: Regex = Regex::new("([\\w-]+)=\"([^\"]*)\"").unwrap();
+}
+
+/// How a user logs in at auth.asvz.ch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum LoginMethod {
+    /// With a university account over SwitchAAI.
+    SwitchAai(IdentityProvider),
+    /// With an account of the ASVZ itself, e.g. for alumni.
+    AsvzAccount,
+}
 
+impl Default for LoginMethod {
+    fn default() -> Self {
+        Self::SwitchAai(IdentityProvider::default())
+    }
+}
+
+impl fmt::Display for LoginMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::SwitchAai(idp) => write!(f, "{}", idp),
+            Self::AsvzAccount => write!(f, "ASVZ"),
+        }
+    }
 }
 
+impl FromStr for LoginMethod {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("asvz") {
+            return Ok(Self::AsvzAccount);
+        }
+        IdentityProvider::from_str(s)
+            .map(Self::SwitchAai)
+            .map_err(|_| "Use one of following: eth, uzh, eduid, asvz".to_string())
+    }
+}
+
+const OIDC_CLIENT_ID: &str = "55776bff-ef75-4c9d-9bdd-45e883ec38e0";
+const OIDC_SCOPE: &str = "openid profile tn-api tn-apiext tn-auth tn-hangfire";
+
 const LOCAL_STORAGE_FORM: [(&str, &str); 8] = [
     ("shib_idp_ls_exception.shib_idp_session_ss", ""),
     ("shib_idp_ls_success.shib_idp_session_ss", "false"),
@@ -31,10 +78,64 @@ const LOCAL_STORAGE_FORM: [(&str, &str); 8] = [
     ("_eventId_proceed", ""),
 ];
 
-const AAI_FORM: [(&str, &str); 2] = [
-    ("user_idp", "https://aai-logon.ethz.ch/idp/shibboleth"),
-    ("Select", "Auswählen"),
-];
+/// The SwitchAAI identity providers, which are selected on the WAYF (where are you from) page.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum IdentityProvider {
+    #[default]
+    Eth,
+    Uzh,
+    /// SWITCH edu-ID, used by most other universities, e.g. ZHAW.
+    EduId,
+}
+
+impl IdentityProvider {
+    pub fn entity_id(&self) -> &'static str {
+        match self {
+            Self::Eth => "https://aai-logon.ethz.ch/idp/shibboleth",
+            Self::Uzh => "https://aai-idp.uzh.ch/idp/shibboleth",
+            Self::EduId => "https://login.eduid.ch/idp/shibboleth",
+        }
+    }
+
+    fn strategy(&self) -> LoginStrategy {
+        match self {
+            Self::Eth | Self::Uzh => LoginStrategy::SingleForm,
+            Self::EduId => LoginStrategy::UsernameFirst,
+        }
+    }
+}
+
+impl fmt::Display for IdentityProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Eth => write!(f, "ETH"),
+            Self::Uzh => write!(f, "UZH"),
+            Self::EduId => write!(f, "edu-ID"),
+        }
+    }
+}
+
+impl FromStr for IdentityProvider {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "eth" => Ok(Self::Eth),
+            "uzh" => Ok(Self::Uzh),
+            "eduid" | "edu-id" => Ok(Self::EduId),
+            _ => Err("Use one of following: eth, uzh, eduid".to_string()),
+        }
+    }
+}
+
+/// How the login form of an identity provider is filled in.
+#[derive(Debug, Clone, Copy)]
+enum LoginStrategy {
+    /// Username and password in one form, like the default Shibboleth login page.
+    SingleForm,
+    /// The username on the first page, the password on a second one.
+    UsernameFirst,
+}
 
 fn unescape(str: &str) -> String {
     let mut r = String::new();
@@ -42,142 +143,194 @@ fn unescape(str: &str) -> String {
     r
 }
 
-#[instrument(skip(client, username, password))]
-pub async fn asvz_login(
-    client: &ClientWithMiddleware,
-    username: &str,
-    password: &str,
-) -> Result<String, AsvzError> {
-    lazy_static! {
-        static ref VERIFI_TOKEN_RE: Regex =
-            Regex::new("name=\"__RequestVerificationToken\".*value=\"(.+)\".*/").unwrap();
-    }
-    trace!("logging in");
-    let login_text = client
-        .get(LOGIN_URL.clone())
-        .send()
-        .await?
-        .error_for_status()?
-        .text()
-        .await?;
-
-    if !login_text.contains("action=\"/Account/Logout\"") {
-        let verifi_token = &VERIFI_TOKEN_RE.captures(&login_text).unwrap()[1];
-
-        let login_form = [
-            ("provider", "SwitchAai"),
-            ("__RequestVerificationToken", verifi_token),
-        ];
+impl AsvzClient {
+    /// Logs in with SwitchAAI or an ASVZ account and returns the access token for the "Schalter" api.
+    #[instrument(skip(self, username, password))]
+    pub async fn login(
+        &self,
+        method: LoginMethod,
+        username: &str,
+        password: &str,
+    ) -> Result<String, AsvzError> {
+        lazy_static! {
+            static ref VERIFI_TOKEN_RE: Regex =
+                Regex::new("name=\"__RequestVerificationToken\".*value=\"(.+)\".*/").unwrap();
+        }
+        trace!("logging in");
+        let client = self.http();
         let response = client
-            .post(EXTERNAL_LOGIN_URL.clone())
-            .form(&login_form)
+            .get(self.auth_url("account/login")?)
             .send()
             .await?
             .error_for_status()?;
+        let login_url = response.url().clone();
+        let login_text = response.text().await?;
 
-        aai_login(
-            client,
-            username,
-            password,
-            response.url().clone(),
-            &AAI_FORM,
-        )
-        .await?;
+        // The cookies of an earlier login might still be valid
+        let logged_in = login_text.contains("action=\"/Account/Logout\"");
+        match method {
+            _ if logged_in => trace!("already logged in"),
+            LoginMethod::AsvzAccount => {
+                local_login(client, &login_url, &login_text, username, password).await?;
+            }
+            LoginMethod::SwitchAai(idp) => {
+                let verifi_token = &VERIFI_TOKEN_RE.captures(&login_text).ok_or(
+                    AsvzError::UnexpectedLoginPage("no verification token on the login page"),
+                )?[1];
+
+                let login_form = [
+                    ("provider", "SwitchAai"),
+                    ("__RequestVerificationToken", verifi_token),
+                ];
+                let response = client
+                    .post(self.auth_url("Account/ExternalLogin")?)
+                    .form(&login_form)
+                    .send()
+                    .await?
+                    .error_for_status()?;
+
+                aai_login(client, idp, username, password, response.url().clone()).await?;
+            }
+        }
+
+        let redirect_url = self.schalter_url("tn/assets/oidc-login-redirect.html")?;
+        let mut auth_url = self.auth_url("connect/authorize")?;
+        let nonce: String = std::iter::repeat_with(|| fastrand::digit(16))
+            .take(32)
+            .collect();
+        let state: String = std::iter::repeat_with(|| fastrand::digit(16))
+            .take(32)
+            .collect();
+        auth_url
+            .query_pairs_mut()
+            .append_pair("client_id", OIDC_CLIENT_ID)
+            .append_pair("redirect_uri", redirect_url.as_str())
+            .append_pair("response_type", "id_token token")
+            .append_pair("scope", OIDC_SCOPE)
+            .append_pair("nonce", nonce.as_str())
+            .append_pair("state", state.as_str());
+        let response = client.get(auth_url).send().await?.error_for_status()?;
+
+        let fragment = response
+            .url()
+            .fragment()
+            .ok_or(AsvzError::UnexpectedLoginPage(
+                "no fragment in the redirect",
+            ))?;
+        let mut dummy_url = DUMMY_URL.clone();
+        dummy_url.set_query(Some(fragment));
+        let map = dummy_url
+            .query_pairs()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect::<HashMap<String, String>>();
+
+        map.get("access_token")
+            .cloned()
+            .ok_or(AsvzError::UnexpectedLoginPage(
+                "no access token in the redirect",
+            ))
     }
+}
 
-    let mut auth_url: Url = AUTH_URL.clone();
-    let nonce: String = std::iter::repeat_with(|| fastrand::digit(16))
-        .take(32)
-        .collect();
-    let state: String = std::iter::repeat_with(|| fastrand::digit(16))
-        .take(32)
-        .collect();
-    auth_url
-        .query_pairs_mut()
-        .append_pair("nonce", nonce.as_str())
-        .append_pair("state", state.as_str());
-    let response = client.get(auth_url).send().await?.error_for_status()?;
-
-    let fragment = response
-        .url()
-        .fragment()
-        .ok_or(AsvzError::UnexpectedFormat)?;
-    let mut dummy_url = DUMMY_URL.clone();
-    dummy_url.set_query(Some(fragment));
-    let map = dummy_url
-        .query_pairs()
-        .map(|(key, value)| (key.to_string(), value.to_string()))
-        .collect::<HashMap<String, String>>();
-
-    map.get("access_token")
-        .map(Clone::clone)
-        .ok_or(AsvzError::UnexpectedFormat)
-}
-
-async fn aai_login<T: Serialize + ?Sized>(
+/// Reads when an access token expires. The token is a JWT, its signature isn't checked.
+pub fn token_expiry(token: &str) -> Option<DateTime<Utc>> {
+    #[derive(Deserialize)]
+    struct Claims {
+        exp: i64,
+    }
+
+    let payload = token.split('.').nth(1)?;
+    let claims: Claims = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).ok()?).ok()?;
+    Utc.timestamp_opt(claims.exp, 0).single()
+}
+
+async fn aai_login(
     client: &ClientWithMiddleware,
+    idp: IdentityProvider,
     username: &str,
     password: &str,
     url: Url,
-    form: &T,
 ) -> Result<(), AsvzError> {
     lazy_static! {
-        static ref ACTION_URL_RE: Regex =
-            Regex::new("<form .*action=\"(.+)\" method=\"post\">").unwrap();
         static ref RELAY_STATE_RE: Regex =
             Regex::new("name=\"RelayState\" value=\"(.+)\"/>").unwrap();
         static ref SAMLRESPONSE_RE: Regex =
             Regex::new("name=\"SAMLResponse\" value=\"(.+)\"/").unwrap();
     }
 
-    let text = client.post(url).form(form).send().await?.text().await?;
+    let wayf_form = [("user_idp", idp.entity_id()), ("Select", "Auswählen")];
+    let response = client.post(url).form(&wayf_form).send().await?;
+    let page_url = response.url().clone();
+    let text = response.text().await?;
+    // Without a session at the IdP, we have to log in there first
     let sam_text = if !text.contains("SAMLResponse") {
-        let local_storage_part = &ACTION_URL_RE
-            .captures(&text)
-            .ok_or(AsvzError::UnexpectedFormat)?[1];
-        let local_storage_url = BASE_AAI_URL.clone().join(local_storage_part)?;
-        let login_page = client
-            .post(local_storage_url)
-            .form(&LOCAL_STORAGE_FORM)
-            .send()
-            .await?
-            .text()
-            .await?;
-
-        let sso_form = [
-            ("_eventId_proceed", ""),
-            ("j_username", username),
-            ("j_password", password),
-        ];
-        let sso_part = &ACTION_URL_RE
-            .captures(&login_page)
-            .ok_or(AsvzError::UnexpectedFormat)?[1];
-        let sso_url = BASE_AAI_URL.clone().join(sso_part)?;
-        client
-            .post(sso_url)
-            .form(&sso_form)
-            .send()
-            .await?
-            .text()
-            .await?
+        let (login_page_url, login_page) = post_page_form(
+            client,
+            &page_url,
+            &text,
+            &LOCAL_STORAGE_FORM,
+            "no local storage form",
+        )
+        .await?;
+        match idp.strategy() {
+            LoginStrategy::SingleForm => {
+                let sso_form = [
+                    ("_eventId_proceed", ""),
+                    ("j_username", username),
+                    ("j_password", password),
+                ];
+                post_page_form(
+                    client,
+                    &login_page_url,
+                    &login_page,
+                    &sso_form,
+                    "no login form",
+                )
+                .await?
+                .1
+            }
+            LoginStrategy::UsernameFirst => {
+                let username_form = [("_eventId_proceed", ""), ("j_username", username)];
+                let (password_page_url, password_page) = post_page_form(
+                    client,
+                    &login_page_url,
+                    &login_page,
+                    &username_form,
+                    "no login form",
+                )
+                .await?;
+                check_form_error(&password_page)?;
+                let password_form = [("_eventId_proceed", ""), ("j_password", password)];
+                post_page_form(
+                    client,
+                    &password_page_url,
+                    &password_page,
+                    &password_form,
+                    "no password form",
+                )
+                .await?
+                .1
+            }
+        }
     } else {
         text
     };
+    check_form_error(&sam_text)?;
 
     let sam_url = Url::parse(&unescape(
         &ACTION_URL_RE
             .captures(&sam_text)
-            .ok_or(AsvzError::UnexpectedFormat)?[1],
+            .ok_or(AsvzError::UnexpectedLoginPage("no SAML form"))?[1],
     ))?;
     let ssm = unescape(
         &RELAY_STATE_RE
             .captures(&sam_text)
-            .ok_or(AsvzError::UnexpectedFormat)?[1],
+            .ok_or(AsvzError::UnexpectedLoginPage("no relay state"))?[1],
     );
     let sam = unescape(
         &SAMLRESPONSE_RE
             .captures(&sam_text)
-            .ok_or(AsvzError::UnexpectedFormat)?[1],
+            .ok_or(AsvzError::UnexpectedLoginPage("no SAML response"))?[1],
     );
 
     let saml_form = [("RelayState", &ssm), ("SAMLResponse", &sam)];
@@ -191,3 +344,172 @@ async fn aai_login<T: Serialize + ?Sized>(
 
     Ok(())
 }
+
+/// Submits the login form of auth.asvz.ch, which is the form with a password field.
+async fn local_login(
+    client: &ClientWithMiddleware,
+    page_url: &Url,
+    page: &str,
+    username: &str,
+    password: &str,
+) -> Result<(), AsvzError> {
+    let (action, fields) = password_form(page).ok_or(AsvzError::UnexpectedLoginPage(
+        "no login form for ASVZ accounts",
+    ))?;
+    let form: Vec<_> = fields
+        .into_iter()
+        .filter_map(|field| {
+            let value = match field.kind.as_str() {
+                "hidden" | "submit" => field.value,
+                "password" => password.to_string(),
+                "text" | "email" => username.to_string(),
+                _ => return None,
+            };
+            Some((field.name, value))
+        })
+        .collect();
+    let response = client
+        .post(page_url.join(&action)?)
+        .form(&form)
+        .send()
+        .await?
+        .error_for_status()?;
+    // A rejected login shows the login form again
+    if password_form(&response.text().await?).is_some() {
+        return Err(AsvzError::WrongCredentials);
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+struct FormField {
+    name: String,
+    kind: String,
+    value: String,
+}
+
+/// The action and the named fields of the first form with a password field.
+fn password_form(page: &str) -> Option<(String, Vec<FormField>)> {
+    FORM_RE.captures_iter(page).find_map(|form| {
+        let fields: Vec<_> = FORM_FIELD_RE
+            .find_iter(&form[2])
+            .filter_map(|tag| {
+                let attributes: HashMap<_, _> = ATTRIBUTE_RE
+                    .captures_iter(tag.as_str())
+                    .map(|caps| (caps[1].to_lowercase(), unescape(&caps[2])))
+                    .collect();
+                let default_kind = if tag.as_str().starts_with("<button") {
+                    "submit"
+                } else {
+                    "text"
+                };
+                Some(FormField {
+                    name: attributes.get("name")?.clone(),
+                    kind: attributes
+                        .get("type")
+                        .map(|kind| kind.to_lowercase())
+                        .unwrap_or_else(|| default_kind.to_string()),
+                    value: attributes.get("value").cloned().unwrap_or_default(),
+                })
+            })
+            .collect();
+        fields
+            .iter()
+            .any(|field| field.kind == "password")
+            .then(|| (unescape(&form[1]), fields))
+    })
+}
+
+/// Submits the form on `page` and returns the url and text of the next page.
+/// The form actions of the IdPs are relative to the page they are on.
+async fn post_page_form<T: Serialize + ?Sized>(
+    client: &ClientWithMiddleware,
+    page_url: &Url,
+    page: &str,
+    form: &T,
+    missing: &'static str,
+) -> Result<(Url, String), AsvzError> {
+    let action = &ACTION_URL_RE
+        .captures(page)
+        .ok_or(AsvzError::UnexpectedLoginPage(missing))?[1];
+    let response = client
+        .post(page_url.join(&unescape(action))?)
+        .form(form)
+        .send()
+        .await?;
+    let url = response.url().clone();
+    Ok((url, response.text().await?))
+}
+
+/// The messages of the Shibboleth IdP, which tell exactly what was wrong.
+const WRONG_PASSWORD_MSG: &str = "The password you entered was incorrect.";
+const UNKNOWN_USER_MSG: &str = "The username you entered cannot be identified.";
+
+/// A rejected login shows the login form again, with the reason.
+/// Only the exact messages of the IdP are told apart, any other message about
+/// the username or password may mean either of them.
+fn check_form_error(page: &str) -> Result<(), AsvzError> {
+    let reason = match FORM_ERROR_RE.captures(page) {
+        Some(caps) => unescape(&caps[1]).trim().to_string(),
+        None => return Ok(()),
+    };
+    trace!("login rejected: {}", reason);
+    let lowercase = reason.to_lowercase();
+    Err(if reason.eq_ignore_ascii_case(WRONG_PASSWORD_MSG) {
+        AsvzError::WrongPassword
+    } else if reason.eq_ignore_ascii_case(UNKNOWN_USER_MSG) {
+        AsvzError::UnknownUser
+    } else if ["password", "passwort", "username", "benutzername"]
+        .iter()
+        .any(|word| lowercase.contains(word))
+    {
+        AsvzError::WrongCredentials
+    } else {
+        AsvzError::UnexpectedLoginPage("unknown login error")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn form_error(msg: &str) -> Result<(), AsvzError> {
+        check_form_error(&format!(
+            r#"<form><p class="form-element form-error">{}</p></form>"#,
+            msg
+        ))
+    }
+
+    #[test]
+    fn form_errors() {
+        let cases = [
+            (WRONG_PASSWORD_MSG, "WrongPassword"),
+            ("the password you entered was incorrect. ", "WrongPassword"),
+            (UNKNOWN_USER_MSG, "UnknownUser"),
+            ("Invalid username or password", "WrongCredentials"),
+            ("Benutzername oder Passwort falsch", "WrongCredentials"),
+            ("Your password has expired", "WrongCredentials"),
+            ("Unknown username", "WrongCredentials"),
+            (
+                "Passwort &amp; Benutzername pr&uuml;fen",
+                "WrongCredentials",
+            ),
+            ("Too many attempts, try again later", "UnexpectedLoginPage"),
+        ];
+        for (msg, expected) in cases {
+            let error = match form_error(msg) {
+                Err(AsvzError::WrongPassword) => "WrongPassword",
+                Err(AsvzError::UnknownUser) => "UnknownUser",
+                Err(AsvzError::WrongCredentials) => "WrongCredentials",
+                Err(AsvzError::UnexpectedLoginPage(_)) => "UnexpectedLoginPage",
+                result => panic!("Unexpected result {:?} for {:?}", result, msg),
+            };
+            assert_eq!(error, expected, "{:?}", msg);
+        }
+    }
+
+    #[test]
+    fn page_without_form_error() {
+        assert!(check_form_error("<form></form>").is_ok());
+    }
+}
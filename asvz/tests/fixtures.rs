@@ -0,0 +1,123 @@
+//! Decodes raw api responses from `tests/fixtures`, independent of how the models serialize.
+//!
+//! The fixtures are written by hand in the shape of the lessons api. Replace them with
+//! captured responses where possible, the codes of `status`, `type` and
+//! `webRegistrationType` in particular are not confirmed.
+
+use asvz::api::lesson::{LessonData, LessonError, LessonStatus, RegistrationType};
+use asvz::enrollment::EnrollmentRejection;
+
+fn read<T: serde::de::DeserializeOwned>(name: &str) -> T {
+    let path = format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name);
+    let json = std::fs::read_to_string(&path).unwrap();
+    serde_json::from_str(&json).unwrap_or_else(|err| panic!("Unable to decode {}: {}", name, err))
+}
+
+fn read_lesson(name: &str) -> LessonData {
+    read(name)
+}
+
+#[test]
+fn full_lesson() {
+    let data = read_lesson("lesson.json").data;
+
+    assert_eq!(data.id, 236310);
+    assert_eq!(data.participants_max, 20);
+    assert_eq!(data.participant_count, 20);
+    assert_eq!(
+        data.enrollment_from.to_rfc3339(),
+        "2021-11-07T18:15:00+01:00"
+    );
+    assert!(!data.is_cancelled());
+    assert!(!data.is_lottery());
+    assert_eq!(data.cancellation_reason(), None);
+    assert_eq!(data.meeting_point_info, None);
+    assert_eq!(data.tl_comment, None);
+}
+
+#[test]
+fn missing_optional_fields_decode_as_none() {
+    let data = read_lesson("lesson_missing_fields.json").data;
+
+    assert!(!data.is_cancelled());
+    assert_eq!(data.cancellation_reason(), None);
+    assert_eq!(data.meeting_point_info, None);
+    assert_eq!(data.tl_comment, None);
+}
+
+#[test]
+fn unknown_codes_and_unexpected_shapes_still_decode() {
+    let data = read_lesson("lesson_unexpected.json").data;
+
+    assert_eq!(data.status, LessonStatus::Unknown(9));
+    assert_eq!(data.web_registration_type, RegistrationType::Unknown(8));
+    assert_eq!(
+        data.meeting_point_info.as_deref(),
+        Some("In front of the entrance")
+    );
+    assert_eq!(data.tl_comment.as_deref(), Some("Bring a towel"));
+    assert!(!data.is_cancelled());
+}
+
+#[test]
+fn cancelled_lesson() {
+    let data = read_lesson("lesson_cancelled.json").data;
+
+    assert!(data.is_cancelled());
+    assert_eq!(
+        data.cancellation_date
+            .map(|date| date.to_rfc3339())
+            .as_deref(),
+        Some("2021-11-08T12:03:00+01:00")
+    );
+    assert_eq!(data.cancellation_reason(), Some("Instructor is sick"));
+}
+
+fn read_rejection(name: &str) -> EnrollmentRejection {
+    EnrollmentRejection::from_error(Some(read::<LessonError>(name)))
+}
+
+#[test]
+fn full_lesson_is_retried() {
+    let rejection = read_rejection("enrollment_rejected.json");
+
+    assert_eq!(rejection, EnrollmentRejection::Full);
+    assert!(rejection.is_retryable());
+}
+
+#[test]
+fn already_enrolled_is_not_retried() {
+    let rejection = read_rejection("enrollment_already_enrolled.json");
+
+    assert_eq!(rejection, EnrollmentRejection::AlreadyEnrolled);
+    assert!(!rejection.is_retryable());
+}
+
+#[test]
+fn overlapping_lesson_is_not_retried() {
+    let rejection = read_rejection("enrollment_overlapping.json");
+
+    assert_eq!(rejection, EnrollmentRejection::OverlappingLesson);
+    assert!(!rejection.is_retryable());
+}
+
+#[test]
+fn unknown_rejection_keeps_its_messages() {
+    let rejection = read_rejection("enrollment_unknown.json");
+
+    assert_eq!(
+        rejection,
+        EnrollmentRejection::Unknown(
+            "Die Anmeldung ist für diese Lektion nicht möglich.".to_string()
+        )
+    );
+    assert!(rejection.is_retryable());
+}
+
+#[test]
+fn undecodable_rejection_is_retried() {
+    let rejection = EnrollmentRejection::from_error(None);
+
+    assert_eq!(rejection, EnrollmentRejection::Unknown(String::new()));
+    assert!(rejection.is_retryable());
+}
@@ -0,0 +1,304 @@
+use std::collections::HashMap;
+
+use axum::extract::{Path, Query, State};
+use axum::http::{header, HeaderMap, HeaderValue, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use chrono::{NaiveDateTime, Utc};
+
+use asvz::api::enrollment::{self, Enrollment, EnrollmentData, EnrollmentStatus, MyEnrollments};
+use asvz::api::lesson::{Error, LessonError};
+use asvz::api::search::{self, Count, EventList};
+use asvz::api::sport::{self, SportSearch};
+
+use crate::{EnrollAttempt, MockState, SharedState};
+
+fn lesson_error(msg: &str) -> Json<LessonError> {
+    Json(LessonError {
+        error_status: "UnprocessableEntity".to_string(),
+        errors: vec![Error {
+            message: msg.to_string(),
+        }],
+    })
+}
+
+/// Sets the `Date` header from the clock of the mock server, instead of the local one.
+pub(crate) async fn date_header<B>(
+    State(state): State<SharedState>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let mut response = next.run(request).await;
+    let now = state.lock().unwrap().now().with_timezone(&Utc);
+    let date = now.format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+    response
+        .headers_mut()
+        .insert(header::DATE, HeaderValue::from_str(&date).unwrap());
+    response
+}
+
+pub(crate) async fn lesson(State(state): State<SharedState>, Path(id): Path<u64>) -> Response {
+    let mut state = state.lock().unwrap();
+    let base_url = state.base_url.to_string();
+    match state.lessons.get_mut(&id) {
+        Some(lesson) => {
+            let data = lesson.to_lesson_data(&base_url);
+            lesson.polls += 1;
+            lesson.lesson.next_poll();
+            Json(data).into_response()
+        }
+        None => (StatusCode::NOT_FOUND, lesson_error("Lesson not found")).into_response(),
+    }
+}
+
+fn token_user(state: &MockState, headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .and_then(|token| state.token_user(token))
+}
+
+pub(crate) async fn enroll(
+    State(state): State<SharedState>,
+    Path(id): Path<u64>,
+    headers: HeaderMap,
+) -> Response {
+    let mut state = state.lock().unwrap();
+    let now = state.now();
+    let username = token_user(&state, &headers);
+    let membership_expired = username
+        .as_ref()
+        .is_some_and(|username| state.expired_memberships.contains(username));
+    let lesson = match state.lessons.get_mut(&id) {
+        Some(lesson) => lesson,
+        None => return (StatusCode::NOT_FOUND, lesson_error("Lesson not found")).into_response(),
+    };
+
+    let (status, response) = match username {
+        None => (
+            StatusCode::UNAUTHORIZED,
+            StatusCode::UNAUTHORIZED.into_response(),
+        ),
+        Some(_) if lesson.lesson.cancellation_reason.is_some() => {
+            let msg = "Lesson is cancelled";
+            let response = (StatusCode::UNPROCESSABLE_ENTITY, lesson_error(msg));
+            (StatusCode::UNPROCESSABLE_ENTITY, response.into_response())
+        }
+        Some(_) if membership_expired => {
+            let msg = "Your membership has expired";
+            let response = (StatusCode::UNPROCESSABLE_ENTITY, lesson_error(msg));
+            (StatusCode::UNPROCESSABLE_ENTITY, response.into_response())
+        }
+        Some(_) if now < lesson.lesson.enrollment_from => {
+            let msg = "Enrollment is not open yet";
+            let response = (StatusCode::UNPROCESSABLE_ENTITY, lesson_error(msg));
+            (StatusCode::UNPROCESSABLE_ENTITY, response.into_response())
+        }
+        Some(_) if now > lesson.lesson.enrollment_until => {
+            let msg = "Enrollment is closed";
+            let response = (StatusCode::UNPROCESSABLE_ENTITY, lesson_error(msg));
+            (StatusCode::UNPROCESSABLE_ENTITY, response.into_response())
+        }
+        Some(username) => match lesson.lesson.enroll_responses.pop_front() {
+            Some(status) => (status, status.into_response()),
+            None if lesson.place_number(&username).is_some() => {
+                let msg = "Already enrolled";
+                let response = (StatusCode::UNPROCESSABLE_ENTITY, lesson_error(msg));
+                (StatusCode::UNPROCESSABLE_ENTITY, response.into_response())
+            }
+            None if lesson.lesson.current_participants() < lesson.lesson.places => {
+                lesson.lesson.add_participant();
+                let place_number = lesson.lesson.current_participants();
+                lesson.enrolled.push((username, place_number));
+                let data = EnrollmentData {
+                    data: enrollment::Data {
+                        place_number,
+                        status_id: EnrollmentStatus::Enrolled,
+                    },
+                };
+                (
+                    StatusCode::CREATED,
+                    (StatusCode::CREATED, Json(data)).into_response(),
+                )
+            }
+            None => {
+                let msg = "Lesson is full";
+                let response = (StatusCode::UNPROCESSABLE_ENTITY, lesson_error(msg));
+                (StatusCode::UNPROCESSABLE_ENTITY, response.into_response())
+            }
+        },
+    };
+    lesson
+        .enroll_attempts
+        .push(EnrollAttempt { at: now, status });
+    response
+}
+
+pub(crate) async fn unenroll(
+    State(state): State<SharedState>,
+    Path(id): Path<u64>,
+    headers: HeaderMap,
+) -> Response {
+    let mut state = state.lock().unwrap();
+    let now = state.now();
+    let username = match token_user(&state, &headers) {
+        Some(username) => username,
+        None => return StatusCode::UNAUTHORIZED.into_response(),
+    };
+    let lesson = match state.lessons.get_mut(&id) {
+        Some(lesson) => lesson,
+        None => return (StatusCode::NOT_FOUND, lesson_error("Lesson not found")).into_response(),
+    };
+    let position = match lesson
+        .enrolled
+        .iter()
+        .position(|(user, _)| *user == username)
+    {
+        Some(position) => position,
+        None => return (StatusCode::NOT_FOUND, lesson_error("Not enrolled")).into_response(),
+    };
+    if now > lesson.lesson.cancelation_until {
+        let msg = "Cancellation is no longer possible";
+        return (StatusCode::UNPROCESSABLE_ENTITY, lesson_error(msg)).into_response();
+    }
+    lesson.enrolled.remove(position);
+    lesson.lesson.remove_participant();
+    StatusCode::NO_CONTENT.into_response()
+}
+
+pub(crate) async fn my_enrollments(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+) -> Response {
+    let state = state.lock().unwrap();
+    let username = match token_user(&state, &headers) {
+        Some(username) => username,
+        None => return StatusCode::UNAUTHORIZED.into_response(),
+    };
+    let data = state
+        .lessons
+        .values()
+        .filter_map(|entry| {
+            let place_number = entry.place_number(&username)?;
+            let lesson = &entry.lesson;
+            Some(Enrollment {
+                id: lesson.id as i64 * 100 + place_number,
+                lesson_id: lesson.id as i64,
+                sport_name: lesson.sport_name.clone(),
+                title: lesson.sport_name.clone(),
+                lesson_start: lesson.starts,
+                lesson_end: lesson.ends,
+                location: Some(lesson.facility_name.clone()),
+                place_number,
+                status_id: EnrollmentStatus::Enrolled,
+            })
+        })
+        .collect();
+    Json(MyEnrollments { data }).into_response()
+}
+
+pub(crate) async fn sport_search(State(state): State<SharedState>) -> Json<SportSearch> {
+    let state = state.lock().unwrap();
+    let mut sports: Vec<_> = state
+        .lessons
+        .values()
+        .map(|lesson| (lesson.lesson.sport_id, lesson.lesson.sport_name.clone()))
+        .collect();
+    sports.sort();
+    sports.dedup();
+    let results: Vec<_> = sports
+        .into_iter()
+        .map(|(nid, title)| sport::Result {
+            nid,
+            title,
+            ..sport::Result::default()
+        })
+        .collect();
+    Json(SportSearch {
+        count: sport::Count {
+            total: results.len() as i64,
+            limit: 999,
+            offset: 0,
+        },
+        results,
+        ..SportSearch::default()
+    })
+}
+
+pub(crate) async fn event_search(
+    State(state): State<SharedState>,
+    Query(params): Query<Vec<(String, String)>>,
+) -> Json<EventList> {
+    let state = state.lock().unwrap();
+    let params: HashMap<_, _> = params.into_iter().collect();
+    let facets: Vec<(&str, i64)> = params
+        .iter()
+        .filter(|(key, _)| key.starts_with("f["))
+        .filter_map(|(_, value)| {
+            let (facet, id) = value.split_once(':')?;
+            Some((facet, id.parse().ok()?))
+        })
+        .collect();
+    let date = params
+        .get("date")
+        .and_then(|date| NaiveDateTime::parse_from_str(date, "%Y-%m-%d %H:%M").ok());
+    let limit = params
+        .get("limit")
+        .and_then(|limit| limit.parse().ok())
+        .unwrap_or(20);
+    let offset = params
+        .get("offset")
+        .and_then(|offset| offset.parse().ok())
+        .unwrap_or(0);
+
+    let mut lessons: Vec<_> = state
+        .lessons
+        .values()
+        .map(|lesson| &lesson.lesson)
+        .filter(|lesson| {
+            facets.iter().all(|(facet, id)| match *facet {
+                "sport" => lesson.sport_id == *id,
+                "facility" => lesson.facility_id == *id,
+                _ => true,
+            })
+        })
+        .filter(|lesson| date.is_none_or(|date| lesson.starts.naive_local() >= date))
+        .collect();
+    lessons.sort_by_key(|lesson| lesson.starts);
+
+    let total = lessons.len() as i64;
+    let results = lessons
+        .into_iter()
+        .skip(offset as usize)
+        .take(limit as usize)
+        .map(|lesson| search::Result {
+            nid: lesson.id as i64,
+            title: lesson.sport_name.clone(),
+            sport: vec![lesson.sport_id],
+            sport_name: lesson.sport_name.clone(),
+            facility: vec![lesson.facility_id],
+            facility_name: vec![lesson.facility_name.clone()],
+            location: lesson.facility_name.clone(),
+            from_date: lesson.starts,
+            to_date: lesson.ends,
+            oe_from_date: lesson.enrollment_from,
+            oe_to_date: lesson.enrollment_until,
+            places_max: lesson.places,
+            cancelled: lesson.cancellation_reason.is_some(),
+            url: format!("{}tn/lessons/{}", state.base_url, lesson.id),
+            ..search::Result::default()
+        })
+        .collect();
+
+    Json(EventList {
+        results,
+        count: Count {
+            total,
+            limit,
+            offset,
+        },
+        ..EventList::default()
+    })
+}
@@ -0,0 +1,218 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use axum::http::StatusCode;
+use chrono::{DateTime, FixedOffset, Local};
+
+use asvz::api::lesson::{
+    Data, EventType, Facility, Language, LessonData, LessonStatus, RegistrationType,
+};
+
+/// A scripted lesson served by the [`MockServer`](crate::MockServer).
+///
+/// By default the lesson starts in a day, the enrollment is already open
+/// and 5 of 20 places are taken. It is held in german.
+#[derive(Debug, Clone)]
+pub struct MockLesson {
+    pub(crate) id: u64,
+    pub(crate) sport_id: i64,
+    pub(crate) sport_name: String,
+    pub(crate) facility_id: i64,
+    pub(crate) facility_name: String,
+    pub(crate) starts: DateTime<FixedOffset>,
+    pub(crate) ends: DateTime<FixedOffset>,
+    pub(crate) enrollment_from: DateTime<FixedOffset>,
+    pub(crate) enrollment_until: DateTime<FixedOffset>,
+    pub(crate) cancelation_until: DateTime<FixedOffset>,
+    pub(crate) places: i64,
+    pub(crate) cancellation_reason: Option<String>,
+    pub(crate) registration: RegistrationType,
+    /// The code of the language the lesson is held in, e.g. `de`.
+    pub(crate) language: String,
+    /// The participant count of every poll, the last one stays once all others are used up.
+    pub(crate) participants: VecDeque<i64>,
+    /// Responses to enrollment attempts after the enrollment opened,
+    /// before the lesson answers on its own.
+    pub(crate) enroll_responses: VecDeque<StatusCode>,
+}
+
+impl MockLesson {
+    pub fn new(id: u64) -> Self {
+        let now = Local::now().fixed_offset();
+        let starts = now + chrono::Duration::days(1);
+        Self {
+            id,
+            sport_id: 1,
+            sport_name: "Spinning".to_string(),
+            facility_id: 45613,
+            facility_name: "Sport Center Polyterrasse".to_string(),
+            starts,
+            ends: starts + chrono::Duration::hours(1),
+            enrollment_from: now - chrono::Duration::hours(1),
+            enrollment_until: starts,
+            cancelation_until: starts - chrono::Duration::hours(2),
+            places: 20,
+            cancellation_reason: None,
+            registration: RegistrationType::Online,
+            language: "de".to_string(),
+            participants: VecDeque::from([5]),
+            enroll_responses: VecDeque::new(),
+        }
+    }
+
+    pub fn sport(mut self, sport_id: i64, sport_name: impl Into<String>) -> Self {
+        self.sport_id = sport_id;
+        self.sport_name = sport_name.into();
+        self
+    }
+
+    pub fn facility(mut self, facility_id: i64, facility_name: impl Into<String>) -> Self {
+        self.facility_id = facility_id;
+        self.facility_name = facility_name.into();
+        self
+    }
+
+    /// Moves the lesson, the enrollment deadlines move along with it.
+    pub fn starts_at(mut self, starts: DateTime<FixedOffset>) -> Self {
+        let shift = starts - self.starts;
+        self.starts = starts;
+        self.ends += shift;
+        self.enrollment_until += shift;
+        self.cancelation_until += shift;
+        self
+    }
+
+    pub fn starts_in(self, duration: Duration) -> Self {
+        let starts = Local::now().fixed_offset() + to_chrono(duration);
+        self.starts_at(starts)
+    }
+
+    pub fn enrollment_opens_in(mut self, duration: Duration) -> Self {
+        self.enrollment_from = Local::now().fixed_offset() + to_chrono(duration);
+        self
+    }
+
+    pub fn enrollment_closes_in(mut self, duration: Duration) -> Self {
+        self.enrollment_until = Local::now().fixed_offset() + to_chrono(duration);
+        self
+    }
+
+    pub fn cancelation_closes_in(mut self, duration: Duration) -> Self {
+        self.cancelation_until = Local::now().fixed_offset() + to_chrono(duration);
+        self
+    }
+
+    pub fn places(mut self, places: i64) -> Self {
+        self.places = places;
+        self
+    }
+
+    pub fn cancelled(mut self, reason: impl Into<String>) -> Self {
+        self.cancellation_reason = Some(reason.into());
+        self
+    }
+
+    pub fn registration(mut self, registration: RegistrationType) -> Self {
+        self.registration = registration;
+        self
+    }
+
+    pub fn language(mut self, code: impl Into<String>) -> Self {
+        self.language = code.into();
+        self
+    }
+
+    /// The participant count returned by successive polls, e.g. `[20, 20, 20, 19]`
+    /// is full for three polls and then a place frees up.
+    pub fn participants(mut self, participants: impl IntoIterator<Item = i64>) -> Self {
+        self.participants = participants.into_iter().collect();
+        assert!(
+            !self.participants.is_empty(),
+            "A lesson needs a participant count"
+        );
+        self
+    }
+
+    /// Answers the first enrollment attempts after the enrollment opened
+    /// with these status codes, e.g. `[429, 429]` to be rate limited twice.
+    pub fn enroll_responses(mut self, responses: impl IntoIterator<Item = StatusCode>) -> Self {
+        self.enroll_responses = responses.into_iter().collect();
+        self
+    }
+
+    pub(crate) fn current_participants(&self) -> i64 {
+        *self.participants.front().expect("Always has a count")
+    }
+
+    /// Advances the participant script by one poll.
+    pub(crate) fn next_poll(&mut self) {
+        if self.participants.len() > 1 {
+            self.participants.pop_front();
+        }
+    }
+
+    pub(crate) fn add_participant(&mut self) {
+        if let Some(count) = self.participants.front_mut() {
+            *count += 1;
+        }
+    }
+
+    pub(crate) fn remove_participant(&mut self) {
+        if let Some(count) = self.participants.front_mut() {
+            *count -= 1;
+        }
+    }
+
+    pub(crate) fn to_lesson_data(&self, base_url: &str) -> LessonData {
+        LessonData {
+            data: Data {
+                id: self.id as i64,
+                event_id: self.id as i64,
+                type_field: EventType::Lesson,
+                base_type: EventType::Lesson,
+                status: match self.cancellation_reason {
+                    Some(_) => LessonStatus::Cancelled,
+                    None => LessonStatus::Active,
+                },
+                cancellation_date: self.cancellation_reason.as_ref().map(|_| self.starts),
+                cancellation_reason: self.cancellation_reason.clone(),
+                web_registration_type: self.registration,
+                enrollment_enabled: true,
+                enrollment_from: self.enrollment_from,
+                enrollment_until: self.enrollment_until,
+                cancelation_until: self.cancelation_until,
+                starts: self.starts,
+                ends: self.ends,
+                participants_max: self.places,
+                participant_count: self.current_participants(),
+                facilities: vec![Facility {
+                    facility_id: self.facility_id,
+                    name_short: self.facility_name.clone(),
+                    name: self.facility_name.clone(),
+                    url: format!(
+                        "{}anlage/{}-{}",
+                        base_url,
+                        self.facility_id,
+                        self.facility_name.to_lowercase().replace(' ', "-")
+                    ),
+                }],
+                number: self.id.to_string(),
+                sport_id: self.sport_id,
+                sport_name: self.sport_name.clone(),
+                sport_url: format!("{}sport/{}-", base_url, self.sport_id),
+                title: self.sport_name.clone(),
+                location: Some(self.facility_name.clone()),
+                language: Language {
+                    id: self.language.clone(),
+                    code: self.language.clone(),
+                    name: self.language.clone(),
+                },
+                ..Data::default()
+            },
+        }
+    }
+}
+
+fn to_chrono(duration: Duration) -> chrono::Duration {
+    chrono::Duration::from_std(duration).expect("Duration out of range")
+}
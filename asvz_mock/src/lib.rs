@@ -0,0 +1,323 @@
+//! A scriptable stand-in for the ASVZ servers, to test the bot without touching production.
+//! [`MockTelegram`] records what the bot sends to its users.
+//!
+//! ```no_run
+//! # async fn example() {
+//! use std::time::Duration;
+//! use asvz_mock::{MockLesson, MockServer, StatusCode};
+//!
+//! let server = MockServer::start().await;
+//! server.add_user("user", "password");
+//! server.add_lesson(
+//!     MockLesson::new(123)
+//!         .enrollment_opens_in(Duration::from_secs(2))
+//!         .enroll_responses([StatusCode::TOO_MANY_REQUESTS, StatusCode::TOO_MANY_REQUESTS]),
+//! );
+//! let urls = server.urls();
+//! # }
+//! ```
+
+use std::collections::{HashMap, HashSet};
+use std::net::{SocketAddr, TcpListener};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::routing::{get, post};
+use axum::{middleware, Router};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, FixedOffset, Local};
+use tokio::task::JoinHandle;
+use url::Url;
+
+use asvz::client::AsvzUrls;
+use asvz::login::{IdentityProvider, LoginMethod};
+
+pub use axum::http::StatusCode;
+
+pub use crate::lesson::MockLesson;
+pub use crate::telegram::{MockTelegram, TelegramRequest};
+
+mod api;
+mod lesson;
+mod login;
+mod telegram;
+
+pub(crate) type SharedState = Arc<Mutex<MockState>>;
+
+/// A single request to the enrollment endpoint.
+#[derive(Debug, Clone)]
+pub struct EnrollAttempt {
+    pub at: DateTime<FixedOffset>,
+    pub status: StatusCode,
+}
+
+#[derive(Debug)]
+pub(crate) struct LessonEntry {
+    pub(crate) lesson: MockLesson,
+    pub(crate) polls: usize,
+    pub(crate) enroll_attempts: Vec<EnrollAttempt>,
+    /// Username and place number
+    pub(crate) enrolled: Vec<(String, i64)>,
+}
+
+impl LessonEntry {
+    pub(crate) fn place_number(&self, username: &str) -> Option<i64> {
+        self.enrolled
+            .iter()
+            .find(|(user, _)| user == username)
+            .map(|(_, place_number)| *place_number)
+    }
+
+    fn to_lesson_data(&self, base_url: &str) -> asvz::api::lesson::LessonData {
+        self.lesson.to_lesson_data(base_url)
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct MockState {
+    pub(crate) base_url: Url,
+    pub(crate) lessons: HashMap<u64, LessonEntry>,
+    /// Username to password and how the user logs in
+    pub(crate) users: HashMap<String, (String, LoginMethod)>,
+    /// Users whose enrollments are rejected because their membership expired
+    pub(crate) expired_memberships: HashSet<String>,
+    /// Access token to username and expiry
+    pub(crate) tokens: HashMap<String, (String, DateTime<FixedOffset>)>,
+    pub(crate) token_lifetime: chrono::Duration,
+    /// Session cookie to username
+    pub(crate) sessions: HashMap<String, String>,
+    pub(crate) session_counter: usize,
+    pub(crate) token_counter: usize,
+    pub(crate) logins: usize,
+    /// How far the server clock is ahead of the local one
+    pub(crate) clock_offset: chrono::Duration,
+}
+
+impl MockState {
+    pub(crate) fn now(&self) -> DateTime<FixedOffset> {
+        Local::now().fixed_offset() + self.clock_offset
+    }
+
+    pub(crate) fn new_session(&mut self, username: String) -> String {
+        self.session_counter += 1;
+        let session = format!("session-{}", self.session_counter);
+        self.sessions.insert(session.clone(), username);
+        session
+    }
+
+    /// Issues a token shaped like a JWT, so its expiry can be read.
+    pub(crate) fn new_token(&mut self, username: String) -> String {
+        self.token_counter += 1;
+        let expires = self.now() + self.token_lifetime;
+        let header = URL_SAFE_NO_PAD.encode(r#"{"alg":"none","typ":"JWT"}"#);
+        let claims = serde_json::json!({ "sub": username, "exp": expires.timestamp() });
+        let claims = URL_SAFE_NO_PAD.encode(claims.to_string());
+        let token = format!(
+            "{}.{}.mock-signature-{}",
+            header, claims, self.token_counter
+        );
+        self.tokens.insert(token.clone(), (username, expires));
+        token
+    }
+
+    /// The user of a token that hasn't expired yet.
+    pub(crate) fn token_user(&self, token: &str) -> Option<String> {
+        match self.tokens.get(token) {
+            Some((username, expires)) if self.now() < *expires => Some(username.clone()),
+            _ => None,
+        }
+    }
+}
+
+/// A running mock server. It serves every ASVZ host on the same address
+/// and is shut down when dropped.
+pub struct MockServer {
+    addr: SocketAddr,
+    state: SharedState,
+    handle: JoinHandle<()>,
+}
+
+impl MockServer {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Unable to bind mock server");
+        let addr = listener.local_addr().unwrap();
+        let base_url = Url::parse(&format!("http://{}/", addr)).unwrap();
+        let state = Arc::new(Mutex::new(MockState {
+            base_url,
+            lessons: HashMap::new(),
+            users: HashMap::new(),
+            expired_memberships: HashSet::new(),
+            tokens: HashMap::new(),
+            token_lifetime: chrono::Duration::hours(2),
+            sessions: HashMap::new(),
+            session_counter: 0,
+            token_counter: 0,
+            logins: 0,
+            clock_offset: chrono::Duration::zero(),
+        }));
+
+        let app = Router::new()
+            .route("/tn-api/api/Lessons/:id", get(api::lesson))
+            .route(
+                "/tn-api/api/Lessons/:id/Enrollment",
+                post(api::enroll).delete(api::unenroll),
+            )
+            .route("/tn-api/api/MyEnrollments", get(api::my_enrollments))
+            .route("/asvz_api/event_search", get(api::event_search))
+            .route("/asvz_api/sport_search", get(api::sport_search))
+            .route("/account/login", get(login::login_page))
+            .route("/Account/Login", post(login::local_login))
+            .route("/Account/ExternalLogin", post(login::external_login))
+            .route("/wayf", get(login::wayf_page).post(login::wayf))
+            .route(
+                "/idp/:idp/sso",
+                get(login::local_storage_page).post(login::idp_sso),
+            )
+            .route("/Shibboleth.sso/SAML2/POST", post(login::saml_post))
+            .route("/connect/authorize", get(login::authorize))
+            .route(
+                "/tn/assets/oidc-login-redirect.html",
+                get(login::oidc_redirect_page),
+            )
+            .layer(middleware::from_fn_with_state(
+                state.clone(),
+                api::date_header,
+            ))
+            .with_state(state.clone());
+
+        let server = axum::Server::from_tcp(listener)
+            .expect("Unable to start mock server")
+            .serve(app.into_make_service());
+        let handle = tokio::spawn(async move {
+            server.await.expect("Mock server failed");
+        });
+
+        Self {
+            addr,
+            state,
+            handle,
+        }
+    }
+
+    pub fn url(&self) -> Url {
+        Url::parse(&format!("http://{}/", self.addr)).unwrap()
+    }
+
+    /// Urls that point every ASVZ host at this server.
+    pub fn urls(&self) -> AsvzUrls {
+        AsvzUrls {
+            www: self.url(),
+            schalter: self.url(),
+            auth: self.url(),
+        }
+    }
+
+    /// Adds an ETH user.
+    pub fn add_user(&self, username: impl Into<String>, password: impl Into<String>) {
+        self.add_user_at(IdentityProvider::Eth, username, password);
+    }
+
+    /// Adds a user who logs in with the given identity provider.
+    pub fn add_user_at(
+        &self,
+        idp: IdentityProvider,
+        username: impl Into<String>,
+        password: impl Into<String>,
+    ) {
+        self.add_user_with(LoginMethod::SwitchAai(idp), username, password);
+    }
+
+    pub fn add_user_with(
+        &self,
+        method: LoginMethod,
+        username: impl Into<String>,
+        password: impl Into<String>,
+    ) {
+        self.state
+            .lock()
+            .unwrap()
+            .users
+            .insert(username.into(), (password.into(), method));
+    }
+
+    /// Rejects all enrollments of the user, as if their membership expired.
+    pub fn expire_membership(&self, username: impl Into<String>) {
+        self.state
+            .lock()
+            .unwrap()
+            .expired_memberships
+            .insert(username.into());
+    }
+
+    pub fn add_lesson(&self, lesson: MockLesson) {
+        let entry = LessonEntry {
+            lesson,
+            polls: 0,
+            enroll_attempts: Vec::new(),
+            enrolled: Vec::new(),
+        };
+        self.state
+            .lock()
+            .unwrap()
+            .lessons
+            .insert(entry.lesson.id, entry);
+    }
+
+    /// How often the lesson data was fetched.
+    pub fn lesson_polls(&self, id: u64) -> usize {
+        self.with_lesson(id, |entry| entry.polls)
+    }
+
+    pub fn enroll_attempts(&self, id: u64) -> Vec<EnrollAttempt> {
+        self.with_lesson(id, |entry| entry.enroll_attempts.clone())
+    }
+
+    /// The usernames of everyone enrolled by the mock server.
+    pub fn enrolled(&self, id: u64) -> Vec<String> {
+        self.with_lesson(id, |entry| {
+            entry
+                .enrolled
+                .iter()
+                .map(|(username, _)| username.clone())
+                .collect()
+        })
+    }
+
+    /// How often someone logged in successfully at the IdP.
+    pub fn logins(&self) -> usize {
+        self.state.lock().unwrap().logins
+    }
+
+    /// Lets the server clock run ahead of the local clock by `offset`, or behind if it's negative.
+    /// The enrollment deadlines of the lessons are checked against the server clock.
+    pub fn set_clock_offset(&self, offset: chrono::Duration) {
+        self.state.lock().unwrap().clock_offset = offset;
+    }
+
+    /// How long newly issued access tokens are valid, 2 hours by default.
+    pub fn set_token_lifetime(&self, lifetime: Duration) {
+        self.state.lock().unwrap().token_lifetime =
+            chrono::Duration::from_std(lifetime).expect("Duration out of range");
+    }
+
+    /// Invalidates all access tokens, as if they expired.
+    pub fn expire_tokens(&self) {
+        self.state.lock().unwrap().tokens.clear();
+    }
+
+    fn with_lesson<T>(&self, id: u64, f: impl FnOnce(&LessonEntry) -> T) -> T {
+        let state = self.state.lock().unwrap();
+        let entry = state
+            .lessons
+            .get(&id)
+            .unwrap_or_else(|| panic!("Unknown lesson {}", id));
+        f(entry)
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
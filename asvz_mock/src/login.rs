@@ -0,0 +1,275 @@
+//! The SwitchAAI (SAML) and OpenID Connect login dance, as walked by `AsvzClient::login`.
+//!
+//! 1. `GET /account/login` returns a page with a verification token.
+//!    ASVZ accounts `POST /Account/Login` with their credentials and continue at 7.
+//! 2. `POST /Account/ExternalLogin` redirects to the WAYF (where are you from) page.
+//! 3. `POST /wayf` redirects to the selected IdP (`/idp/{eth,uzh,eduid}/sso`),
+//!    which answers with a local storage form.
+//! 4. `POST /idp/:idp/sso` without credentials returns the login form.
+//! 5. `POST /idp/:idp/sso` with credentials returns the SAML response form.
+//!    edu-ID asks for the username first and for the password on a second page.
+//! 6. `POST /Shibboleth.sso/SAML2/POST` starts a session on the ASVZ side.
+//! 7. `GET /connect/authorize` redirects to the redirect uri with the access token in the fragment.
+
+use std::collections::HashMap;
+
+use axum::extract::{Path, Query, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{Html, IntoResponse, Redirect, Response};
+use axum::Form;
+
+use asvz::login::{IdentityProvider, LoginMethod};
+
+use crate::SharedState;
+
+pub(crate) const SESSION_COOKIE: &str = "mock_session";
+pub(crate) const UNKNOWN_USER_MSG: &str = "The username you entered cannot be identified.";
+pub(crate) const WRONG_PASSWORD_MSG: &str = "The password you entered was incorrect.";
+
+const VERIFICATION_TOKEN: &str = "mock-verification-token";
+
+pub(crate) fn session_user(state: &SharedState, headers: &HeaderMap) -> Option<String> {
+    let cookies = headers.get(header::COOKIE)?.to_str().ok()?;
+    let session = cookies
+        .split(';')
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(name, _)| *name == SESSION_COOKIE)?
+        .1;
+    state.lock().unwrap().sessions.get(session).cloned()
+}
+
+pub(crate) async fn login_page(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+) -> Html<String> {
+    if session_user(&state, &headers).is_some() {
+        Html(r#"<form action="/Account/Logout" method="post"></form>"#.to_string())
+    } else {
+        Html(local_login_page(None))
+    }
+}
+
+fn local_login_page(error: Option<&str>) -> String {
+    let error = error
+        .map(|msg| format!(r#"<div class="validation-summary-errors">{}</div>"#, msg))
+        .unwrap_or_default();
+    format!(
+        r#"{error}
+<form action="/Account/Login" method="post">
+<input name="__RequestVerificationToken" type="hidden" value="{token}" />
+<input id="AsvzId" name="AsvzId" type="text" />
+<input id="Password" name="Password" type="password" />
+<button type="submit" name="button" value="login">Login</button>
+</form>
+<form action="/Account/ExternalLogin" method="post">
+<input name="__RequestVerificationToken" type="hidden" value="{token}" />
+<button type="submit" name="provider" value="SwitchAai">SwitchAAI</button>
+</form>"#,
+        error = error,
+        token = VERIFICATION_TOKEN
+    )
+}
+
+/// The login of ASVZ accounts, which starts a session right away.
+pub(crate) async fn local_login(
+    State(state): State<SharedState>,
+    Form(form): Form<HashMap<String, String>>,
+) -> Response {
+    let field = |name: &str| form.get(name).map(String::as_str);
+    if field("__RequestVerificationToken") != Some(VERIFICATION_TOKEN)
+        || field("button") != Some("login")
+    {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+    let (username, password) = match (field("AsvzId"), field("Password")) {
+        (Some(username), Some(password)) => (username, password),
+        _ => return StatusCode::BAD_REQUEST.into_response(),
+    };
+
+    let mut state = state.lock().unwrap();
+    match state.users.get(username) {
+        Some((expected, LoginMethod::AsvzAccount)) if expected == password => {
+            state.logins += 1;
+            let session = state.new_session(username.to_string());
+            (
+                [(
+                    header::SET_COOKIE,
+                    format!("{}={}; Path=/", SESSION_COOKIE, session),
+                )],
+                Redirect::to("/account/login"),
+            )
+                .into_response()
+        }
+        _ => Html(local_login_page(Some("Invalid username or password"))).into_response(),
+    }
+}
+
+pub(crate) async fn external_login(Form(form): Form<HashMap<String, String>>) -> Response {
+    if form.get("__RequestVerificationToken").map(String::as_str) != Some(VERIFICATION_TOKEN) {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+    Redirect::to("/wayf").into_response()
+}
+
+pub(crate) async fn wayf_page() -> Html<&'static str> {
+    Html(r#"<form id="IdPList" action="/wayf" method="post">"#)
+}
+
+pub(crate) async fn wayf(Form(form): Form<HashMap<String, String>>) -> Response {
+    let idp = match form
+        .get("user_idp")
+        .and_then(|entity_id| idp_path(entity_id))
+    {
+        Some(idp) => idp,
+        None => return StatusCode::BAD_REQUEST.into_response(),
+    };
+    Redirect::to(&format!("/idp/{}/sso", idp)).into_response()
+}
+
+const IDENTITY_PROVIDERS: [(&str, IdentityProvider); 3] = [
+    ("eth", IdentityProvider::Eth),
+    ("uzh", IdentityProvider::Uzh),
+    ("eduid", IdentityProvider::EduId),
+];
+
+fn idp_path(entity_id: &str) -> Option<&'static str> {
+    IDENTITY_PROVIDERS
+        .iter()
+        .find(|(_, idp)| idp.entity_id() == entity_id)
+        .map(|(path, _)| *path)
+}
+
+fn idp_from_path(path: &str) -> Option<IdentityProvider> {
+    IDENTITY_PROVIDERS
+        .iter()
+        .find(|(idp_path, _)| *idp_path == path)
+        .map(|(_, idp)| *idp)
+}
+
+pub(crate) async fn local_storage_page() -> Html<&'static str> {
+    Html(r#"<form id="local-storage" action="sso" method="post">"#)
+}
+
+pub(crate) async fn idp_sso(
+    State(state): State<SharedState>,
+    Path(idp): Path<String>,
+    Query(query): Query<HashMap<String, String>>,
+    Form(form): Form<HashMap<String, String>>,
+) -> Response {
+    let idp = match idp_from_path(&idp) {
+        Some(idp) => idp,
+        None => return StatusCode::NOT_FOUND.into_response(),
+    };
+    let mut state = state.lock().unwrap();
+    // Users of other identity providers are unknown here
+    let user_password = |username: &String| match state.users.get(username) {
+        Some((password, method)) if *method == LoginMethod::SwitchAai(idp) => {
+            Some(password.clone())
+        }
+        _ => None,
+    };
+
+    let username = form.get("j_username").or_else(|| query.get("user"));
+    let (username, password) = match (username, form.get("j_password")) {
+        (Some(username), Some(password)) => (username, password),
+        (Some(username), None) if idp == IdentityProvider::EduId => {
+            return match user_password(username) {
+                Some(_) => Html(password_form(username)).into_response(),
+                None => Html(login_form(idp, Some(UNKNOWN_USER_MSG))).into_response(),
+            };
+        }
+        _ => return Html(login_form(idp, None)).into_response(),
+    };
+
+    match user_password(username) {
+        None => Html(login_form(idp, Some(UNKNOWN_USER_MSG))).into_response(),
+        Some(expected) if expected != *password => {
+            Html(login_form(idp, Some(WRONG_PASSWORD_MSG))).into_response()
+        }
+        Some(_) => {
+            state.logins += 1;
+            Html(format!(
+                r#"<form action="{}Shibboleth.sso/SAML2/POST" method="post">
+<input type="hidden" name="RelayState" value="mock-relay-state"/>
+<input type="hidden" name="SAMLResponse" value="{}"/>
+</form>"#,
+                state.base_url, username
+            ))
+            .into_response()
+        }
+    }
+}
+
+fn password_form(username: &str) -> String {
+    let username: String = url::form_urlencoded::byte_serialize(username.as_bytes()).collect();
+    format!(
+        r#"<form id="password" action="sso?user={}" method="post">
+<input id="password" name="j_password" type="password"/>
+</form>"#,
+        username
+    )
+}
+
+fn login_form(idp: IdentityProvider, error: Option<&str>) -> String {
+    let error = error
+        .map(|msg| format!(r#"<p class="form-element form-error">{}</p>"#, msg))
+        .unwrap_or_default();
+    let password = if idp == IdentityProvider::EduId {
+        ""
+    } else {
+        r#"<input id="password" name="j_password" type="password"/>"#
+    };
+    format!(
+        r#"<form id="login" action="sso" method="post">
+{}
+<input id="username" name="j_username" type="text"/>
+{}
+</form>"#,
+        error, password
+    )
+}
+
+pub(crate) async fn saml_post(
+    State(state): State<SharedState>,
+    Form(form): Form<HashMap<String, String>>,
+) -> Response {
+    let username = match form.get("SAMLResponse") {
+        Some(username) => username.clone(),
+        None => return StatusCode::BAD_REQUEST.into_response(),
+    };
+    let session = state.lock().unwrap().new_session(username);
+    (
+        [(
+            header::SET_COOKIE,
+            format!("{}={}; Path=/", SESSION_COOKIE, session),
+        )],
+        "",
+    )
+        .into_response()
+}
+
+pub(crate) async fn authorize(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+    Query(query): Query<HashMap<String, String>>,
+) -> Response {
+    let username = match session_user(&state, &headers) {
+        Some(username) => username,
+        None => return StatusCode::UNAUTHORIZED.into_response(),
+    };
+    let redirect_uri = match query.get("redirect_uri") {
+        Some(redirect_uri) => redirect_uri,
+        None => return StatusCode::BAD_REQUEST.into_response(),
+    };
+    let token = state.lock().unwrap().new_token(username);
+    let state_param = query.get("state").cloned().unwrap_or_default();
+    Redirect::to(&format!(
+        "{}#access_token={}&token_type=Bearer&state={}",
+        redirect_uri, token, state_param
+    ))
+    .into_response()
+}
+
+pub(crate) async fn oidc_redirect_page() -> Html<&'static str> {
+    Html("<html></html>")
+}
@@ -0,0 +1,117 @@
+use std::net::{SocketAddr, TcpListener};
+use std::sync::{Arc, Mutex};
+
+use axum::extract::{Path, State};
+use axum::routing::post;
+use axum::{Json, Router};
+use chrono::Utc;
+use serde_json::{json, Value};
+use tokio::task::JoinHandle;
+use url::Url;
+
+/// A request the bot sent to the Telegram api.
+#[derive(Debug, Clone)]
+pub struct TelegramRequest {
+    /// The method in lowercase, e.g. `sendmessage`.
+    pub method: String,
+    pub body: Value,
+}
+
+#[derive(Debug, Default)]
+struct Requests {
+    requests: Vec<TelegramRequest>,
+    /// How many of the next requests are answered with a `retry_after`, and its seconds.
+    rate_limited: (u32, u32),
+}
+
+type SharedRequests = Arc<Mutex<Requests>>;
+
+/// A stand-in for the Telegram bot api that accepts every request and records it.
+/// Point the bot at it with `Bot::new(token).set_api_url(telegram.url())`.
+pub struct MockTelegram {
+    addr: SocketAddr,
+    requests: SharedRequests,
+    handle: JoinHandle<()>,
+}
+
+impl MockTelegram {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Unable to bind mock telegram");
+        let addr = listener.local_addr().unwrap();
+        let requests = SharedRequests::default();
+
+        let app = Router::new()
+            .route("/:token/:method", post(request))
+            .with_state(requests.clone());
+        let server = axum::Server::from_tcp(listener)
+            .expect("Unable to start mock telegram")
+            .serve(app.into_make_service());
+        let handle = tokio::spawn(async move {
+            server.await.expect("Mock telegram failed");
+        });
+
+        Self {
+            addr,
+            requests,
+            handle,
+        }
+    }
+
+    pub fn url(&self) -> Url {
+        Url::parse(&format!("http://{}/", self.addr)).unwrap()
+    }
+
+    pub fn requests(&self) -> Vec<TelegramRequest> {
+        self.requests.lock().unwrap().requests.clone()
+    }
+
+    /// Answers the next `requests` requests with a `retry_after` of `secs` seconds,
+    /// as Telegram does when the bot sends too many messages. They are not recorded.
+    pub fn rate_limit(&self, requests: u32, secs: u32) {
+        self.requests.lock().unwrap().rate_limited = (requests, secs);
+    }
+
+    /// The texts of all sent messages, in the order they were sent.
+    pub fn messages(&self) -> Vec<String> {
+        self.requests()
+            .into_iter()
+            .filter(|request| request.method == "sendmessage")
+            .filter_map(|request| Some(request.body.get("text")?.as_str()?.to_string()))
+            .collect()
+    }
+}
+
+impl Drop for MockTelegram {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+async fn request(
+    State(requests): State<SharedRequests>,
+    Path((_token, method)): Path<(String, String)>,
+    Json(body): Json<Value>,
+) -> Json<Value> {
+    let method = method.to_lowercase();
+    let mut requests = requests.lock().unwrap();
+    if let (remaining @ 1.., secs) = requests.rate_limited {
+        requests.rate_limited = (remaining - 1, secs);
+        return Json(json!({
+            "ok": false,
+            "error_code": 429,
+            "description": format!("Too Many Requests: retry after {}", secs),
+            "parameters": { "retry_after": secs },
+        }));
+    }
+    let result = match method.as_str() {
+        "sendmessage" | "editmessagetext" => json!({
+            "message_id": requests.requests.len() + 1,
+            "date": Utc::now().timestamp(),
+            "chat": { "id": body["chat_id"], "type": "private", "first_name": "User" },
+            "text": body["text"],
+        }),
+        _ => json!(true),
+    };
+    requests.requests.push(TelegramRequest { method, body });
+    Json(json!({ "ok": true, "result": result }))
+}
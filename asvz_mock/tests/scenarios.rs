@@ -0,0 +1,383 @@
+use std::str::FromStr;
+use std::time::Duration;
+
+use chrono::{Local, Utc};
+use reqwest_middleware::ClientBuilder;
+
+use asvz::api::lesson::{LessonStatus, RegistrationType};
+use asvz::client::AsvzClient;
+use asvz::enrollment::{EnrollmentRejection, EnrollmentResponse, UnenrollmentResponse};
+use asvz::error::AsvzError;
+use asvz::lesson::{LessonID, SearchQuery};
+use asvz::login::{token_expiry, IdentityProvider, LoginMethod};
+use asvz_mock::{MockLesson, MockServer, StatusCode};
+
+const ETH: LoginMethod = LoginMethod::SwitchAai(IdentityProvider::Eth);
+
+fn client(server: &MockServer) -> AsvzClient {
+    let client = reqwest::Client::builder()
+        .cookie_store(true)
+        .build()
+        .unwrap();
+    AsvzClient::with_urls(ClientBuilder::new(client).build(), server.urls())
+}
+
+fn lesson_id(id: u64) -> LessonID {
+    LessonID::from_str(&id.to_string()).unwrap()
+}
+
+#[tokio::test]
+async fn place_frees_after_three_polls() {
+    let server = MockServer::start().await;
+    server.add_lesson(MockLesson::new(1).places(20).participants([20, 20, 20, 19]));
+    let client = client(&server);
+
+    let mut free_places = Vec::new();
+    for _ in 0..5 {
+        let data = client.lesson_data(&lesson_id(1)).await.unwrap().data;
+        free_places.push(data.participants_max - data.participant_count);
+    }
+
+    assert_eq!(free_places, [0, 0, 0, 1, 1]);
+    assert_eq!(server.lesson_polls(1), 5);
+}
+
+#[tokio::test]
+async fn cancelled_on_site_lesson() {
+    let server = MockServer::start().await;
+    server.add_lesson(
+        MockLesson::new(1)
+            .registration(RegistrationType::OnSite)
+            .cancelled("Instructor is sick"),
+    );
+
+    let data = client(&server)
+        .lesson_data(&lesson_id(1))
+        .await
+        .unwrap()
+        .data;
+
+    assert!(data.is_cancelled());
+    assert_eq!(data.status, LessonStatus::Cancelled);
+    assert_eq!(data.web_registration_type, RegistrationType::OnSite);
+    assert_eq!(data.cancellation_reason(), Some("Instructor is sick"));
+}
+
+#[tokio::test]
+async fn login_dance() {
+    let server = MockServer::start().await;
+    server.add_user("user", "password");
+
+    let token = client(&server)
+        .login(ETH, "user", "password")
+        .await
+        .unwrap();
+
+    assert!(!token.is_empty());
+    assert_eq!(server.logins(), 1);
+}
+
+#[tokio::test]
+async fn token_expiry_and_login_cookies() {
+    let server = MockServer::start().await;
+    server.add_user("user", "password");
+    server.set_token_lifetime(Duration::from_secs(60 * 60));
+    let client = client(&server);
+
+    let token = client.login(ETH, "user", "password").await.unwrap();
+    let expires = token_expiry(&token).unwrap();
+    let lifetime = expires - Utc::now();
+    assert!(lifetime > chrono::Duration::minutes(59));
+    assert!(lifetime <= chrono::Duration::minutes(60));
+
+    // The cookies of the first login skip the IdP
+    let second_token = client.login(ETH, "user", "password").await.unwrap();
+    assert_ne!(token, second_token);
+    assert_eq!(server.logins(), 1);
+}
+
+#[tokio::test]
+async fn login_with_wrong_credentials_fails() {
+    let server = MockServer::start().await;
+    server.add_user("user", "password");
+
+    let wrong_password = client(&server).login(ETH, "user", "wrong").await;
+    let unknown_user = client(&server).login(ETH, "nobody", "password").await;
+
+    assert!(matches!(wrong_password, Err(AsvzError::WrongPassword)));
+    assert!(matches!(unknown_user, Err(AsvzError::UnknownUser)));
+    assert_eq!(server.logins(), 0);
+}
+
+#[tokio::test]
+async fn login_at_other_identity_providers() {
+    let server = MockServer::start().await;
+    server.add_user_at(IdentityProvider::Uzh, "uzh_user", "password");
+    server.add_user_at(IdentityProvider::EduId, "edu@example.com", "password");
+
+    let uzh = client(&server)
+        .login(
+            LoginMethod::SwitchAai(IdentityProvider::Uzh),
+            "uzh_user",
+            "password",
+        )
+        .await;
+    let edu_id = client(&server)
+        .login(
+            LoginMethod::SwitchAai(IdentityProvider::EduId),
+            "edu@example.com",
+            "password",
+        )
+        .await;
+    let edu_id_wrong_password = client(&server)
+        .login(
+            LoginMethod::SwitchAai(IdentityProvider::EduId),
+            "edu@example.com",
+            "wrong",
+        )
+        .await;
+    let wrong_idp = client(&server).login(ETH, "uzh_user", "password").await;
+
+    assert!(uzh.is_ok());
+    assert!(edu_id.is_ok());
+    assert!(matches!(
+        edu_id_wrong_password,
+        Err(AsvzError::WrongPassword)
+    ));
+    assert!(matches!(wrong_idp, Err(AsvzError::UnknownUser)));
+    assert_eq!(server.logins(), 2);
+}
+
+#[tokio::test]
+async fn login_with_an_asvz_account() {
+    let server = MockServer::start().await;
+    server.add_user_with(LoginMethod::AsvzAccount, "alumnus", "password");
+    server.add_user("eth_user", "password");
+
+    let asvz = client(&server)
+        .login(LoginMethod::AsvzAccount, "alumnus", "password")
+        .await;
+    let wrong_password = client(&server)
+        .login(LoginMethod::AsvzAccount, "alumnus", "wrong")
+        .await;
+    let eth_user = client(&server)
+        .login(LoginMethod::AsvzAccount, "eth_user", "password")
+        .await;
+
+    let token = asvz.unwrap();
+    assert!(token_expiry(&token).is_some());
+    assert!(matches!(wrong_password, Err(AsvzError::WrongCredentials)));
+    assert!(matches!(eth_user, Err(AsvzError::WrongCredentials)));
+    assert_eq!(server.logins(), 1);
+}
+
+#[tokio::test]
+async fn enrollment_opens_in_two_seconds_and_is_rate_limited_twice() {
+    let server = MockServer::start().await;
+    server.add_user("user", "password");
+    server.add_lesson(
+        MockLesson::new(1)
+            .enrollment_opens_in(Duration::from_secs(2))
+            .enroll_responses([StatusCode::TOO_MANY_REQUESTS, StatusCode::TOO_MANY_REQUESTS]),
+    );
+    let client = client(&server);
+    let token = client.login(ETH, "user", "password").await.unwrap();
+    let opens = Local::now().fixed_offset() + chrono::Duration::seconds(2);
+
+    loop {
+        match client.enroll(&token, &lesson_id(1)).await.unwrap() {
+            EnrollmentResponse::Enrolled(data) => {
+                assert_eq!(data.unwrap().data.place_number, 6);
+                break;
+            }
+            EnrollmentResponse::Rejected(_) | EnrollmentResponse::TooManyRequests => {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+            }
+            EnrollmentResponse::Unexpected(code) => panic!("Unexpected status code {}", code),
+        }
+    }
+
+    let attempts = server.enroll_attempts(1);
+    let after_opening: Vec<_> = attempts
+        .iter()
+        .filter(|attempt| attempt.status != StatusCode::UNPROCESSABLE_ENTITY)
+        .collect();
+    assert_eq!(after_opening.len(), 3);
+    assert!(after_opening
+        .iter()
+        .all(|attempt| attempt.at >= opens - chrono::Duration::milliseconds(50)));
+    assert_eq!(after_opening[0].status, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(after_opening[1].status, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(after_opening[2].status, StatusCode::CREATED);
+    assert_eq!(server.enrolled(1), ["user"]);
+}
+
+#[tokio::test]
+async fn enrollment_rejection_reasons() {
+    let server = MockServer::start().await;
+    server.add_user("user", "password");
+    server.add_user("other", "password");
+    server.add_user("expired", "password");
+    server.expire_membership("expired");
+    server.add_lesson(MockLesson::new(1).places(6));
+    server.add_lesson(MockLesson::new(2).enrollment_opens_in(Duration::from_secs(60)));
+    server.add_lesson(MockLesson::new(3).cancelled("Pool closed"));
+    let token = client(&server)
+        .login(ETH, "user", "password")
+        .await
+        .unwrap();
+    let other = client(&server)
+        .login(ETH, "other", "password")
+        .await
+        .unwrap();
+    let expired = client(&server)
+        .login(ETH, "expired", "password")
+        .await
+        .unwrap();
+    let client = client(&server);
+
+    let rejection = |response| match response {
+        EnrollmentResponse::Rejected(rejection) => rejection,
+        response => panic!("Unexpected response {:?}", response),
+    };
+    let response = client.enroll(&expired, &lesson_id(1)).await.unwrap();
+    assert_eq!(rejection(response), EnrollmentRejection::MembershipExpired);
+    let response = client.enroll(&token, &lesson_id(1)).await.unwrap();
+    assert!(matches!(response, EnrollmentResponse::Enrolled(_)));
+    let response = client.enroll(&token, &lesson_id(1)).await.unwrap();
+    assert_eq!(rejection(response), EnrollmentRejection::AlreadyEnrolled);
+    let response = client.enroll(&other, &lesson_id(1)).await.unwrap();
+    assert_eq!(rejection(response), EnrollmentRejection::Full);
+    let response = client.enroll(&token, &lesson_id(2)).await.unwrap();
+    assert_eq!(rejection(response), EnrollmentRejection::NotOpenYet);
+    let response = client.enroll(&token, &lesson_id(3)).await.unwrap();
+    assert_eq!(rejection(response), EnrollmentRejection::Cancelled);
+}
+
+#[tokio::test]
+async fn unenroll_before_and_after_the_deadline() {
+    let server = MockServer::start().await;
+    server.add_user("user", "password");
+    server.add_lesson(MockLesson::new(1));
+    server.add_lesson(MockLesson::new(2).cancelation_closes_in(Duration::from_secs(1)));
+    let client = client(&server);
+    let token = client.login(ETH, "user", "password").await.unwrap();
+
+    for id in [1, 2] {
+        let response = client.enroll(&token, &lesson_id(id)).await.unwrap();
+        assert!(matches!(response, EnrollmentResponse::Enrolled(_)));
+    }
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    let response = client.unenroll(&token, &lesson_id(1)).await.unwrap();
+    assert!(matches!(response, UnenrollmentResponse::Unenrolled));
+    let response = client.unenroll(&token, &lesson_id(1)).await.unwrap();
+    assert!(matches!(response, UnenrollmentResponse::NotEnrolled));
+    let response = client.unenroll(&token, &lesson_id(2)).await.unwrap();
+    assert!(matches!(response, UnenrollmentResponse::Rejected));
+
+    assert!(server.enrolled(1).is_empty());
+    assert_eq!(server.enrolled(2), ["user"]);
+}
+
+#[tokio::test]
+async fn my_enrollments_are_sorted() {
+    let server = MockServer::start().await;
+    server.add_user("user", "password");
+    server.add_lesson(MockLesson::new(1).starts_in(Duration::from_secs(60 * 60 * 48)));
+    server.add_lesson(MockLesson::new(2).starts_in(Duration::from_secs(60 * 60 * 24)));
+    server.add_lesson(MockLesson::new(3));
+    let client = client(&server);
+    let token = client.login(ETH, "user", "password").await.unwrap();
+    for id in [1, 2] {
+        client.enroll(&token, &lesson_id(id)).await.unwrap();
+    }
+
+    let enrollments = client.my_enrollments(&token).await.unwrap();
+
+    let ids: Vec<_> = enrollments.iter().map(|e| e.lesson_id).collect();
+    assert_eq!(ids, [2, 1]);
+    assert!(enrollments.iter().all(|e| e.place_number == 6));
+}
+
+#[tokio::test]
+async fn expired_token_is_unauthorized() {
+    let server = MockServer::start().await;
+    server.add_user("user", "password");
+    server.add_lesson(MockLesson::new(1));
+    let client = client(&server);
+    let token = client.login(ETH, "user", "password").await.unwrap();
+
+    server.expire_tokens();
+
+    match client.enroll(&token, &lesson_id(1)).await.unwrap() {
+        EnrollmentResponse::Unexpected(code) => assert_eq!(code, StatusCode::UNAUTHORIZED),
+        response => panic!("Unexpected response {:?}", response),
+    }
+}
+
+#[tokio::test]
+async fn login_again_after_the_token_expired() {
+    let server = MockServer::start().await;
+    server.add_user("user", "password");
+    server.add_lesson(MockLesson::new(1));
+    let client = client(&server);
+    let expired = client.login(ETH, "user", "password").await.unwrap();
+
+    server.expire_tokens();
+    let token = client.login(ETH, "user", "password").await.unwrap();
+
+    let response = client.enroll(&expired, &lesson_id(1)).await.unwrap();
+    assert!(matches!(response, EnrollmentResponse::Unexpected(_)));
+    match client.enroll(&token, &lesson_id(1)).await.unwrap() {
+        EnrollmentResponse::Enrolled(_) => (),
+        response => panic!("Unexpected response {:?}", response),
+    }
+    assert_eq!(server.enrolled(1), ["user"]);
+}
+
+#[tokio::test]
+async fn clock_offset_of_a_drifting_server() {
+    let server = MockServer::start().await;
+    let drift = chrono::Duration::milliseconds(1300);
+    server.set_clock_offset(drift);
+
+    let offset = client(&server).clock_offset(5).await.unwrap();
+
+    let error = (offset.estimate() - drift).num_milliseconds().abs();
+    assert!(error <= offset.uncertainty().num_milliseconds() + 10);
+    assert!(offset.uncertainty() < chrono::Duration::milliseconds(250));
+}
+
+#[tokio::test]
+async fn search_finds_next_weeks_lesson() {
+    let server = MockServer::start().await;
+    server.add_lesson(MockLesson::new(1).starts_in(Duration::from_secs(60 * 60 * 24)));
+    server.add_lesson(MockLesson::new(2).starts_in(Duration::from_secs(60 * 60 * 24 * 8)));
+    server.add_lesson(
+        MockLesson::new(3)
+            .facility(1, "Sport Center Irchel")
+            .starts_in(Duration::from_secs(60 * 60 * 24 * 8)),
+    );
+
+    let event_list = client(&server).search_data(&lesson_id(1), 1).await.unwrap();
+
+    assert_eq!(event_list.lesson_id(), Some(lesson_id(2)));
+}
+
+#[tokio::test]
+async fn search_all_paginates() {
+    let server = MockServer::start().await;
+    for id in 1..=5 {
+        server.add_lesson(MockLesson::new(id).starts_in(Duration::from_secs(60 * 60 * id)));
+    }
+    let client = client(&server);
+
+    let query = SearchQuery::new().sport("1").limit(2);
+    let all = client.search_all(&query, 10).await.unwrap();
+    let some = client.search_all(&query, 3).await.unwrap();
+
+    let ids: Vec<_> = all.iter().filter_map(|result| result.lesson_id()).collect();
+    assert_eq!(ids, (1..=5).map(lesson_id).collect::<Vec<_>>());
+    assert_eq!(some.len(), 3);
+}
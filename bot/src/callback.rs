@@ -0,0 +1,202 @@
+use std::fmt;
+use std::fmt::Formatter;
+use std::str::FromStr;
+
+use teloxide::types::InlineKeyboardButton;
+
+use asvz::lesson::LessonID;
+
+use crate::job::JobId;
+use crate::user::SettingKind;
+
+/// What a button under the `/jobs` message does with its job.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum JobAction {
+    Cancel,
+    Pause,
+    Resume,
+    Details,
+}
+
+impl JobAction {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Cancel => "cancel",
+            Self::Pause => "pause",
+            Self::Resume => "resume",
+            Self::Details => "details",
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            Self::Cancel => "Cancel",
+            Self::Pause => "Pause",
+            Self::Resume => "Resume",
+            Self::Details => "Details",
+        }
+    }
+}
+
+impl FromStr for JobAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cancel" => Ok(Self::Cancel),
+            "pause" => Ok(Self::Pause),
+            "resume" => Ok(Self::Resume),
+            "details" => Ok(Self::Details),
+            _ => Err(format!("Unknown job action: {}", s)),
+        }
+    }
+}
+
+/// What a button under a search result starts for its lesson.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LessonAction {
+    Notify,
+    Enroll,
+}
+
+impl LessonAction {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Notify => "notify",
+            Self::Enroll => "enroll",
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            Self::Notify => "Notify",
+            Self::Enroll => "Enroll",
+        }
+    }
+}
+
+impl FromStr for LessonAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "notify" => Ok(Self::Notify),
+            "enroll" => Ok(Self::Enroll),
+            _ => Err(format!("Unknown lesson action: {}", s)),
+        }
+    }
+}
+
+/// The data attached to an inline keyboard button.
+/// Telegram limits it to 64 bytes, so it is kept as a short string like `job:pause:3`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CallbackData {
+    Job(JobAction, JobId),
+    Lesson(LessonAction, LessonID),
+    /// Changes a setting to its next value.
+    Setting(SettingKind),
+}
+
+impl CallbackData {
+    pub fn button(&self) -> InlineKeyboardButton {
+        let text = match self {
+            Self::Job(action, id) => format!("{} {}", action.label(), id),
+            Self::Lesson(action, id) => format!("{} {}", action.label(), id.as_str()),
+            Self::Setting(kind) => format!("Change {}", kind.label()),
+        };
+        InlineKeyboardButton::callback(text, self.to_string())
+    }
+}
+
+impl fmt::Display for CallbackData {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Job(action, id) => write!(f, "job:{}:{}", action.as_str(), id.0),
+            Self::Lesson(action, id) => write!(f, "lesson:{}:{}", action.as_str(), id.as_str()),
+            Self::Setting(kind) => write!(f, "setting:{}", kind.as_str()),
+        }
+    }
+}
+
+impl FromStr for CallbackData {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(':').collect();
+        match &*parts {
+            ["job", action, id] => {
+                let id = u32::from_str(id).map_err(|_| format!("Invalid job id: {}", id))?;
+                Ok(Self::Job(JobAction::from_str(action)?, JobId(id)))
+            }
+            ["lesson", action, id] => Ok(Self::Lesson(
+                LessonAction::from_str(action)?,
+                LessonID::from_str(id)?,
+            )),
+            ["setting", kind] => Ok(Self::Setting(SettingKind::from_str(kind)?)),
+            _ => Err(format!("Unknown callback data: {}", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Telegram rejects buttons with more callback data.
+    const MAX_CALLBACK_BYTES: usize = 64;
+
+    fn all_callbacks() -> Vec<CallbackData> {
+        let job_actions = [
+            JobAction::Cancel,
+            JobAction::Pause,
+            JobAction::Resume,
+            JobAction::Details,
+        ];
+        let lesson_id = LessonID::from_str(&u64::MAX.to_string()).unwrap();
+        let jobs = job_actions
+            .into_iter()
+            .flat_map(|action| [0, u32::MAX].map(|id| CallbackData::Job(action, JobId(id))));
+        let lessons = [LessonAction::Notify, LessonAction::Enroll]
+            .into_iter()
+            .map(|action| CallbackData::Lesson(action, lesson_id.clone()));
+        let settings = SettingKind::ALL.into_iter().map(CallbackData::Setting);
+        jobs.chain(lessons).chain(settings).collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        for callback in all_callbacks() {
+            let data = callback.to_string();
+            assert_eq!(CallbackData::from_str(&data), Ok(callback), "{}", data);
+        }
+    }
+
+    #[test]
+    fn fits_into_the_limit_of_telegram() {
+        for callback in all_callbacks() {
+            let data = callback.to_string();
+            assert!(data.len() <= MAX_CALLBACK_BYTES, "{} is too long", data);
+        }
+    }
+
+    #[test]
+    fn invalid_data() {
+        let cases = [
+            "",
+            "job",
+            "job:pause",
+            "job:pause:",
+            "job:pause:-1",
+            "job:pause:4294967296",
+            "job:stop:3",
+            "job:pause:3:4",
+            "lesson:notify:abc",
+            "lesson:unenroll:236310",
+            "setting:colour",
+            "settings:weekly",
+        ];
+        for data in cases {
+            assert!(CallbackData::from_str(data).is_err(), "{:?}", data);
+        }
+    }
+}
@@ -1,14 +1,21 @@
 use std::fmt;
 use std::fmt::Formatter;
 use std::str::FromStr;
+
+use chrono::{Datelike, Duration, Local, NaiveDate};
+use serde::{Deserialize, Serialize};
 use teloxide::utils::command::ParseError;
+use zeroize::Zeroize;
 
 use asvz::lesson::LessonID;
+use asvz::login::LoginMethod;
 use bot_derive::BotCommands;
 
+use crate::config::Config;
 use crate::user::UrlAction;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(transparent)]
 pub struct Username(String);
 
 impl Username {
@@ -29,7 +36,8 @@ impl FromStr for Username {
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(transparent)]
 pub struct Password(String);
 
 impl Password {
@@ -38,6 +46,12 @@ impl Password {
     }
 }
 
+impl Drop for Password {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
 impl fmt::Debug for Password {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         f.debug_tuple("Password").field(&"***").finish()
@@ -56,11 +70,128 @@ impl FromStr for Password {
     }
 }
 
+/// Either the id of a job or the id of a lesson, see `/jobs`.
+#[derive(Clone, Debug)]
+pub struct CancelTarget(u64);
+
+impl CancelTarget {
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+impl FromStr for CancelTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.strip_prefix('#').unwrap_or(s);
+        if s.is_empty() {
+            Err("You need to supply a job_id or a lesson_id!".to_string())
+        } else {
+            u64::from_str(s)
+                .map(Self)
+                .map_err(|_| "A job_id or lesson_id may only contain numbers!".to_string())
+        }
+    }
+}
+
+/// Parses `<username> <password> [method]`, the login method defaults to ETH.
+fn parse_login(s: String) -> Result<(Username, Password, LoginMethod), ParseError> {
+    let args: Vec<_> = s.split_whitespace().collect();
+    let (username, password, method) = match args.as_slice() {
+        [username, password] => (username, password, None),
+        [username, password, method] => (username, password, Some(method)),
+        _ => {
+            let message = "Expected a username, a password and optionally a login method";
+            return Err(if args.len() < 2 {
+                ParseError::TooFewArguments {
+                    expected: 2,
+                    found: args.len(),
+                    message: message.to_string(),
+                }
+            } else {
+                ParseError::TooManyArguments {
+                    expected: 3,
+                    found: args.len(),
+                    message: message.to_string(),
+                }
+            });
+        }
+    };
+    let method = match method {
+        Some(method) => LoginMethod::from_str(method),
+        None => Ok(LoginMethod::default()),
+    };
+    Ok((
+        Username::from_str(username).map_err(custom_err)?,
+        Password::from_str(password).map_err(custom_err)?,
+        method.map_err(custom_err)?,
+    ))
+}
+
+fn custom_err(err: String) -> ParseError {
+    ParseError::Custom(err.into())
+}
+
+/// Parses `<sport> [date]`, where the sport name may contain spaces.
+fn parse_search(s: String) -> Result<(String, Option<NaiveDate>), ParseError> {
+    let s = s.trim();
+    if s.is_empty() {
+        return Err(ParseError::TooFewArguments {
+            expected: 1,
+            found: 0,
+            message: "Expected a sport".to_string(),
+        });
+    }
+    match s.rsplit_once(' ') {
+        Some((sport, date)) => match parse_date(date) {
+            Some(date) => Ok((sport.trim().to_string(), Some(date))),
+            None => Ok((s.to_string(), None)),
+        },
+        None => Ok((s.to_string(), None)),
+    }
+}
+
+/// Accepts `today`, `tomorrow`, `2021-11-08`, `08.11.2021` and `08.11.`
+fn parse_date(s: &str) -> Option<NaiveDate> {
+    let today = Local::now().date_naive();
+    match s.to_lowercase().as_str() {
+        "today" => return Some(today),
+        "tomorrow" => return Some(today + Duration::days(1)),
+        _ => (),
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        return Some(date);
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(s, "%d.%m.%Y") {
+        return Some(date);
+    }
+    // Without a year we take the next matching date
+    let (day, month) = s.strip_suffix('.').unwrap_or(s).split_once('.')?;
+    next_date(u32::from_str(day).ok()?, u32::from_str(month).ok()?, today)
+}
+
+/// The first date with this day and month on or after `today`.
+/// The 29th of February is in the next leap year, which is at most 8 years away.
+fn next_date(day: u32, month: u32, today: NaiveDate) -> Option<NaiveDate> {
+    (today.year()..=today.year() + 8)
+        .filter_map(|year| NaiveDate::from_ymd_opt(year, month, day))
+        .find(|date| *date >= today)
+}
+
 pub trait BotCommands: Sized {
     fn parse(s: &str, bot_username: &str) -> Result<Self, ParseError>;
     fn descriptions() -> String;
 }
 
+/// The descriptions of all commands, with the values of the config filled in.
+pub fn help(config: &Config) -> String {
+    Command::descriptions().replace(
+        "{credential_timeout_days}",
+        &config.credential_timeout_days.to_string(),
+    )
+}
+
 #[derive(Debug, bot_derive::BotCommands)]
 #[command(
     rename = "lowercase",
@@ -98,18 +229,31 @@ pub enum Command {
     EnrollWeekly { lesson_id: LessonID },
 
     #[command(
-        description = " <username> <password> - Stores your username and password, so you can be enrolled automatically. \
-    Important: While your password is never stored in persistent memory, \
+        description = " <lesson_id> - Cancel your enrollment in a lesson.",
+        parse_with = "split"
+    )]
+    Unenroll { lesson_id: LessonID },
+
+    #[command(description = " - Lists the upcoming lessons you are enrolled in.")]
+    MyLessons,
+
+    #[command(
+        description = " <username> <password> [eth|uzh|eduid|asvz] - Checks and stores your username and password, so you can be enrolled automatically. \
+    Use uzh or eduid (e.g. for ZHAW) if you don't log in with an ETH account, or asvz for an ASVZ account. \
+    Important: While your password is only stored encrypted and deleted after {credential_timeout_days} days of inactivity, \
     your are still giving a random person on the internet your password. \
     I wouldn't do it, if I were you :)",
-        parse_with = "split"
+        parse_with = "parse_login"
     )]
     Login {
         username: Username,
         password: Password,
+        method: LoginMethod,
     },
 
-    #[command(description = " - Remove your login credentials.")]
+    #[command(
+        description = " - Remove your login credentials and cancel the enrollment Jobs that need them."
+    )]
     Logout,
 
     #[command(
@@ -121,9 +265,208 @@ pub enum Command {
     )]
     UrlAction { url_action: UrlAction },
 
+    #[command(description = " - Show and change your settings.")]
+    Settings,
+
+    #[command(
+        description = " <sport> [date] - Search for lessons of a sport, e.g. /search Spinning tomorrow.",
+        parse_with = "parse_search"
+    )]
+    Search {
+        sport: String,
+        date: Option<NaiveDate>,
+    },
+
     #[command(description = " - Show your current Jobs.")]
     Jobs,
 
+    #[command(
+        description = " <job_id|lesson_id> - Cancel the Job with this id or all Jobs of this lesson.",
+        parse_with = "split"
+    )]
+    Cancel { target: CancelTarget },
+
     #[command(description = " - Cancel all Jobs.")]
     CancelAll,
 }
+
+#[cfg(test)]
+mod tests {
+    use asvz::login::IdentityProvider;
+
+    use super::*;
+
+    /// The arguments of a parsed login, or the name of the error.
+    fn login(s: &str) -> Result<(String, String, LoginMethod), String> {
+        parse_login(s.to_string())
+            .map(|(username, password, method)| {
+                let password = password.as_str_dangerous().to_string();
+                (username.as_str().to_string(), password, method)
+            })
+            .map_err(|err| match err {
+                ParseError::TooFewArguments { found, .. } => format!("too few: {}", found),
+                ParseError::TooManyArguments { found, .. } => format!("too many: {}", found),
+                ParseError::Custom(err) => err.to_string(),
+                err => panic!("Unexpected error {:?}", err),
+            })
+    }
+
+    fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(year, month, day).unwrap()
+    }
+
+    #[test]
+    fn help_shows_the_configured_credential_timeout() {
+        let config = Config {
+            credential_timeout_days: 7,
+            ..Config::default()
+        };
+
+        let help = help(&config);
+
+        assert!(help.contains("deleted after 7 days of inactivity"));
+        assert!(!help.contains("{credential_timeout_days}"));
+    }
+
+    #[test]
+    fn cancel_target() {
+        let cases = [
+            ("3", Ok(3)),
+            ("#3", Ok(3)),
+            ("236310", Ok(236310)),
+            ("", Err("You need to supply a job_id or a lesson_id!")),
+            ("#", Err("You need to supply a job_id or a lesson_id!")),
+            (
+                "abc",
+                Err("A job_id or lesson_id may only contain numbers!"),
+            ),
+            ("-1", Err("A job_id or lesson_id may only contain numbers!")),
+            (
+                "##3",
+                Err("A job_id or lesson_id may only contain numbers!"),
+            ),
+        ];
+        for (input, expected) in cases {
+            let target = CancelTarget::from_str(input).map(|target| target.as_u64());
+            assert_eq!(target, expected.map_err(str::to_string), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn parse_login_table() {
+        let eth = LoginMethod::SwitchAai(IdentityProvider::Eth);
+        let ok = |method| Ok(("user".to_string(), "secret".to_string(), method));
+        let cases = [
+            ("user secret", ok(eth)),
+            ("user secret eth", ok(eth)),
+            (
+                "user secret uzh",
+                ok(LoginMethod::SwitchAai(IdentityProvider::Uzh)),
+            ),
+            (
+                "user secret EduID",
+                ok(LoginMethod::SwitchAai(IdentityProvider::EduId)),
+            ),
+            ("user secret asvz", ok(LoginMethod::AsvzAccount)),
+            ("  user \t secret  \n", ok(eth)),
+            ("", Err("too few: 0".to_string())),
+            ("   ", Err("too few: 0".to_string())),
+            ("user", Err("too few: 1".to_string())),
+            ("user secret eth more", Err("too many: 4".to_string())),
+            (
+                "user secret epfl",
+                Err("Use one of following: eth, uzh, eduid, asvz".to_string()),
+            ),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(login(input), expected, "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn parse_search_table() {
+        let cases = [
+            ("Spinning", Ok(("Spinning", None))),
+            (
+                "Spinning 2021-11-08",
+                Ok(("Spinning", Some(date(2021, 11, 8)))),
+            ),
+            (
+                "Beach Volleyball 08.11.2021",
+                Ok(("Beach Volleyball", Some(date(2021, 11, 8)))),
+            ),
+            (
+                "  Spinning   2021-11-08  ",
+                Ok(("Spinning", Some(date(2021, 11, 8)))),
+            ),
+            // Something that isn't a date is part of the sport
+            ("Spinning 2021-13-08", Ok(("Spinning 2021-13-08", None))),
+            ("Spinning 31.02.2021", Ok(("Spinning 31.02.2021", None))),
+            ("Crossfit 2", Ok(("Crossfit 2", None))),
+            ("", Err(0)),
+            ("   ", Err(0)),
+        ];
+        for (input, expected) in cases {
+            let result = match parse_search(input.to_string()) {
+                Ok((sport, date)) => Ok((sport, date)),
+                Err(ParseError::TooFewArguments { found, .. }) => Err(found),
+                Err(err) => panic!("Unexpected error {:?}", err),
+            };
+            let expected = expected.map(|(sport, date)| (sport.to_string(), date));
+            assert_eq!(result, expected, "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn parse_date_table() {
+        let today = Local::now().date_naive();
+        let cases = [
+            ("today", Some(today)),
+            ("Tomorrow", Some(today + Duration::days(1))),
+            ("2021-11-08", Some(date(2021, 11, 8))),
+            ("08.11.2021", Some(date(2021, 11, 8))),
+            ("8.11.2021", Some(date(2021, 11, 8))),
+            ("", None),
+            ("yesterday", None),
+            ("2021-02-30", None),
+            ("30.02.2021", None),
+            ("08-11-2021", None),
+            ("32.01.", None),
+            ("01.13.", None),
+            ("a.b.", None),
+            ("08", None),
+            ("30.02.", None),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(parse_date(input), expected, "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn parse_date_without_a_year_is_the_next_one() {
+        let today = Local::now().date_naive();
+        let tomorrow = today + Duration::days(1);
+        let yesterday = today - Duration::days(1);
+
+        let same_day = format!("{}.{}.", today.day(), today.month());
+        assert_eq!(parse_date(&same_day), Some(today));
+        let next = format!("{}.{}", tomorrow.day(), tomorrow.month());
+        assert_eq!(parse_date(&next), Some(tomorrow));
+        let past = format!("{:02}.{:02}.", yesterday.day(), yesterday.month());
+        let past = parse_date(&past).unwrap();
+        assert!(past > today);
+        assert_eq!(
+            (past.day(), past.month()),
+            (yesterday.day(), yesterday.month())
+        );
+    }
+
+    #[test]
+    fn leap_day_without_a_year_is_in_the_next_leap_year() {
+        assert_eq!(next_date(29, 2, date(2025, 3, 1)), Some(date(2028, 2, 29)));
+        assert_eq!(next_date(29, 2, date(2028, 2, 29)), Some(date(2028, 2, 29)));
+        assert_eq!(next_date(29, 2, date(2028, 3, 1)), Some(date(2032, 2, 29)));
+        assert_eq!(next_date(29, 2, date(2097, 1, 1)), Some(date(2104, 2, 29)));
+        assert_eq!(next_date(1, 3, date(2025, 3, 2)), Some(date(2026, 3, 1)));
+    }
+}
@@ -0,0 +1,349 @@
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::Deserialize;
+use thiserror::Error;
+use url::Url;
+
+use asvz::client::AsvzUrls;
+
+/// The config is read from here if `ASVZ_CONFIG` isn't set. The file doesn't have to exist.
+const DEFAULT_PATH: &str = "asvz-bot.toml";
+
+/// About 100 years, longer timeouts would overflow the dates they are subtracted from.
+const MAX_CREDENTIAL_TIMEOUT_DAYS: u32 = 36500;
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Unable to read the config file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid config file: {0}")]
+    Toml(#[from] toml::de::Error),
+    #[error("Invalid value in {0}: {1}")]
+    Env(&'static str, String),
+    #[error("Invalid {0}: {1}")]
+    Invalid(&'static str, String),
+}
+
+/// The configuration of the bot, see `asvz-bot.example.toml`.
+///
+/// Every value has a default and some can be overwritten with env variables,
+/// which take precedence over the file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// A json file to keep users and jobs across restarts (`ASVZ_STORAGE_PATH`).
+    pub storage_path: Option<PathBuf>,
+    /// Credentials of users who haven't used the bot for this many days are deleted
+    /// (`ASVZ_CREDENTIAL_TIMEOUT_DAYS`). Between 1 and 36500.
+    pub credential_timeout_days: u32,
+    /// Receive updates with a webhook instead of long polling.
+    pub webhook: Option<WebhookConfig>,
+    /// Serve Prometheus metrics at `/metrics` on this address (`ASVZ_METRICS_ADDRESS`).
+    pub metrics_address: Option<SocketAddr>,
+    /// How long to wait for the users to be told about a shutdown, before the bot exits anyway.
+    pub shutdown_timeout_secs: u64,
+    pub asvz: AsvzConfig,
+    pub jobs: JobConfig,
+    pub telegram: TelegramConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            storage_path: None,
+            credential_timeout_days: 30,
+            webhook: None,
+            metrics_address: None,
+            shutdown_timeout_secs: 10,
+            asvz: AsvzConfig::default(),
+            jobs: JobConfig::default(),
+            telegram: TelegramConfig::default(),
+        }
+    }
+}
+
+/// The webhook server only speaks plain http, TLS has to be terminated by a reverse proxy
+/// which forwards the path of the url.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    /// The public url Telegram sends the updates to (`ASVZ_WEBHOOK_URL`).
+    pub url: Url,
+    /// Where the webhook server listens (`ASVZ_WEBHOOK_ADDRESS`).
+    #[serde(default = "default_webhook_address")]
+    pub address: SocketAddr,
+    /// Sent by Telegram with every update, a random one is used if it isn't set
+    /// (`ASVZ_WEBHOOK_SECRET`).
+    pub secret: Option<String>,
+    /// A self-signed certificate of the proxy, which is uploaded to Telegram
+    /// (`ASVZ_WEBHOOK_CERTIFICATE`).
+    pub certificate: Option<PathBuf>,
+}
+
+impl WebhookConfig {
+    fn new(url: Url) -> Self {
+        Self {
+            url,
+            address: default_webhook_address(),
+            secret: None,
+            certificate: None,
+        }
+    }
+}
+
+fn default_webhook_address() -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], 8080))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AsvzConfig {
+    /// The ASVZ hosts (`ASVZ_WWW_URL`, `ASVZ_SCHALTER_URL` and `ASVZ_AUTH_URL`),
+    /// e.g. to use a staging instance or a local stand-in server.
+    pub www: Url,
+    pub schalter: Url,
+    pub auth: Url,
+    /// How often a request that failed with a transient error is retried.
+    pub max_retries: u32,
+}
+
+impl AsvzConfig {
+    pub fn urls(&self) -> AsvzUrls {
+        AsvzUrls {
+            www: self.www.clone(),
+            schalter: self.schalter.clone(),
+            auth: self.auth.clone(),
+        }
+    }
+}
+
+impl Default for AsvzConfig {
+    fn default() -> Self {
+        let urls = AsvzUrls::default();
+        Self {
+            www: urls.www,
+            schalter: urls.schalter,
+            auth: urls.auth,
+            max_retries: 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JobConfig {
+    /// How often a watched lesson is polled for free spots.
+    pub poll_interval_secs: u64,
+    /// How long before the enrollment opens an enrollment job logs in.
+    pub login_lead_secs: u64,
+    /// How long after the enrollment opened an enrollment job keeps trying.
+    pub enroll_window_secs: u64,
+    /// How long to wait between attempts while the enrollment opens.
+    pub enroll_attempt_gap_millis: u64,
+    /// How long to wait while the enrollment opens, after ASVZ answered with too many requests.
+    pub opening_rate_limit_delay_millis: u64,
+    /// How long to wait after ASVZ answered with too many requests once the enrollment is open.
+    pub rate_limit_delay_millis: u64,
+    /// How long to wait before trying again, if ASVZ says the enrollment is not open yet.
+    pub not_open_retry_millis: u64,
+    /// How many lessons `/search` shows at most.
+    pub max_search_results: usize,
+    /// How many lessons `/search` looks at to find lessons in the language of the user.
+    pub max_searched_lessons: usize,
+    /// How often a job that failed with an unexpected error is restarted before giving up.
+    pub max_restarts: usize,
+    /// How long to wait before the first restart, the wait doubles with every further one.
+    pub restart_delay_secs: u64,
+    /// The longest wait before a restart.
+    pub max_restart_delay_secs: u64,
+}
+
+impl JobConfig {
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_secs(self.poll_interval_secs)
+    }
+
+    pub fn login_lead(&self) -> Duration {
+        Duration::from_secs(self.login_lead_secs)
+    }
+
+    pub fn enroll_window(&self) -> Duration {
+        Duration::from_secs(self.enroll_window_secs)
+    }
+
+    pub fn enroll_attempt_gap(&self) -> Duration {
+        Duration::from_millis(self.enroll_attempt_gap_millis)
+    }
+
+    pub fn opening_rate_limit_delay(&self) -> Duration {
+        Duration::from_millis(self.opening_rate_limit_delay_millis)
+    }
+
+    pub fn rate_limit_delay(&self) -> Duration {
+        Duration::from_millis(self.rate_limit_delay_millis)
+    }
+
+    pub fn not_open_retry(&self) -> Duration {
+        Duration::from_millis(self.not_open_retry_millis)
+    }
+
+    /// How long to wait before restarting a job which was already restarted `retry_count` times.
+    pub fn restart_delay(&self, retry_count: usize) -> Duration {
+        let factor = 2u64.saturating_pow(retry_count.try_into().unwrap_or(u32::MAX));
+        let secs = self.restart_delay_secs.saturating_mul(factor);
+        Duration::from_secs(secs.min(self.max_restart_delay_secs))
+    }
+}
+
+impl Default for JobConfig {
+    fn default() -> Self {
+        Self {
+            poll_interval_secs: 10,
+            login_lead_secs: 30,
+            enroll_window_secs: 5,
+            enroll_attempt_gap_millis: 100,
+            opening_rate_limit_delay_millis: 300,
+            rate_limit_delay_millis: 500,
+            not_open_retry_millis: 1000,
+            max_search_results: 10,
+            max_searched_lessons: 50,
+            max_restarts: 5,
+            restart_delay_secs: 5,
+            max_restart_delay_secs: 600,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TelegramConfig {
+    /// Added to the wait time Telegram asks for when we send too many messages.
+    pub retry_after_margin_secs: u64,
+    /// How often a message is sent again after Telegram asked us to wait.
+    pub max_send_retries: u32,
+}
+
+impl TelegramConfig {
+    pub fn retry_after_margin(&self) -> Duration {
+        Duration::from_secs(self.retry_after_margin_secs)
+    }
+}
+
+impl Default for TelegramConfig {
+    fn default() -> Self {
+        Self {
+            retry_after_margin_secs: 5,
+            max_send_retries: 3,
+        }
+    }
+}
+
+impl Config {
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_timeout_secs)
+    }
+
+    pub fn credential_timeout(&self) -> chrono::Duration {
+        chrono::Duration::days(self.credential_timeout_days.into())
+    }
+
+    /// Reads the file at `ASVZ_CONFIG` or `asvz-bot.toml` and applies the env variables.
+    pub fn load() -> Result<Self, ConfigError> {
+        let mut config = match std::env::var_os("ASVZ_CONFIG") {
+            Some(path) => Self::from_file(Path::new(&path))?,
+            None if Path::new(DEFAULT_PATH).exists() => Self::from_file(Path::new(DEFAULT_PATH))?,
+            None => Self::default(),
+        };
+        config.apply_env()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let content = std::fs::read_to_string(path)?;
+        Ok(toml::from_str(&content)?)
+    }
+
+    fn apply_env(&mut self) -> Result<(), ConfigError> {
+        if let Some(path) = env("ASVZ_STORAGE_PATH")? {
+            self.storage_path = Some(path);
+        }
+        if let Some(days) = env("ASVZ_CREDENTIAL_TIMEOUT_DAYS")? {
+            self.credential_timeout_days = days;
+        }
+        if let Some(url) = env("ASVZ_WEBHOOK_URL")? {
+            match &mut self.webhook {
+                Some(webhook) => webhook.url = url,
+                None => self.webhook = Some(WebhookConfig::new(url)),
+            }
+        }
+        if let Some(webhook) = &mut self.webhook {
+            if let Some(address) = env("ASVZ_WEBHOOK_ADDRESS")? {
+                webhook.address = address;
+            }
+            if let Some(secret) = env("ASVZ_WEBHOOK_SECRET")? {
+                webhook.secret = Some(secret);
+            }
+            if let Some(certificate) = env("ASVZ_WEBHOOK_CERTIFICATE")? {
+                webhook.certificate = Some(certificate);
+            }
+        }
+        if let Some(address) = env("ASVZ_METRICS_ADDRESS")? {
+            self.metrics_address = Some(address);
+        }
+        if let Some(url) = env("ASVZ_WWW_URL")? {
+            self.asvz.www = url;
+        }
+        if let Some(url) = env("ASVZ_SCHALTER_URL")? {
+            self.asvz.schalter = url;
+        }
+        if let Some(url) = env("ASVZ_AUTH_URL")? {
+            self.asvz.auth = url;
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if !(1..=MAX_CREDENTIAL_TIMEOUT_DAYS).contains(&self.credential_timeout_days) {
+            return Err(ConfigError::Invalid(
+                "credential_timeout_days",
+                format!(
+                    "{} is not between 1 and {}",
+                    self.credential_timeout_days, MAX_CREDENTIAL_TIMEOUT_DAYS
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn env<T: FromStr>(key: &'static str) -> Result<Option<T>, ConfigError> {
+    match std::env::var(key) {
+        Ok(value) => T::from_str(&value)
+            .map(Some)
+            .map_err(|_| ConfigError::Env(key, value)),
+        Err(_) => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn credential_timeout_has_to_be_in_range() {
+        let config = |days| Config {
+            credential_timeout_days: days,
+            ..Config::default()
+        };
+
+        assert!(config(1).validate().is_ok());
+        assert!(config(MAX_CREDENTIAL_TIMEOUT_DAYS).validate().is_ok());
+        assert!(config(0).validate().is_err());
+        assert!(config(MAX_CREDENTIAL_TIMEOUT_DAYS + 1).validate().is_err());
+        assert!(toml::from_str::<Config>("credential_timeout_days = -1").is_err());
+    }
+}
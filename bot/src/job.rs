@@ -1,25 +1,53 @@
+use std::fmt;
+use std::fmt::Formatter;
 use std::future::Future;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::task::Context;
+use std::time::Duration;
 
+use chrono::NaiveDate;
 use futures::FutureExt;
+use serde::{Deserialize, Serialize};
+use teloxide::types::InlineKeyboardMarkup;
 use teloxide::{prelude::*, RequestError};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::watch;
 use tokio::task::{JoinError, JoinHandle};
 
 use asvz::lesson::LessonID;
 
-use crate::cmd::{Password, Username};
 use crate::job_err::JobError;
 use crate::job_fns;
 use crate::job_update_cx::JobUpdateCx;
-use crate::user::{BotCtx, UserId};
+use crate::metrics::ActiveJobGuard;
+use crate::state::StateEvent;
+use crate::user::{BotCtx, LoginCredentials, UserId};
+
+/// Identifies a job of a user. Ids are only unique per user and never reused.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct JobId(pub u32);
+
+impl fmt::Display for JobId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
 
 #[derive(Debug)]
 pub struct Job {
+    pub id: Option<JobId>,
     pub kind: JobKind,
     pub user_id: UserId,
+    pub bot: BotCtx,
     pub handle: JoinHandle<Result<(), JobError>>,
+    /// How often the job was restarted after an error.
+    pub retry_count: usize,
+    /// The lesson the job is currently at, weekly jobs move on every week.
+    lesson: Option<watch::Receiver<LessonID>>,
+    aborted: AtomicBool,
 }
 
 impl Job {
@@ -29,6 +57,29 @@ impl Job {
     pub fn builder(kind: JobKind, user_id: UserId, bot: BotCtx) -> JobBuilder {
         JobBuilder::new(kind, user_id, bot)
     }
+
+    /// Aborts the job. It stays in the job list until its handle has finished,
+    /// so aborted jobs have to be skipped with [`Job::is_active`].
+    pub fn abort(&self) {
+        self.aborted.store(true, Ordering::Relaxed);
+        self.handle.abort();
+    }
+
+    pub fn is_active(&self) -> bool {
+        !self.aborted.load(Ordering::Relaxed)
+    }
+
+    /// The kind with the lesson the job is currently at, to resume it from there.
+    pub fn current_kind(&self) -> JobKind {
+        current_kind(&self.kind, &self.lesson)
+    }
+}
+
+pub(crate) fn current_kind(kind: &JobKind, lesson: &Option<watch::Receiver<LessonID>>) -> JobKind {
+    match lesson {
+        Some(lesson) => kind.clone().with_lesson_id(lesson.borrow().clone()),
+        None => kind.clone(),
+    }
 }
 
 impl Future for Job {
@@ -40,62 +91,85 @@ impl Future for Job {
 }
 
 pub struct JobBuilder {
+    id: Option<JobId>,
     kind: JobKind,
     retry_count: usize,
     user_id: UserId,
     bot: BotCtx,
     pre_msg: Option<String>,
+    delay: Option<Duration>,
 }
 
 impl JobBuilder {
     pub fn new(kind: JobKind, user_id: UserId, bot: BotCtx) -> Self {
         Self {
+            id: None,
             kind,
             user_id,
             bot,
             retry_count: 0,
             pre_msg: None,
+            delay: None,
         }
     }
 
+    pub fn id(mut self, id: JobId) -> Self {
+        self.id = Some(id);
+        self
+    }
+
     pub fn pre_msg(mut self, msg: impl Into<String>) -> Self {
         self.pre_msg = Some(msg.into());
         self
     }
 
+    /// Waits before the job starts. The job is listed and can be canceled while it waits.
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.delay = Some(delay);
+        self
+    }
+
     pub fn retry_count(mut self, retry_count: usize) -> Self {
         self.retry_count = retry_count;
         self
     }
 
     pub fn build(self) -> Job {
-        let fut = self.kind.clone().to_fut(self.bot.clone());
-        let handle = if let Some(pre_msg) = self.pre_msg {
-            let bot_clone = self.bot.clone();
-            let fut = async move {
-                job_fns::msg_user(&bot_clone, pre_msg).await?;
-                fut.await
-            };
-            tokio::spawn(job_fns::utils::attach_ctx(
-                fut,
-                self.user_id,
-                self.kind.clone(),
-                self.bot,
-                self.retry_count,
-            ))
-        } else {
-            tokio::spawn(job_fns::utils::attach_ctx(
-                fut,
-                self.user_id,
-                self.kind.clone(),
-                self.bot,
-                self.retry_count,
-            ))
+        let progress = self.kind.lesson_id().map(|id| watch::channel(id.clone()).0);
+        let lesson = progress.as_ref().map(watch::Sender::subscribe);
+        let fut = self.kind.clone().to_fut(self.bot.clone(), progress);
+        let guard = ActiveJobGuard::new(self.kind.name());
+        let bot = self.bot.clone();
+        let delay = self.delay;
+        let pre_msg = self.pre_msg;
+        let fut = async move {
+            let _guard = guard;
+            if let Some(delay) = delay {
+                tokio::time::sleep(delay).await;
+            }
+            if let Some(pre_msg) = pre_msg {
+                job_fns::msg_user(&bot, pre_msg).await?;
+            }
+            fut.await
         };
+        let handle = tokio::spawn(job_fns::utils::attach_ctx(
+            fut,
+            self.id,
+            self.user_id,
+            self.kind.clone(),
+            lesson.clone(),
+            self.bot.clone(),
+            self.retry_count,
+        ));
         Job {
+            id: self.id,
             kind: self.kind,
             user_id: self.user_id,
+            bot: self.bot,
             handle,
+            retry_count: self.retry_count,
+            lesson,
+            aborted: AtomicBool::new(false),
         }
     }
 }
@@ -104,8 +178,8 @@ impl JobBuilder {
 pub enum JobKind {
     Notify(LessonID),
     NotifyWeekly(LessonID),
-    Enroll(LessonID, Username, Password),
-    EnrollWeekly(LessonID, Username, Password),
+    Enroll(LessonID, LoginCredentials),
+    EnrollWeekly(LessonID, LoginCredentials),
     Internal(InternalJob),
 }
 
@@ -114,40 +188,88 @@ impl JobKind {
         matches!(self, Self::Internal(_))
     }
 
-    pub fn to_fut(self, bot: BotCtx) -> impl Future<Output = Result<(), RequestError>> {
+    pub fn lesson_id(&self) -> Option<&LessonID> {
+        match self {
+            Self::Notify(id)
+            | Self::NotifyWeekly(id)
+            | Self::Enroll(id, _)
+            | Self::EnrollWeekly(id, _) => Some(id),
+            Self::Internal(_) => None,
+        }
+    }
+
+    /// Replaces the lesson of the job, internal jobs stay the same.
+    pub fn with_lesson_id(self, id: LessonID) -> Self {
+        match self {
+            Self::Notify(_) => Self::Notify(id),
+            Self::NotifyWeekly(_) => Self::NotifyWeekly(id),
+            Self::Enroll(_, credentials) => Self::Enroll(id, credentials),
+            Self::EnrollWeekly(_, credentials) => Self::EnrollWeekly(id, credentials),
+            Self::Internal(internal) => Self::Internal(internal),
+        }
+    }
+
+    /// The credentials the job holds a copy of.
+    pub fn credentials(&self) -> Option<&LoginCredentials> {
+        match self {
+            Self::Enroll(_, credentials)
+            | Self::EnrollWeekly(_, credentials)
+            | Self::Internal(InternalJob::Unenroll(_, credentials))
+            | Self::Internal(InternalJob::MyLessons(credentials))
+            | Self::Internal(InternalJob::Login { credentials, .. }) => Some(credentials),
+            Self::Notify(_) | Self::NotifyWeekly(_) | Self::Internal(_) => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Notify(_) => "Notify",
+            Self::NotifyWeekly(_) => "NotifyWeekly",
+            Self::Enroll(_, _) => "Enroll",
+            Self::EnrollWeekly(_, _) => "EnrollWeekly",
+            Self::Internal(_) => "Internal",
+        }
+    }
+
+    /// `progress` has to be given for jobs with a lesson, they report their current lesson to it.
+    pub fn to_fut(
+        self,
+        bot: BotCtx,
+        progress: Option<watch::Sender<LessonID>>,
+    ) -> impl Future<Output = Result<(), RequestError>> {
         match self {
             Self::Notify(id) => {
-                let job_cx = JobUpdateCx::new(bot, id.clone());
+                let job_cx = update_cx(bot, progress);
                 async move {
                     job_fns::utils::wrap_exit_status(&job_cx, job_fns::notify(&job_cx, id)).await
                 }
                 .boxed()
             }
             Self::NotifyWeekly(id) => {
-                let job_cx = JobUpdateCx::new(bot, id.clone());
+                let job_cx = update_cx(bot, progress);
                 async move {
                     job_fns::utils::wrap_exit_status(&job_cx, job_fns::notify_weekly(&job_cx, id))
                         .await
                 }
                 .boxed()
             }
-            Self::Enroll(id, username, password) => {
-                let job_cx = JobUpdateCx::new(bot, id.clone());
+            Self::Enroll(id, credentials) => {
+                let job_cx = update_cx(bot, progress);
                 async move {
                     job_fns::utils::wrap_exit_status(
                         &job_cx,
-                        job_fns::enroll(&job_cx, id.clone(), username, password),
+                        job_fns::enroll(&job_cx, id.clone(), credentials),
                     )
                     .await
                 }
                 .boxed()
             }
-            Self::EnrollWeekly(id, username, password) => {
-                let job_cx = JobUpdateCx::new(bot, id.clone());
+            Self::EnrollWeekly(id, credentials) => {
+                let job_cx = update_cx(bot, progress);
                 async move {
                     job_fns::utils::wrap_exit_status(
                         &job_cx,
-                        job_fns::enroll_weekly(&job_cx, id.clone(), username, password),
+                        job_fns::enroll_weekly(&job_cx, id.clone(), credentials),
                     )
                     .await
                 }
@@ -160,11 +282,44 @@ impl JobKind {
                 InternalJob::DeleteMsgUser(msg) => {
                     async move { job_fns::reply_and_del(&bot, msg.clone()).await }.boxed()
                 }
+                InternalJob::MsgUserKeyboard(msg, keyboard) => async move {
+                    job_fns::msg_user_keyboard(&bot, msg.clone(), keyboard.clone()).await
+                }
+                .boxed(),
+                InternalJob::Search(sport, date) => {
+                    async move { job_fns::search(&bot, sport, date).await }.boxed()
+                }
+                InternalJob::MyLessons(credentials) => {
+                    async move { job_fns::my_lessons(&bot, credentials).await }.boxed()
+                }
+                InternalJob::Login {
+                    user_id,
+                    credentials,
+                    events,
+                } => {
+                    async move { job_fns::login(&bot, user_id, credentials, events).await }.boxed()
+                }
+                InternalJob::Unenroll(id, credentials) => {
+                    async move { job_fns::unenroll(&bot, id, credentials).await }.boxed()
+                }
+                InternalJob::AnswerCallback {
+                    query_id,
+                    text,
+                    show_alert,
+                    edit,
+                } => async move {
+                    job_fns::answer_callback(&bot, query_id, text, show_alert, edit).await
+                }
+                .boxed(),
             },
         }
     }
 }
 
+fn update_cx(bot: BotCtx, progress: Option<watch::Sender<LessonID>>) -> JobUpdateCx {
+    JobUpdateCx::new(bot, progress.expect("Jobs with a lesson have a progress"))
+}
+
 impl From<InternalJob> for JobKind {
     fn from(internal_job: InternalJob) -> Self {
         Self::Internal(internal_job)
@@ -175,4 +330,38 @@ impl From<InternalJob> for JobKind {
 pub enum InternalJob {
     MsgUser(String),
     DeleteMsgUser(String),
+    MsgUserKeyboard(String, InlineKeyboardMarkup),
+    Search(String, Option<NaiveDate>),
+    Unenroll(LessonID, LoginCredentials),
+    MyLessons(LoginCredentials),
+    /// Checks the credentials of a `/login` and reports them back if they work.
+    Login {
+        user_id: UserId,
+        credentials: LoginCredentials,
+        events: UnboundedSender<StateEvent>,
+    },
+    /// Answers a callback query and optionally replaces the message the button belonged to.
+    AnswerCallback {
+        query_id: String,
+        text: Option<String>,
+        show_alert: bool,
+        edit: Option<(String, InlineKeyboardMarkup)>,
+    },
+}
+
+impl InternalJob {
+    /// The command of a request that is lost if the job is aborted, e.g. on a shutdown.
+    /// Plain messages and callback answers aren't requests of the user.
+    pub fn command(&self) -> Option<&'static str> {
+        match self {
+            Self::Search(_, _) => Some("/search"),
+            Self::Unenroll(_, _) => Some("/unenroll"),
+            Self::MyLessons(_) => Some("/mylessons"),
+            Self::Login { .. } => Some("/login"),
+            Self::MsgUser(_)
+            | Self::DeleteMsgUser(_)
+            | Self::MsgUserKeyboard(_, _)
+            | Self::AnswerCallback { .. } => None,
+        }
+    }
 }
@@ -6,11 +6,12 @@ use serde::Serializer;
 use teloxide::prelude::*;
 use teloxide::{Bot, RequestError};
 
-use crate::job::JobKind;
+use crate::job::{JobId, JobKind};
 use crate::user::{BotCtx, UserId};
 
 pub struct JobError {
     pub source: RequestError,
+    pub job_id: Option<JobId>,
     pub user_id: UserId,
     pub job_kind: JobKind,
     pub bot: BotCtx,
@@ -21,6 +22,7 @@ impl Debug for JobError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("JobError")
             .field("source", &self.source)
+            .field("job_id", &self.job_id)
             .field("user_id", &self.user_id)
             .field("job_kind", &self.job_kind)
             .finish()
@@ -30,6 +32,7 @@ impl Debug for JobError {
 impl JobError {
     pub fn new(
         source: RequestError,
+        job_id: Option<JobId>,
         user_id: UserId,
         job_kind: JobKind,
         bot: BotCtx,
@@ -37,6 +40,7 @@ impl JobError {
     ) -> Self {
         Self {
             source,
+            job_id,
             user_id,
             job_kind,
             bot,
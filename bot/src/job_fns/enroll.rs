@@ -1,60 +1,71 @@
 use std::cmp::max;
 use std::time::Duration;
 
-use asvz::api::enrollment::EnrollmentData;
-use reqwest::{Client, StatusCode};
-use reqwest_middleware::{ClientBuilder, ClientWithMiddleware, Error};
+use chrono::Utc;
+use reqwest::StatusCode;
+use reqwest_middleware::Error;
 use reqwest_retry::{
-    default_on_request_failure, default_on_request_success, policies::ExponentialBackoff,
-    RetryTransientMiddleware, Retryable, RetryableStrategy,
+    default_on_request_failure, default_on_request_success, Retryable, RetryableStrategy,
 };
-use reqwest_tracing::{DefaultSpanBackend, TracingMiddleware};
 use teloxide::{prelude::*, RequestError};
-use tracing::{instrument, trace};
+use tokio::time::Instant;
+use tracing::{debug, instrument, trace, warn};
 
+use asvz::client::AsvzClient;
+use asvz::clock::ClockOffset;
+use asvz::enrollment::{EnrollmentRejection, EnrollmentResponse};
 use asvz::error::AsvzError;
 use asvz::lesson::LessonID;
-use asvz::lesson::{lesson_data, search_data};
-use asvz::login::asvz_login;
 
-use crate::cmd::{Password, Username};
+use crate::config::Config;
+use crate::job_fns::utils::{build_client_with, check_lesson};
 use crate::job_fns::ExistStatus;
 use crate::job_update_cx::JobUpdateCx;
+use crate::metrics;
+use crate::session::{Session, SESSIONS};
+use crate::user::LoginCredentials;
 use crate::utils::ret_on_err;
 use crate::utils::{current_timestamp, reply};
+use crate::watcher::LESSON_WATCHER;
 
-#[instrument(skip(cx, password))]
+/// Requests to estimate the server clock offset with, each one takes up to a second.
+const CLOCK_SAMPLES: usize = 5;
+const TOKEN_REJECTED_MSG: &str =
+    "ASVZ rejected the token of a fresh login, please try to log in again";
+
+#[instrument(skip(cx, credentials))]
 pub async fn enroll(
     cx: &JobUpdateCx,
     id: LessonID,
-    username: Username,
-    password: Password,
+    credentials: LoginCredentials,
 ) -> Result<ExistStatus, RequestError> {
     trace!("new enroll job");
-    let client = build_client();
-    enroll_once(&client, cx, &id, &username, &password).await
+    let client = build_client_with(cx.config(), EnrollRetryableStrategy);
+    let session = SESSIONS.session(&credentials, cx.config());
+    enroll_once(&client, cx, &id, &session).await
 }
 
-#[instrument(skip(cx))]
+#[instrument(skip(cx, credentials))]
 pub async fn enroll_weekly(
     cx: &JobUpdateCx,
     start_id: LessonID,
-    username: Username,
-    password: Password,
+    credentials: LoginCredentials,
 ) -> Result<ExistStatus, RequestError> {
     trace!("new enroll_weekly job");
-    let client = build_client();
+    let client = build_client_with(cx.config(), EnrollRetryableStrategy);
+    let session = SESSIONS.session(&credentials, cx.config());
     let mut current_id = start_id;
     loop {
-        match enroll_once(&client, cx, &current_id, &username, &password).await? {
+        match enroll_once(&client, cx, &current_id, &session).await? {
             ExistStatus::Success(msg) | ExistStatus::Failure(msg) => {
                 cx.answer(msg).await?;
             }
             ExistStatus::Error(msg) => return Ok(ExistStatus::Error(msg)),
         }
-        let event_list = ret_on_err!(search_data(&client, &current_id, 1).await);
+        let event_list = ret_on_err!(client.search_data(&current_id, 1).await);
         if let Some(id) = event_list.lesson_id() {
             current_id = id;
+            cx.advance(current_id.clone());
             reply!(cx, "Found next week's lesson: {}", current_id.as_str()).await?;
         } else {
             return Ok(ExistStatus::failure("Unable to find next lesson"));
@@ -63,113 +74,142 @@ pub async fn enroll_weekly(
 }
 
 async fn enroll_once(
-    client: &ClientWithMiddleware,
+    client: &AsvzClient,
     cx: &JobUpdateCx,
     id: &LessonID,
-    username: &Username,
-    password: &Password,
+    session: &Session,
 ) -> Result<ExistStatus, RequestError> {
     trace!("enroll once");
-    let enroll_url = format!(
-        "https://schalter.asvz.ch/tn-api/api/Lessons/{}/Enrollment",
-        id.as_str()
-    );
-    let mut token = ret_on_err!(
-        asvz_login(client, username.as_str(), password.as_str_dangerous()).await,
-        "Unable to log in"
-    );
-
-    let data = ret_on_err!(lesson_data(client, id).await);
-    let until_ts = ret_on_err!(data.enroll_until_timestamp());
-    let from_ts = ret_on_err!(data.enroll_from_timestamp());
+    let mut token = ret_on_err!(session.token().await, "Unable to log in");
+    // Whether the token was just renewed, so a rejection isn't an expired token
+    let mut renewed = false;
+
+    let data = ret_on_err!(client.lesson_data(id).await);
+    if let Some(status) = check_lesson(&data.data) {
+        return Ok(status);
+    }
+    if data.data.is_lottery() {
+        reply!(
+            cx,
+            "This is a lottery lesson, I will enter you into the draw once the enrollment opens."
+        )
+        .await?;
+    }
+    let until_ts = data.data.enrollment_until.timestamp();
+    let from_ts = data.data.enrollment_from.timestamp();
 
     let current_ts = current_timestamp();
     if from_ts > current_ts {
         // We still need to wait to enroll
-        let wait_time = max(from_ts - current_ts - 30, 0) as u64;
+        let login_lead = cx.config().jobs.login_lead().as_secs() as i64;
+        let wait_time = max(from_ts - current_ts - login_lead, 0) as u64;
         reply!(cx, "I will enroll you in {} seconds", from_ts - current_ts).await?;
         trace!("waiting for {} seconds before we can enroll", wait_time);
         tokio::time::sleep(Duration::from_secs(wait_time)).await;
 
-        token = ret_on_err!(
-            asvz_login(client, username.as_str(), password.as_str_dangerous()).await,
-            "Unable to log in"
-        );
-        trace!("refreshed token");
+        // The token has to last until the enrollment opened
+        token = ret_on_err!(session.token().await, "Unable to log in");
 
-        let current_ts = current_timestamp();
-        let wait_time = max(from_ts - current_ts, 0) as u64;
-        trace!("waiting again for {} seconds", wait_time);
-        tokio::time::sleep(Duration::from_secs(wait_time)).await;
+        // The enrollment opens by the server clock, which can be off from ours
+        let offset = match client.clock_offset(CLOCK_SAMPLES).await {
+            Ok(offset) => offset,
+            Err(err) => {
+                warn!("Unable to estimate the server clock offset: {}", err);
+                ClockOffset::default()
+            }
+        };
+        // Rather be a bit early, the server rejects attempts before the opening anyway
+        let opens_at = offset.to_instant(data.data.enrollment_from - offset.uncertainty());
+        let give_up_at = opens_at + cx.config().jobs.enroll_window();
+        trace!(
+            "waiting for {:?}, the server clock is {}ms ahead",
+            opens_at - Instant::now(),
+            offset.estimate().num_milliseconds()
+        );
+        tokio::time::sleep_until(opens_at).await;
 
-        while current_timestamp() < from_ts + 5 {
+        while Instant::now() < give_up_at {
             trace!("starting to enroll");
-            let enroll_response = ret_on_err!(
-                client
-                    .post(enroll_url.clone())
-                    .bearer_auth(&token)
-                    .json(&())
-                    .send()
-                    .await
-            );
-            trace!(
-                "enroll response with status code {}",
-                enroll_response.status()
-            );
-
-            match enroll_response.status() {
-                StatusCode::CREATED => {
-                    if let Ok(enrollment_data) = enroll_response.json::<EnrollmentData>().await {
-                        trace!("enrollment_data: {:?}", enrollment_data);
-                    }
+            match ret_on_err!(try_enroll(client, &token, id).await) {
+                EnrollmentResponse::Enrolled(_) => {
+                    // By the server clock, as that's when the enrollment opened
+                    let server_now = Utc::now() + offset.estimate();
+                    metrics::record_enrollment_delay(
+                        server_now.signed_duration_since(data.data.enrollment_from),
+                    );
                     return Ok(ExistStatus::success("I successfully enrolled you"));
                 }
-                StatusCode::UNPROCESSABLE_ENTITY => (),
-                StatusCode::TOO_MANY_REQUESTS => {
-                    tokio::time::sleep(Duration::from_millis(300)).await;
+                EnrollmentResponse::Rejected(rejection) if rejection.is_retryable() => {
+                    renewed = false
+                }
+                EnrollmentResponse::Rejected(rejection) => return Ok(rejected(rejection)),
+                EnrollmentResponse::TooManyRequests => {
+                    tokio::time::sleep(cx.config().jobs.opening_rate_limit_delay()).await;
+                }
+                EnrollmentResponse::Unexpected(StatusCode::UNAUTHORIZED) if !renewed => {
+                    trace!("token rejected, logging in again");
+                    token = ret_on_err!(session.renew(&token).await, "Unable to log in");
+                    renewed = true;
+                    continue;
+                }
+                EnrollmentResponse::Unexpected(StatusCode::UNAUTHORIZED) => {
+                    return Ok(ExistStatus::error(TOKEN_REJECTED_MSG));
                 }
-                code => {
+                EnrollmentResponse::Unexpected(code) => {
                     let msg = format!("Got unexpected status code: {}", code);
                     return Ok(ExistStatus::error(msg));
                 }
             }
-            tokio::time::sleep(Duration::from_millis(100)).await;
+            tokio::time::sleep(cx.config().jobs.enroll_attempt_gap()).await;
         }
     }
 
     trace!("trying normal enrollment");
+    let mut subscription = LESSON_WATCHER.subscribe(id, cx.config());
     for count in 0.. {
         let current_ts = current_timestamp();
+        // Set when ASVZ rejected the attempt for a reason we don't know
+        let mut retry_after = None;
 
         if current_ts > until_ts {
             return Ok(ExistStatus::failure("You can no longer enroll"));
         }
-        let enroll_response = ret_on_err!(
-            client
-                .post(enroll_url.clone())
-                .bearer_auth(&token)
-                .json(&())
-                .send()
-                .await
-        );
-
-        trace!(
-            "Tried to enroll with status code: {}",
-            enroll_response.status()
-        );
-
-        match enroll_response.status() {
-            StatusCode::CREATED => {
-                if let Ok(enrollment_data) = enroll_response.json::<EnrollmentData>().await {
-                    trace!("enrollment_data: {:?}", enrollment_data);
-                }
+        match ret_on_err!(try_enroll(client, &token, id).await) {
+            EnrollmentResponse::Enrolled(_) => {
+                // By our clock, the server clock is only estimated before the opening
+                metrics::record_enrollment_delay(
+                    Utc::now().signed_duration_since(data.data.enrollment_from),
+                );
                 return Ok(ExistStatus::success("I successfully enrolled you"));
             }
-            StatusCode::UNPROCESSABLE_ENTITY => (),
-            StatusCode::TOO_MANY_REQUESTS => {
-                tokio::time::sleep(Duration::from_millis(500)).await;
+            EnrollmentResponse::Rejected(EnrollmentRejection::NotOpenYet) => {
+                // Our clock is ahead of the server's, there won't be a poll showing the change
+                renewed = false;
+                tokio::time::sleep(cx.config().jobs.not_open_retry()).await;
+                continue;
+            }
+            EnrollmentResponse::Rejected(EnrollmentRejection::Unknown(msg)) => {
+                debug!("enrollment rejected: {}", msg);
+                renewed = false;
+                retry_after = Some(cx.config().jobs.poll_interval());
+            }
+            EnrollmentResponse::Rejected(rejection) if rejection.is_retryable() => renewed = false,
+            EnrollmentResponse::Rejected(rejection) => return Ok(rejected(rejection)),
+            EnrollmentResponse::TooManyRequests => {
+                tokio::time::sleep(cx.config().jobs.rate_limit_delay()).await;
+                continue;
+            }
+            EnrollmentResponse::Unexpected(StatusCode::UNAUTHORIZED) if !renewed => {
+                // The token expired while we were waiting for a free place
+                trace!("token rejected, logging in again");
+                token = ret_on_err!(session.renew(&token).await, "Unable to log in");
+                renewed = true;
+                continue;
             }
-            code => {
+            EnrollmentResponse::Unexpected(StatusCode::UNAUTHORIZED) => {
+                return Ok(ExistStatus::error(TOKEN_REJECTED_MSG));
+            }
+            EnrollmentResponse::Unexpected(code) => {
                 let msg = format!("Got unexpected status code: {}", code);
                 return Ok(ExistStatus::error(msg));
             }
@@ -183,11 +223,54 @@ async fn enroll_once(
             .await?;
         }
 
-        tokio::time::sleep(Duration::from_secs(10)).await;
+        // Only try again once the watcher sees a free spot, or after a poll interval if the
+        // reason is unknown, as nothing the watcher sees might change then
+        loop {
+            let next = subscription.next_until(data.data.enrollment_until);
+            let poll = match retry_after {
+                Some(retry_after) => match tokio::time::timeout(retry_after, next).await {
+                    Ok(poll) => poll,
+                    Err(_) => break,
+                },
+                None => next.await,
+            };
+            let fresh_data = match poll {
+                Some(data) => data,
+                None => return Ok(ExistStatus::failure("You can no longer enroll")),
+            };
+            if fresh_data.data.participants_max > fresh_data.data.participant_count {
+                break;
+            }
+        }
     }
     unreachable!()
 }
 
+/// Tells the user why the enrollment is not possible.
+fn rejected(rejection: EnrollmentRejection) -> ExistStatus {
+    match rejection {
+        EnrollmentRejection::AlreadyEnrolled => {
+            ExistStatus::success("You are already enrolled in this lesson")
+        }
+        EnrollmentRejection::MembershipExpired => {
+            ExistStatus::failure("Your ASVZ membership expired, please renew it to enroll")
+        }
+        EnrollmentRejection::OverlappingLesson => {
+            ExistStatus::failure("You are already enrolled in a lesson at the same time")
+        }
+        EnrollmentRejection::SkillsMissing => {
+            ExistStatus::failure("You are missing a skill that this lesson requires")
+        }
+        EnrollmentRejection::Cancelled => ExistStatus::failure("The lesson has been cancelled"),
+        EnrollmentRejection::Closed => ExistStatus::failure("You can no longer enroll"),
+        EnrollmentRejection::Full => ExistStatus::failure("The lesson is full"),
+        EnrollmentRejection::NotOpenYet => ExistStatus::failure("The enrollment is not open yet"),
+        EnrollmentRejection::Unknown(msg) => {
+            ExistStatus::failure(format!("ASVZ rejected the enrollment: {}", msg))
+        }
+    }
+}
+
 pub struct EnrollRetryableStrategy;
 
 impl RetryableStrategy for EnrollRetryableStrategy {
@@ -214,13 +297,12 @@ pub fn enroll_on_request_success(success: &reqwest::Response) -> Option<Retryabl
     }
 }
 
-fn build_client() -> ClientWithMiddleware {
-    let retry_policy = ExponentialBackoff::builder().build_with_max_retries(3);
-    ClientBuilder::new(Client::builder().cookie_store(true).build().unwrap())
-        .with(TracingMiddleware::<DefaultSpanBackend>::new())
-        .with(RetryTransientMiddleware::new_with_policy_and_strategy(
-            retry_policy,
-            EnrollRetryableStrategy,
-        ))
-        .build()
+async fn try_enroll(
+    client: &AsvzClient,
+    token: &str,
+    id: &LessonID,
+) -> Result<EnrollmentResponse, AsvzError> {
+    let response = client.enroll(token, id).await;
+    metrics::record_enrollment_attempt(matches!(response, Ok(EnrollmentResponse::Enrolled(_))));
+    response
 }
@@ -1,6 +1,7 @@
 use std::time::Duration;
 
 use crate::user::BotCtx;
+use teloxide::types::InlineKeyboardMarkup;
 use teloxide::{prelude::*, RequestError};
 use tracing::{instrument, trace};
 
@@ -19,3 +20,31 @@ pub async fn reply_and_del(bot: &BotCtx, text: String) -> Result<(), RequestErro
     bot.delete_message().await?;
     Ok(())
 }
+
+#[instrument(skip(bot, text, keyboard))]
+pub async fn msg_user_keyboard(
+    bot: &BotCtx,
+    text: String,
+    keyboard: InlineKeyboardMarkup,
+) -> Result<(), RequestError> {
+    trace!("new msg keyboard job");
+    bot.answer_with_keyboard(text, keyboard).await?;
+    Ok(())
+}
+
+#[instrument(skip(bot, text, edit))]
+pub async fn answer_callback(
+    bot: &BotCtx,
+    query_id: String,
+    text: Option<String>,
+    show_alert: bool,
+    edit: Option<(String, InlineKeyboardMarkup)>,
+) -> Result<(), RequestError> {
+    trace!("answer_callback");
+    bot.answer_callback_query(query_id, text, show_alert)
+        .await?;
+    if let Some((text, keyboard)) = edit {
+        bot.edit_message(text, keyboard).await?;
+    }
+    Ok(())
+}
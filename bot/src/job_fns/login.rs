@@ -0,0 +1,61 @@
+use teloxide::RequestError;
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::{instrument, trace, warn};
+
+use asvz::error::AsvzError;
+
+use crate::job_fns::utils::build_client;
+use crate::metrics;
+use crate::state::StateEvent;
+use crate::user::{BotCtx, LoginCredentials, UserId};
+
+/// Tries to log in with the credentials and only hands them to the state if that worked.
+#[instrument(skip(bot, credentials, events))]
+pub async fn login(
+    bot: &BotCtx,
+    user_id: UserId,
+    credentials: LoginCredentials,
+    events: UnboundedSender<StateEvent>,
+) -> Result<(), RequestError> {
+    trace!("new login job");
+    // Don't leave the password in the chat while we check it
+    bot.delete_message().await?;
+
+    let result = build_client(bot.config())
+        .login(
+            credentials.method,
+            credentials.username.as_str(),
+            credentials.password.as_str_dangerous(),
+        )
+        .await;
+    metrics::record_login(credentials.method, &result);
+    let msg = match result {
+        Ok(_) => {
+            // The receiver only closes when the bot shuts down
+            let msg = format!(
+                "Logged in successfully with your {} account, I stored your credentials.",
+                credentials.method
+            );
+            let _ = events.send(StateEvent::LoginVerified(user_id, credentials));
+            msg
+        }
+        Err(AsvzError::WrongPassword) => {
+            "Your password is wrong, I didn't store your credentials.".to_string()
+        }
+        Err(AsvzError::UnknownUser) => {
+            "Your username is unknown, I didn't store your credentials.".to_string()
+        }
+        Err(AsvzError::WrongCredentials) => {
+            "Your username or password is wrong, I didn't store your credentials.".to_string()
+        }
+        Err(err) => {
+            warn!("Login check failed: {}", &err);
+            format!(
+                "I was unable to check your credentials ({}), so I didn't store them. \
+                Please try again later.",
+                err
+            )
+        }
+    };
+    bot.answer(msg).await
+}
@@ -1,15 +1,26 @@
 pub use crate::job_fns::enroll::enroll;
 pub use crate::job_fns::enroll::enroll_weekly;
+pub use crate::job_fns::internals::answer_callback;
 pub use crate::job_fns::internals::msg_user;
+pub use crate::job_fns::internals::msg_user_keyboard;
 pub use crate::job_fns::internals::reply_and_del;
+pub use crate::job_fns::login::login;
+pub use crate::job_fns::my_lessons::my_lessons;
 pub use crate::job_fns::notify::notify;
 pub use crate::job_fns::notify::notify_weekly;
+pub use crate::job_fns::search::search;
+pub use crate::job_fns::unenroll::unenroll;
 
 mod enroll;
 mod internals;
+mod login;
+mod my_lessons;
 mod notify;
+mod search;
+mod unenroll;
 pub mod utils;
 
+#[derive(Debug)]
 pub enum ExistStatus {
     Success(String),
     Failure(String),
@@ -0,0 +1,69 @@
+use teloxide::RequestError;
+use tracing::{instrument, trace, warn};
+
+use asvz::api::enrollment::{Enrollment, EnrollmentStatus};
+
+use crate::job_fns::utils::build_client;
+use crate::session::SESSIONS;
+use crate::user::{BotCtx, LoginCredentials};
+
+#[instrument(skip(bot, credentials))]
+pub async fn my_lessons(bot: &BotCtx, credentials: LoginCredentials) -> Result<(), RequestError> {
+    trace!("new my_lessons job");
+    let client = build_client(bot.config());
+    let session = SESSIONS.session(&credentials, bot.config());
+
+    let token = match session.token().await {
+        Ok(token) => token,
+        Err(err) => {
+            warn!("Job error: {}", &err);
+            return bot.answer(format!("Unable to log in: {}", err)).await;
+        }
+    };
+    let mut enrollments = client.my_enrollments(&token).await;
+    if matches!(&enrollments, Err(err) if err.is_unauthorized()) {
+        // The cached token might have been revoked, try once more with a new one
+        enrollments = match session.renew(&token).await {
+            Ok(token) => client.my_enrollments(&token).await,
+            Err(err) => Err(err),
+        };
+    }
+    let enrollments = match enrollments {
+        Ok(enrollments) => enrollments,
+        Err(err) => {
+            warn!("Job error: {}", &err);
+            return bot
+                .answer(format!("Unable to fetch your lessons: {}", err))
+                .await;
+        }
+    };
+
+    if enrollments.is_empty() {
+        return bot
+            .answer("You are not enrolled in any upcoming lessons.".to_string())
+            .await;
+    }
+    let mut text = "You are enrolled in these lessons:".to_string();
+    for enrollment in &enrollments {
+        text.push_str(&format!("\n{}", describe(enrollment)));
+    }
+    bot.answer(text).await
+}
+
+fn describe(enrollment: &Enrollment) -> String {
+    let mut description = format!(
+        "{}: {}, {} - {}",
+        enrollment.lesson_id,
+        enrollment.title,
+        enrollment.lesson_start.format("%a %d.%m. %H:%M"),
+        enrollment.lesson_end.format("%H:%M"),
+    );
+    if let Some(location) = &enrollment.location {
+        description.push_str(&format!(", {}", location));
+    }
+    description.push_str(&format!(", place {}", enrollment.place_number));
+    if let EnrollmentStatus::Unknown(status) = enrollment.status_id {
+        description.push_str(&format!(" (status {})", status));
+    }
+    description
+}
@@ -1,27 +1,25 @@
 use std::cmp::max;
 use std::time::Duration;
 
-use reqwest::Client;
-use reqwest_middleware::{ClientBuilder, ClientWithMiddleware};
-use reqwest_retry::{policies::ExponentialBackoff, RetryTransientMiddleware};
-use reqwest_tracing::{DefaultSpanBackend, TracingMiddleware};
 use teloxide::{prelude::*, RequestError};
 use tracing::{instrument, trace};
 
+use asvz::client::AsvzClient;
 use asvz::error::AsvzError;
 use asvz::lesson::LessonID;
-use asvz::lesson::{lesson_data, search_data};
 
+use crate::job_fns::utils::{build_client, check_lesson};
 use crate::job_fns::ExistStatus;
 use crate::job_update_cx::JobUpdateCx;
 use crate::utils::current_timestamp;
 use crate::utils::reply;
 use crate::utils::ret_on_err;
+use crate::watcher::LESSON_WATCHER;
 
 #[instrument(skip(cx))]
 pub async fn notify(cx: &JobUpdateCx, id: LessonID) -> Result<ExistStatus, RequestError> {
     trace!("new notify job");
-    let client = build_client();
+    let client = build_client(cx.config());
     notify_once(&client, cx, &id).await
 }
 
@@ -31,7 +29,7 @@ pub async fn notify_weekly(
     start_id: LessonID,
 ) -> Result<ExistStatus, RequestError> {
     trace!("new notify_weekly job");
-    let client = build_client();
+    let client = build_client(cx.config());
     let mut current_id = start_id;
     loop {
         match notify_once(&client, cx, &current_id).await? {
@@ -41,9 +39,10 @@ pub async fn notify_weekly(
             ExistStatus::Error(msg) => return Ok(ExistStatus::Error(msg)),
         }
 
-        let event_list = ret_on_err!(search_data(&client, &current_id, 1).await);
+        let event_list = ret_on_err!(client.search_data(&current_id, 1).await);
         if let Some(id) = event_list.lesson_id() {
             current_id = id;
+            cx.advance(current_id.clone());
             reply!(cx, "Found next week's lesson: {}", current_id.as_str()).await?;
         } else {
             return Ok(ExistStatus::failure("Unable to find next lesson"));
@@ -52,21 +51,30 @@ pub async fn notify_weekly(
 }
 
 async fn notify_once(
-    client: &ClientWithMiddleware,
+    client: &AsvzClient,
     cx: &JobUpdateCx,
     id: &LessonID,
 ) -> Result<ExistStatus, RequestError> {
     trace!("notify_once");
-    let data = ret_on_err!(lesson_data(client, id).await);
+    let data = ret_on_err!(client.lesson_data(id).await);
+    if let Some(status) = check_lesson(&data.data) {
+        return Ok(status);
+    }
+    if data.data.is_lottery() {
+        reply!(
+            cx,
+            "This is a lottery lesson, the places are drawn after the enrollment closes."
+        )
+        .await?;
+    }
     let current_ts = current_timestamp();
 
-    let until_ts = ret_on_err!(data.enroll_until_timestamp());
-
-    let from_ts = ret_on_err!(data.enroll_from_timestamp());
+    let from_ts = data.data.enrollment_from.timestamp();
 
     if from_ts > current_ts {
         // We still need to wait to enroll
-        let wait_time = max(from_ts - current_ts - 60, 0) as u64;
+        let lead_time = i64::from(cx.settings().lead_time_minutes) * 60;
+        let wait_time = max(from_ts - current_ts - lead_time, 0) as u64;
         reply!(cx, "I will remind you to enroll in {} seconds.", wait_time).await?;
         tokio::time::sleep(Duration::from_secs(wait_time)).await;
         let current_time = current_timestamp();
@@ -74,12 +82,12 @@ async fn notify_once(
         return Ok(ExistStatus::success(msg));
     }
 
+    let mut subscription = LESSON_WATCHER.subscribe(id, cx.config());
     for count in 0.. {
-        if current_ts > until_ts {
-            return Ok(ExistStatus::failure("You can no longer enroll."));
-        }
-
-        let fresh_data = ret_on_err!(lesson_data(client, id).await);
+        let fresh_data = match subscription.next_until(data.data.enrollment_until).await {
+            Some(data) => data,
+            None => return Ok(ExistStatus::failure("You can no longer enroll.")),
+        };
         let free_places = fresh_data.data.participants_max - fresh_data.data.participant_count;
         if free_places > 0 {
             let msg = format!("There are currently {} free spots.", free_places);
@@ -92,15 +100,6 @@ async fn notify_once(
             )
             .await?;
         }
-        tokio::time::sleep(Duration::from_secs(10)).await;
     }
     unreachable!()
 }
-
-fn build_client() -> ClientWithMiddleware {
-    let retry_policy = ExponentialBackoff::builder().build_with_max_retries(3);
-    ClientBuilder::new(Client::builder().cookie_store(true).build().unwrap())
-        .with(TracingMiddleware::<DefaultSpanBackend>::new())
-        .with(RetryTransientMiddleware::new_with_policy(retry_policy))
-        .build()
-}
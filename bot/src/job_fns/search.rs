@@ -0,0 +1,188 @@
+use chrono::{Duration, Local, NaiveDate};
+use futures::future::join_all;
+use teloxide::types::InlineKeyboardMarkup;
+use teloxide::RequestError;
+use tracing::{instrument, trace, warn};
+
+use asvz::api::lesson::{LessonData, RegistrationType};
+use asvz::client::AsvzClient;
+use asvz::error::AsvzError;
+use asvz::lesson::{LessonID, SearchQuery};
+
+use crate::callback::{CallbackData, LessonAction};
+use crate::config::JobConfig;
+use crate::job_fns::utils::build_client;
+use crate::user::{BotCtx, LessonLanguage};
+
+#[instrument(skip(bot))]
+pub async fn search(
+    bot: &BotCtx,
+    sport: String,
+    date: Option<NaiveDate>,
+) -> Result<(), RequestError> {
+    trace!("new search job");
+    let client = build_client(bot.config());
+
+    let sports = match client.get_sport_data().await {
+        Ok(sports) => sports,
+        Err(err) => {
+            warn!("Job error: {}", &err);
+            return bot.answer(format!("Unable to search: {}", err)).await;
+        }
+    };
+    let (sport_name, sport_id) = match find_sport(sports.iter(), &sport) {
+        Ok(found) => found,
+        Err(msg) => return bot.answer(msg).await,
+    };
+
+    let mut query = SearchQuery::new().sport(sport_id.clone());
+    query = match date {
+        Some(date) => query
+            .from(date.and_hms_opt(0, 0, 0).unwrap())
+            .until((date + Duration::days(1)).and_hms_opt(0, 0, 0).unwrap()),
+        None => query.from(Local::now().naive_local()),
+    };
+    let language = bot.settings().lesson_language;
+    let lessons = match find_lessons(&client, &query, language, &bot.config().jobs).await {
+        Ok(lessons) => lessons,
+        Err(err) => {
+            warn!("Job error: {}", &err);
+            return bot.answer(format!("Unable to search: {}", err)).await;
+        }
+    };
+    if lessons.is_empty() {
+        if language == LessonLanguage::Any {
+            return bot
+                .answer(format!("I couldn't find any {} lessons.", sport_name))
+                .await;
+        }
+        return bot
+            .answer(format!(
+                "I couldn't find any {} lessons in {}. See /settings.",
+                sport_name, language
+            ))
+            .await;
+    }
+
+    let mut text = format!("Found these {} lessons:", sport_name);
+    if language != LessonLanguage::Any {
+        text = format!("Found these {} lessons in {}:", sport_name, language);
+    }
+    let mut rows = Vec::new();
+    for (id, lesson) in lessons {
+        match lesson {
+            Ok(lesson) => text.push_str(&format!("\n{}: {}", id.as_str(), describe(&lesson))),
+            Err(err) => text.push_str(&format!("\n{}: {}", id.as_str(), err)),
+        }
+        rows.push(vec![
+            CallbackData::Lesson(LessonAction::Notify, id.clone()).button(),
+            CallbackData::Lesson(LessonAction::Enroll, id.clone()).button(),
+        ]);
+    }
+    bot.answer_with_keyboard(text, InlineKeyboardMarkup::new(rows))
+        .await
+}
+
+/// Goes through the search results until `max_search_results` lessons are held in `language`.
+/// The search has no language filter, so the details of every result are fetched,
+/// but only as many at once as could still be shown.
+async fn find_lessons(
+    client: &AsvzClient,
+    query: &SearchQuery,
+    language: LessonLanguage,
+    config: &JobConfig,
+) -> Result<Vec<(LessonID, Result<LessonData, AsvzError>)>, AsvzError> {
+    let max_results = config.max_search_results;
+    let mut lessons = Vec::new();
+    let mut searched = 0;
+    let mut pages = client.search_pages(query);
+    while lessons.len() < max_results && searched < config.max_searched_lessons {
+        let page = match pages.next().await? {
+            Some(page) => page,
+            None => break,
+        };
+        let mut ids: Vec<_> = page
+            .iter()
+            .filter_map(|result| result.lesson_id())
+            .collect();
+        ids.truncate(config.max_searched_lessons - searched);
+        let mut ids = &ids[..];
+        while !ids.is_empty() && lessons.len() < max_results {
+            let (batch, rest) = ids.split_at(ids.len().min(max_results - lessons.len()));
+            ids = rest;
+            searched += batch.len();
+            let details = join_all(batch.iter().map(|id| client.lesson_data(id))).await;
+            lessons.extend(
+                batch
+                    .iter()
+                    .cloned()
+                    .zip(details)
+                    .filter(|(_, lesson)| match lesson {
+                        Ok(lesson) => language.matches(&lesson.data.language),
+                        Err(_) => true,
+                    }),
+            );
+        }
+    }
+    Ok(lessons)
+}
+
+/// Finds a sport by its name. An exact match (ignoring case) wins,
+/// otherwise the name has to be part of exactly one sport.
+fn find_sport<'a>(
+    sports: impl Iterator<Item = (&'a String, &'a String)>,
+    name: &str,
+) -> Result<(String, String), String> {
+    let name = name.to_lowercase();
+    let mut candidates = Vec::new();
+    for (sport_name, sport_id) in sports {
+        let lower = sport_name.to_lowercase();
+        if lower == name {
+            return Ok((sport_name.clone(), sport_id.clone()));
+        } else if lower.contains(&name) {
+            candidates.push((sport_name.clone(), sport_id.clone()));
+        }
+    }
+    match candidates.len() {
+        0 => Err(format!("I don't know the sport {}.", name)),
+        1 => Ok(candidates.remove(0)),
+        _ => {
+            let mut names: Vec<_> = candidates.into_iter().map(|(name, _)| name).collect();
+            names.sort();
+            Err(format!("Did you mean one of these?\n{}", names.join("\n")))
+        }
+    }
+}
+
+fn describe(lesson: &LessonData) -> String {
+    let data = &lesson.data;
+    let time = format!(
+        "{} - {}",
+        data.starts.format("%a %d.%m. %H:%M"),
+        data.ends.format("%H:%M")
+    );
+    let place = data
+        .location
+        .clone()
+        .or_else(|| {
+            data.facilities
+                .first()
+                .map(|facility| facility.name.clone())
+        })
+        .unwrap_or_default();
+    let free_places = data.participants_max - data.participant_count;
+    let mut description = format!(
+        "{}, {}, {}/{} free",
+        time, place, free_places, data.participants_max
+    );
+    if data.is_cancelled() {
+        description.push_str(", cancelled");
+    } else if data.web_registration_type == RegistrationType::OnSite {
+        description.push_str(", on-site registration");
+    } else if data.web_registration_type == RegistrationType::NotRequired {
+        description.push_str(", no registration");
+    } else if data.is_lottery() {
+        description.push_str(", lottery");
+    }
+    description
+}
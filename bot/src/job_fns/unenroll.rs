@@ -0,0 +1,79 @@
+use chrono::Local;
+use reqwest::StatusCode;
+use teloxide::RequestError;
+use tracing::{instrument, trace, warn};
+
+use asvz::enrollment::UnenrollmentResponse;
+use asvz::lesson::LessonID;
+
+use crate::job_fns::utils::build_client;
+use crate::session::SESSIONS;
+use crate::user::{BotCtx, LoginCredentials};
+
+#[instrument(skip(bot, credentials))]
+pub async fn unenroll(
+    bot: &BotCtx,
+    id: LessonID,
+    credentials: LoginCredentials,
+) -> Result<(), RequestError> {
+    trace!("new unenroll job");
+    let client = build_client(bot.config());
+
+    let data = match client.lesson_data(&id).await {
+        Ok(data) => data.data,
+        Err(err) => {
+            warn!("Job error: {}", &err);
+            return bot.answer(format!("Unable to unenroll: {}", err)).await;
+        }
+    };
+    if Local::now() > data.cancelation_until {
+        let deadline = data.cancelation_until.format("%a %d.%m. %H:%M");
+        let msg = format!(
+            "The cancellation deadline of {} passed on {}. I will try anyway, \
+            but you might have to contact the ASVZ.",
+            id.as_str(),
+            deadline
+        );
+        bot.answer(msg).await?;
+    }
+
+    let session = SESSIONS.session(&credentials, bot.config());
+    let token = match session.token().await {
+        Ok(token) => token,
+        Err(err) => {
+            warn!("Job error: {}", &err);
+            return bot.answer(format!("Unable to log in: {}", err)).await;
+        }
+    };
+    let mut response = client.unenroll(&token, &id).await;
+    if let Ok(UnenrollmentResponse::Unexpected(StatusCode::UNAUTHORIZED)) = response {
+        // The cached token might have been revoked, try once more with a new one
+        response = match session.renew(&token).await {
+            Ok(token) => client.unenroll(&token, &id).await,
+            Err(err) => Err(err),
+        };
+    }
+
+    let msg = match response {
+        Ok(UnenrollmentResponse::Unenrolled) => {
+            format!("I removed your enrollment in {}", id.as_str())
+        }
+        Ok(UnenrollmentResponse::NotEnrolled) => {
+            format!("You are not enrolled in {}", id.as_str())
+        }
+        Ok(UnenrollmentResponse::Rejected) => {
+            format!("The ASVZ didn't let me cancel {}", id.as_str())
+        }
+        Ok(UnenrollmentResponse::TooManyRequests) => {
+            "Too many requests, please try again later".to_string()
+        }
+        Ok(UnenrollmentResponse::Unexpected(code)) => {
+            format!("Got unexpected status code: {}", code)
+        }
+        Err(err) => {
+            warn!("Job error: {}", &err);
+            format!("Unable to unenroll: {}", err)
+        }
+    };
+    bot.answer(msg).await
+}
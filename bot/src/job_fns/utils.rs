@@ -1,13 +1,28 @@
 use std::sync::Arc;
 
 use futures::Future;
+use reqwest::Client;
+use reqwest_middleware::{ClientBuilder, ClientWithMiddleware};
+use reqwest_retry::{
+    policies::ExponentialBackoff, DefaultRetryableStrategy, RetryTransientMiddleware,
+    RetryableStrategy,
+};
+use reqwest_tracing::TracingMiddleware;
+use tracing::warn;
+
+use asvz::api::lesson::{Data, RegistrationType};
+use asvz::client::AsvzClient;
+use asvz::lesson::LessonID;
 use teloxide::prelude::*;
 use teloxide::RequestError;
+use tokio::sync::watch;
 
-use crate::job::JobKind;
+use crate::config::Config;
+use crate::job::{current_kind, JobId, JobKind};
 use crate::job_err::JobError;
 use crate::job_fns::ExistStatus;
 use crate::job_update_cx::JobUpdateCx;
+use crate::metrics::MetricsSpanBackend;
 use crate::user::{BotCtx, UserId};
 
 pub async fn wrap_exit_status(
@@ -27,11 +42,71 @@ pub async fn wrap_exit_status(
 
 pub async fn attach_ctx<T>(
     fut: impl Future<Output = Result<T, RequestError>>,
+    job_id: Option<JobId>,
     user_id: UserId,
     job_kind: JobKind,
+    lesson: Option<watch::Receiver<LessonID>>,
     bot: BotCtx,
     retry_count: usize,
 ) -> Result<T, JobError> {
-    fut.await
-        .map_err(|err| JobError::new(err, user_id, job_kind, bot, retry_count))
+    fut.await.map_err(|err| {
+        // Restart at the lesson the job got to
+        let job_kind = current_kind(&job_kind, &lesson);
+        JobError::new(err, job_id, user_id, job_kind, bot, retry_count)
+    })
+}
+
+pub fn build_client(config: &Config) -> AsvzClient {
+    build_client_with(config, DefaultRetryableStrategy)
+}
+
+/// Builds a client whose transient errors are told apart by `strategy`.
+pub fn build_client_with(
+    config: &Config,
+    strategy: impl RetryableStrategy + Send + Sync + 'static,
+) -> AsvzClient {
+    let retry_policy =
+        ExponentialBackoff::builder().build_with_max_retries(config.asvz.max_retries);
+    let client = ClientBuilder::new(Client::builder().cookie_store(true).build().unwrap())
+        .with(TracingMiddleware::<MetricsSpanBackend>::new())
+        .with(RetryTransientMiddleware::new_with_policy_and_strategy(
+            retry_policy,
+            strategy,
+        ))
+        .build();
+    AsvzClient::with_urls(client, config.asvz.urls())
+}
+
+/// Checks whether the bot can do anything for this lesson at all.
+/// Returns the reason if it can't.
+///
+/// Unknown registration types are just logged, the job tries the lesson anyway.
+pub fn check_lesson(data: &Data) -> Option<ExistStatus> {
+    if data.is_cancelled() {
+        let msg = match data.cancellation_reason() {
+            Some(reason) => format!("This lesson is cancelled: {}", reason),
+            None => "This lesson is cancelled".to_string(),
+        };
+        return Some(ExistStatus::failure(msg));
+    }
+    match data.web_registration_type {
+        RegistrationType::Online => {}
+        RegistrationType::OnSite => {
+            return Some(ExistStatus::failure(
+                "This lesson only takes registrations on site, I can't enroll you.",
+            ));
+        }
+        RegistrationType::NotRequired => {
+            return Some(ExistStatus::success(
+                "This lesson needs no registration, you can just show up.",
+            ));
+        }
+        RegistrationType::Unknown(_) => {
+            warn!(
+                "Registration type {:?} of lesson {}",
+                data.web_registration_type, data.id
+            );
+        }
+    }
+    None
 }
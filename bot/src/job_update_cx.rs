@@ -2,22 +2,37 @@ use std::sync::Arc;
 
 use teloxide::prelude::*;
 use teloxide::RequestError;
+use tokio::sync::watch;
 
-use crate::user::BotCtx;
+use crate::config::Config;
+use crate::user::{BotCtx, Settings};
 use asvz::lesson::LessonID;
 
 pub struct JobUpdateCx {
     bot: BotCtx,
-    id: LessonID,
+    lesson: watch::Sender<LessonID>,
 }
 
 impl JobUpdateCx {
-    pub fn new(bot: BotCtx, id: LessonID) -> Self {
-        Self { bot, id }
+    pub fn new(bot: BotCtx, lesson: watch::Sender<LessonID>) -> Self {
+        Self { bot, lesson }
+    }
+
+    /// Moves a weekly job on to the next lesson, so it is resumed there after a restart.
+    pub fn advance(&self, id: LessonID) {
+        self.lesson.send_replace(id);
     }
 
     fn transform_msg(&self, text: &str) -> String {
-        format!("[{}] {}", self.id.as_str(), text)
+        format!("[{}] {}", self.lesson.borrow().as_str(), text)
+    }
+
+    pub fn config(&self) -> &Config {
+        self.bot.config()
+    }
+
+    pub fn settings(&self) -> Settings {
+        self.bot.settings()
     }
 
     pub async fn answer<T: Into<String>>(&self, text: T) -> Result<(), RequestError> {
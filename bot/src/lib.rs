@@ -0,0 +1,19 @@
+#![allow(unused_imports)]
+#![allow(dead_code)]
+#![allow(clippy::new_without_default)]
+
+pub mod callback;
+pub mod cmd;
+pub mod config;
+pub mod job;
+pub mod job_err;
+pub mod job_fns;
+pub mod job_update_cx;
+pub mod metrics;
+pub mod session;
+pub mod state;
+pub mod storage;
+pub mod user;
+pub mod utils;
+pub mod vault;
+pub mod watcher;
@@ -2,38 +2,61 @@
 #![allow(dead_code)]
 #![allow(clippy::new_without_default)]
 
+use std::pin::Pin;
 use std::sync::Arc;
+use std::time::Duration;
 
-use futures::stream::StreamExt;
+use futures::stream::{Stream, StreamExt};
 use reqwest::Client;
 use teloxide::prelude::*;
-use teloxide::types::UpdateKind;
+use teloxide::types::{InputFile, Update, UpdateKind};
 use teloxide::update_listeners;
-use teloxide::update_listeners::AsUpdateStream;
-use tracing::{info, Level};
+use teloxide::update_listeners::{webhooks, AsUpdateStream};
+use teloxide::RequestError;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
+use tokio::time::MissedTickBehavior;
+use tracing::{error, info, warn, Level};
 use tracing_subscriber::EnvFilter;
 
-use asvz::lesson::search_data;
 use asvz::lesson::LessonID;
 
-use crate::state::State;
+use asvz_bot::config::{Config, WebhookConfig};
+use asvz_bot::metrics;
+use asvz_bot::state::State;
+use asvz_bot::storage::{JsonStorage, NoStorage, Storage};
+use asvz_bot::vault::CredentialVault;
 
-pub mod cmd;
-pub mod job;
-pub mod job_err;
-pub mod job_fns;
-pub mod job_update_cx;
-pub mod state;
-pub mod user;
-pub mod utils;
-
-static BOT_NAME: &str = "asvz_bot";
+/// How often to look for inactive users.
+const WIPE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// How often changes to the users and jobs are written to the storage.
+const SAVE_INTERVAL: Duration = Duration::from_secs(1);
 
 #[tokio::main]
 async fn main() {
     run().await;
 }
 
+fn webhook_options(webhook: &WebhookConfig) -> webhooks::Options {
+    let mut options = webhooks::Options::new(webhook.address, webhook.url.clone());
+    if let Some(secret) = &webhook.secret {
+        options = options.secret_token(secret.clone());
+    }
+    if let Some(path) = &webhook.certificate {
+        options = options.certificate(InputFile::file(path));
+    }
+    options
+}
+
+/// Completes on SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("Unable to listen for SIGTERM");
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result.expect("Unable to listen for SIGINT"),
+        _ = terminate.recv() => (),
+    }
+}
+
 async fn run() {
     let filter = EnvFilter::from_default_env()
         .add_directive(Level::TRACE.into())
@@ -43,44 +66,114 @@ async fn run() {
     tracing::subscriber::set_global_default(subscriber).expect("Unable to make logging");
 
     // let client = Client::new();
-    // dbg!(search_data(&client, &LessonID("236310".to_string()), 1).await);
+    // dbg!(client.search_data(&LessonID("236310".to_string()), 1).await);
 
     info!("Starting Bot");
 
+    let config = Arc::new(Config::load().expect("Unable to load the config"));
     let bot = Bot::from_env();
-    let mut state = State::new();
+    let me = bot.get_me().await.expect("Unable to get the bot user");
+    let bot_name = me.username().to_string();
+    info!("Running as {}", bot_name);
+    let storage: Box<dyn Storage> = match &config.storage_path {
+        Some(path) => Box::new(JsonStorage::new(path)),
+        None => Box::new(NoStorage),
+    };
+    let vault = CredentialVault::from_env().expect("Unable to read the vault key");
+    if vault.is_none() {
+        info!("No vault key configured, credentials won't be stored");
+    }
+    if let Some(address) = config.metrics_address {
+        if let Err(err) = metrics::serve(address) {
+            error!("Not serving metrics at {}: {}", address, err);
+        }
+    }
+    let (events, mut event_receiver) = mpsc::unbounded_channel();
+    let mut state = State::new(config.clone(), bot_name, storage, vault, events);
+    state
+        .restore(bot.clone())
+        .expect("Unable to restore the saved state");
 
-    let mut bot_update = update_listeners::polling_default(bot.clone()).await;
-    let bot_stream = bot_update.as_stream();
+    // Both listeners are kept here, as their streams borrow them
+    let mut polling_listener;
+    let mut webhook_listener;
+    let bot_stream: Pin<Box<dyn Stream<Item = Result<Update, RequestError>>>> =
+        match &config.webhook {
+            Some(webhook) => {
+                info!("Receiving updates with a webhook at {}", webhook.address);
+                webhook_listener = webhooks::axum(bot.clone(), webhook_options(webhook))
+                    .await
+                    .expect("Unable to set up the webhook");
+                Box::pin(
+                    webhook_listener
+                        .as_stream()
+                        .map(|update| update.map_err(|never| match never {})),
+                )
+            }
+            None => {
+                info!("Receiving updates with long polling");
+                polling_listener = update_listeners::polling_default(bot.clone()).await;
+                Box::pin(polling_listener.as_stream())
+            }
+        };
     tokio::pin!(bot_stream);
+    let mut wipe_interval = tokio::time::interval(WIPE_INTERVAL);
+    let mut save_interval = tokio::time::interval(SAVE_INTERVAL);
+    save_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
 
     loop {
         tokio::select! {
+            _ = &mut shutdown => {
+                info!("Shutting down");
+                break;
+            },
             Some(update) = bot_stream.next() => {
                 match update {
                     Ok(update) => {
-                        if let UpdateKind::Message(msg) = update.kind {
-                            state.handle_update(bot.clone(), msg);
+                        match update.kind {
+                            UpdateKind::Message(msg) => state.handle_update(bot.clone(), msg),
+                            UpdateKind::CallbackQuery(query) => {
+                                state.handle_callback(bot.clone(), query)
+                            }
+                            _ => (),
                         }
                     }
                     Err(err) => state.handle_req_err(err),
                 }
             },
+            _ = wipe_interval.tick() => state.wipe_inactive_credentials(),
+            _ = save_interval.tick() => state.flush().await,
+            Some(event) = event_receiver.recv() => {
+                state.handle_event(event);
+                state.mark_dirty();
+            },
             Some(handle_result) = state.next() => {
                 match handle_result {
                     Ok(result) => {
                         if let Err(err) = result {
                             state.handle_job_err(err)
                         }
+                        state.mark_dirty();
                     },
                     Err(err) => {
                         if let Ok(reason) = err.try_into_panic() {
                             std::panic::resume_unwind(reason);
                         }
+                        state.mark_dirty();
                     }
                 }
             },
             else => break,
         }
     }
+
+    // New updates are no longer handled from here on
+    if tokio::time::timeout(config.shutdown_timeout(), state.shutdown())
+        .await
+        .is_err()
+    {
+        warn!("Not all users could be told about the shutdown in time");
+    }
 }
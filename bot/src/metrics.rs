@@ -0,0 +1,226 @@
+use std::net::{SocketAddr, TcpListener};
+use std::time::Instant;
+
+use axum::http::{header, StatusCode};
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::Router;
+use lazy_static::lazy_static;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use reqwest::{Request, Response};
+use reqwest_middleware::Result;
+use reqwest_tracing::{DefaultSpanBackend, ReqwestOtelSpanBackend};
+use task_local_extensions::Extensions;
+use teloxide::RequestError;
+use tracing::{error, info, Span};
+
+use asvz::error::AsvzError;
+use asvz::login::LoginMethod;
+
+lazy_static! {
+    static ref REGISTRY: Registry = Registry::new();
+    static ref ACTIVE_JOBS: IntGaugeVec = register(IntGaugeVec::new(
+        Opts::new("asvz_bot_active_jobs", "Running jobs by their kind"),
+        &["kind"]
+    ));
+    static ref ASVZ_REQUEST_DURATION: HistogramVec = register(HistogramVec::new(
+        HistogramOpts::new(
+            "asvz_request_duration_seconds",
+            "Duration of requests to ASVZ, including retries"
+        ),
+        &["host"]
+    ));
+    static ref ASVZ_RESPONSES: IntCounterVec = register(IntCounterVec::new(
+        Opts::new(
+            "asvz_responses_total",
+            "Responses from ASVZ by status code, `error` if there was none"
+        ),
+        &["host", "status"]
+    ));
+    static ref LOGINS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("asvz_logins_total", "Logins by method and result"),
+        &["method", "result"]
+    ));
+    static ref ENROLLMENT_ATTEMPTS: IntCounter = register(IntCounter::new(
+        "asvz_enrollment_attempts_total",
+        "Requests to enroll in a lesson"
+    ));
+    static ref ENROLLMENT_WINS: IntCounter = register(IntCounter::new(
+        "asvz_enrollment_wins_total",
+        "Requests to enroll in a lesson that got a spot"
+    ));
+    static ref ENROLLMENT_DELAY: Histogram = register(Histogram::with_opts(
+        HistogramOpts::new(
+            "asvz_enrollment_delay_seconds",
+            "Time from the opening of the enrollment to getting a spot"
+        )
+        .buckets(vec![0.05, 0.1, 0.25, 0.5, 1.0, 2.0, 5.0])
+    ));
+    static ref TELEGRAM_ERRORS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new(
+            "asvz_bot_telegram_errors_total",
+            "Errors while sending messages to Telegram"
+        ),
+        &["kind"]
+    ));
+}
+
+fn register<T: prometheus::core::Collector + Clone + 'static>(
+    collector: prometheus::Result<T>,
+) -> T {
+    let collector = collector.expect("Invalid metric");
+    REGISTRY
+        .register(Box::new(collector.clone()))
+        .expect("Metric registered twice");
+    collector
+}
+
+/// Counts a job as active until it is dropped, i.e. finished or aborted.
+#[derive(Debug)]
+pub struct ActiveJobGuard(&'static str);
+
+impl ActiveJobGuard {
+    pub fn new(kind: &'static str) -> Self {
+        ACTIVE_JOBS.with_label_values(&[kind]).inc();
+        Self(kind)
+    }
+}
+
+impl Drop for ActiveJobGuard {
+    fn drop(&mut self) {
+        ACTIVE_JOBS.with_label_values(&[self.0]).dec();
+    }
+}
+
+pub fn record_login<T>(method: LoginMethod, result: &std::result::Result<T, AsvzError>) {
+    let result = match result {
+        Ok(_) => "success",
+        Err(AsvzError::WrongPassword | AsvzError::UnknownUser | AsvzError::WrongCredentials) => {
+            "rejected"
+        }
+        Err(_) => "error",
+    };
+    LOGINS
+        .with_label_values(&[&method.to_string(), result])
+        .inc();
+}
+
+pub fn record_enrollment_attempt(enrolled: bool) {
+    ENROLLMENT_ATTEMPTS.inc();
+    if enrolled {
+        ENROLLMENT_WINS.inc();
+    }
+}
+
+pub fn record_enrollment_delay(delay: chrono::Duration) {
+    if let Ok(delay) = delay.to_std() {
+        ENROLLMENT_DELAY.observe(delay.as_secs_f64());
+    }
+}
+
+pub fn record_telegram_error(err: &RequestError) {
+    let kind = match err {
+        RequestError::Api(_) => "api",
+        RequestError::MigrateToChatId(_) => "migrate_to_chat_id",
+        RequestError::RetryAfter(_) => "retry_after",
+        RequestError::Network(_) => "network",
+        RequestError::InvalidJson { .. } => "invalid_json",
+        RequestError::Io(_) => "io",
+    };
+    TELEGRAM_ERRORS.with_label_values(&[kind]).inc();
+}
+
+#[cfg(test)]
+pub(crate) fn telegram_errors(kind: &str) -> u64 {
+    TELEGRAM_ERRORS.with_label_values(&[kind]).get()
+}
+
+/// Traces requests like [`DefaultSpanBackend`] and records their duration and status.
+pub struct MetricsSpanBackend;
+
+impl ReqwestOtelSpanBackend for MetricsSpanBackend {
+    fn on_request_start(req: &Request, extension: &mut Extensions) -> Span {
+        extension.insert(RequestStart {
+            host: req.url().host_str().unwrap_or_default().to_string(),
+            instant: Instant::now(),
+        });
+        DefaultSpanBackend::on_request_start(req, extension)
+    }
+
+    fn on_request_end(span: &Span, outcome: &Result<Response>, extension: &mut Extensions) {
+        if let Some(start) = extension.get::<RequestStart>() {
+            ASVZ_REQUEST_DURATION
+                .with_label_values(&[&start.host])
+                .observe(start.instant.elapsed().as_secs_f64());
+            let status = match outcome {
+                Ok(response) => response.status().as_u16().to_string(),
+                Err(_) => "error".to_string(),
+            };
+            ASVZ_RESPONSES
+                .with_label_values(&[&start.host, &status])
+                .inc();
+        }
+        DefaultSpanBackend::on_request_end(span, outcome, extension)
+    }
+}
+
+struct RequestStart {
+    host: String,
+    instant: Instant,
+}
+
+/// Serves the metrics at `/metrics` until the bot stops.
+/// Fails if the address can't be bound, e.g. because the port is taken.
+pub fn serve(address: SocketAddr) -> std::io::Result<()> {
+    // Metrics are registered on first use, but should show up from the start
+    lazy_static::initialize(&ACTIVE_JOBS);
+    lazy_static::initialize(&ASVZ_REQUEST_DURATION);
+    lazy_static::initialize(&ASVZ_RESPONSES);
+    lazy_static::initialize(&LOGINS);
+    lazy_static::initialize(&ENROLLMENT_ATTEMPTS);
+    lazy_static::initialize(&ENROLLMENT_WINS);
+    lazy_static::initialize(&ENROLLMENT_DELAY);
+    lazy_static::initialize(&TELEGRAM_ERRORS);
+    let listener = TcpListener::bind(address)?;
+    let server = axum::Server::from_tcp(listener).map_err(std::io::Error::other)?;
+    let app = Router::new().route("/metrics", get(metrics));
+    info!("Serving metrics at {}", address);
+    tokio::spawn(async move {
+        if let Err(err) = server.serve(app.into_make_service()).await {
+            error!("The metrics server stopped: {}", err);
+        }
+    });
+    Ok(())
+}
+
+async fn metrics() -> impl IntoResponse {
+    let mut buffer = Vec::new();
+    let encoder = TextEncoder::new();
+    match encoder.encode(&REGISTRY.gather(), &mut buffer) {
+        Ok(()) => (
+            StatusCode::OK,
+            [(header::CONTENT_TYPE, encoder.format_type().to_string())],
+            buffer,
+        ),
+        Err(err) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            [(header::CONTENT_TYPE, "text/plain".to_string())],
+            err.to_string().into_bytes(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn taken_port_is_an_error() {
+        let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        assert!(serve(taken.local_addr().unwrap()).is_err());
+    }
+}
@@ -0,0 +1,265 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use tracing::{instrument, trace};
+
+use asvz::client::AsvzClient;
+use asvz::error::AsvzError;
+use asvz::login::{token_expiry, LoginMethod};
+
+use crate::config::Config;
+use crate::job_fns::utils::build_client;
+use crate::metrics;
+use crate::user::LoginCredentials;
+
+lazy_static! {
+    pub static ref SESSIONS: SessionManager = SessionManager::default();
+}
+
+/// Tokens are renewed this many minutes before they expire,
+/// so they don't run out in the middle of an enrollment.
+const EXPIRY_MARGIN_MINUTES: i64 = 5;
+/// How long a token is used if its expiry can't be read.
+const FALLBACK_LIFETIME_MINUTES: i64 = 30;
+
+/// Keeps one [`Session`] per ASVZ account, shared by all jobs of that account.
+///
+/// A session is only shared with the same password. Otherwise its token or the cookies
+/// of its login would let wrong credentials pass.
+#[derive(Debug, Default)]
+pub struct SessionManager {
+    sessions: Mutex<HashMap<(LoginMethod, String), Arc<Session>>>,
+}
+
+impl SessionManager {
+    /// Returns the session of the account, or replaces it with a new one
+    /// if it was logged in with a different password.
+    pub fn session(&self, credentials: &LoginCredentials, config: &Config) -> Arc<Session> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let key = session_key(credentials);
+        match sessions.get(&key) {
+            Some(session) if session.matches(credentials) => session.clone(),
+            _ => {
+                trace!("starting new session");
+                let session = Arc::new(Session::new(credentials.clone(), build_client(config)));
+                sessions.insert(key, session.clone());
+                session
+            }
+        }
+    }
+
+    /// Forgets the session, e.g. because the user logged out.
+    /// Jobs that are still running keep using their copy.
+    pub fn remove(&self, credentials: &LoginCredentials) {
+        let mut sessions = self.sessions.lock().unwrap();
+        let key = session_key(credentials);
+        if sessions
+            .get(&key)
+            .is_some_and(|session| session.matches(credentials))
+        {
+            sessions.remove(&key);
+        }
+    }
+}
+
+fn session_key(credentials: &LoginCredentials) -> (LoginMethod, String) {
+    (
+        credentials.method,
+        credentials.username.as_str().to_string(),
+    )
+}
+
+/// The access token of an account and the cookies of the SwitchAAI login.
+/// With the cookies, a new token doesn't need the whole login dance again.
+#[derive(Debug)]
+pub struct Session {
+    credentials: LoginCredentials,
+    client: AsvzClient,
+    token: tokio::sync::Mutex<Option<CachedToken>>,
+}
+
+#[derive(Debug)]
+struct CachedToken {
+    token: String,
+    expires: DateTime<Utc>,
+}
+
+impl Session {
+    fn new(credentials: LoginCredentials, client: AsvzClient) -> Self {
+        Self {
+            credentials,
+            client,
+            token: tokio::sync::Mutex::new(None),
+        }
+    }
+
+    /// Whether the session was created for exactly these credentials.
+    fn matches(&self, credentials: &LoginCredentials) -> bool {
+        self.credentials.method == credentials.method
+            && self.credentials.username.as_str() == credentials.username.as_str()
+            && self.credentials.password.as_str_dangerous()
+                == credentials.password.as_str_dangerous()
+    }
+
+    /// Returns the cached token, or logs in if there is none or it expires soon.
+    /// Concurrent calls wait for a single login.
+    #[instrument(skip(self), fields(username = self.credentials.username.as_str()))]
+    pub async fn token(&self) -> Result<String, AsvzError> {
+        let mut cached = self.token.lock().await;
+        let margin = chrono::Duration::minutes(EXPIRY_MARGIN_MINUTES);
+        if let Some(cached) = &*cached {
+            if cached.expires - margin > Utc::now() {
+                trace!("reusing token");
+                return Ok(cached.token.clone());
+            }
+        }
+
+        trace!("logging in");
+        let credentials = &self.credentials;
+        let token = self
+            .client
+            .login(
+                credentials.method,
+                credentials.username.as_str(),
+                credentials.password.as_str_dangerous(),
+            )
+            .await;
+        metrics::record_login(credentials.method, &token);
+        let token = token?;
+        let expires = token_expiry(&token)
+            .unwrap_or_else(|| Utc::now() + chrono::Duration::minutes(FALLBACK_LIFETIME_MINUTES));
+        *cached = Some(CachedToken {
+            token: token.clone(),
+            expires,
+        });
+        Ok(token)
+    }
+
+    /// Replaces a token the server rejected. If another job already replaced it,
+    /// that new token is returned instead of logging in again.
+    pub async fn renew(&self, rejected: &str) -> Result<String, AsvzError> {
+        {
+            let mut cached = self.token.lock().await;
+            if cached
+                .as_ref()
+                .is_some_and(|cached| cached.token == rejected)
+            {
+                trace!("dropping rejected token");
+                *cached = None;
+            }
+        }
+        self.token().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    use asvz_mock::MockServer;
+
+    use crate::cmd::{Password, Username};
+
+    use super::*;
+
+    fn config(server: &MockServer) -> Config {
+        let mut config = Config::default();
+        config.asvz.www = server.url();
+        config.asvz.schalter = server.url();
+        config.asvz.auth = server.url();
+        config
+    }
+
+    fn credentials(password: &str) -> LoginCredentials {
+        LoginCredentials::new(
+            Username::from_str("user").unwrap(),
+            Password::from_str(password).unwrap(),
+            LoginMethod::default(),
+        )
+    }
+
+    #[tokio::test]
+    async fn token_is_cached() {
+        let server = MockServer::start().await;
+        server.add_user("user", "password");
+        let sessions = SessionManager::default();
+        let config = config(&server);
+
+        let first = sessions.session(&credentials("password"), &config);
+        let token = first.token().await.unwrap();
+        let second = sessions.session(&credentials("password"), &config);
+
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(second.token().await.unwrap(), token);
+        assert_eq!(server.logins(), 1);
+    }
+
+    #[tokio::test]
+    async fn token_that_expires_soon_is_replaced() {
+        let server = MockServer::start().await;
+        server.add_user("user", "password");
+        // Shorter than the margin, so the token is already too old to use
+        server.set_token_lifetime(Duration::from_secs(60));
+        let sessions = SessionManager::default();
+        let session = sessions.session(&credentials("password"), &config(&server));
+
+        let first = session.token().await.unwrap();
+        let second = session.token().await.unwrap();
+
+        assert_ne!(first, second);
+    }
+
+    #[tokio::test]
+    async fn renew_replaces_the_rejected_token_once() {
+        let server = MockServer::start().await;
+        server.add_user("user", "password");
+        let sessions = SessionManager::default();
+        let session = sessions.session(&credentials("password"), &config(&server));
+
+        let rejected = session.token().await.unwrap();
+        let renewed = session.renew(&rejected).await.unwrap();
+        assert_ne!(renewed, rejected);
+
+        // Another job with the old token gets the one that was already renewed
+        assert_eq!(session.renew(&rejected).await.unwrap(), renewed);
+        assert_eq!(session.token().await.unwrap(), renewed);
+    }
+
+    #[tokio::test]
+    async fn session_is_not_shared_with_a_wrong_password() {
+        let server = MockServer::start().await;
+        server.add_user("user", "password");
+        let sessions = SessionManager::default();
+        let config = config(&server);
+        let session = sessions.session(&credentials("password"), &config);
+        session.token().await.unwrap();
+
+        let wrong = sessions.session(&credentials("wrong"), &config);
+
+        assert!(!Arc::ptr_eq(&session, &wrong));
+        assert!(wrong.token().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn remove_needs_the_same_password() {
+        let server = MockServer::start().await;
+        let sessions = SessionManager::default();
+        let config = config(&server);
+        let session = sessions.session(&credentials("password"), &config);
+
+        sessions.remove(&credentials("wrong"));
+        assert!(Arc::ptr_eq(
+            &session,
+            &sessions.session(&credentials("password"), &config)
+        ));
+
+        sessions.remove(&credentials("password"));
+        assert!(!Arc::ptr_eq(
+            &session,
+            &sessions.session(&credentials("password"), &config)
+        ));
+    }
+}
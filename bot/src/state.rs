@@ -3,26 +3,32 @@ use std::pin::Pin;
 use std::str::FromStr;
 use std::sync::Arc;
 use std::task::Context;
-use std::thread::sleep;
 use std::time::Duration;
 
+use crate::callback::{CallbackData, JobAction, LessonAction};
 use crate::cmd::BotCommands;
 use asvz::lesson::LessonID;
+use chrono::Utc;
+use futures::future::join_all;
 use futures::stream::FuturesUnordered;
 use futures::Stream;
 use lazy_static::lazy_static;
 use regex::Regex;
-use teloxide::types::{MediaKind, MessageKind};
+use teloxide::types::{InlineKeyboardMarkup, MediaKind, MessageKind};
 use teloxide::utils::command::ParseError;
 use teloxide::{prelude::*, RequestError};
-use tokio::task::JoinError;
-use tracing::{error, instrument, trace};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::task::{JoinError, JoinHandle};
+use tracing::{error, instrument, trace, warn};
 
-use crate::cmd::Command;
-use crate::job::{InternalJob, Job, JobKind};
+use crate::cmd::{self, CancelTarget, Command};
+use crate::config::Config;
+use crate::job::{InternalJob, Job, JobBuilder, JobId, JobKind};
 use crate::job_err::JobError;
-use crate::user::{BotCtx, LoginCredentials, UrlAction, UserId, UserState};
-use crate::BOT_NAME;
+use crate::session::SESSIONS;
+use crate::storage::{Snapshot, Storage, StorageError, StoredJob, StoredJobKind, StoredUser};
+use crate::user::{BotCtx, LoginCredentials, SettingKind, Settings, UrlAction, UserId, UserState};
+use crate::vault::CredentialVault;
 
 static START_MSG: &str = r"Welcome to the ASVZ telegram bot.
 This bot allows you to get notified/enroll when a lesson starts or as soon as a spot opens up.
@@ -38,6 +44,35 @@ lazy_static! {
 pub struct State {
     jobs: FuturesUnordered<Job>,
     users: HashMap<UserId, UserState>,
+    paused: Vec<PausedJob>,
+    config: Arc<Config>,
+    /// The username of the bot, commands may be addressed to it like `/help@asvz_bot`.
+    bot_name: String,
+    storage: Arc<dyn Storage>,
+    /// Whether something changed since the last snapshot was taken.
+    dirty: bool,
+    /// The write of the last snapshot, it runs on the blocking thread pool.
+    saving: Option<JoinHandle<Result<(), StorageError>>>,
+    /// Credentials are only persisted if there is a vault to encrypt them.
+    vault: Option<CredentialVault>,
+    events: UnboundedSender<StateEvent>,
+}
+
+/// Results of jobs that change the state. They are sent back over a channel,
+/// as the jobs run detached from it.
+#[derive(Debug)]
+pub enum StateEvent {
+    /// Logging in with the credentials worked, so they can be stored.
+    LoginVerified(UserId, LoginCredentials),
+}
+
+/// A job that was stopped from the `/jobs` keyboard and can be resumed later.
+#[derive(Debug)]
+struct PausedJob {
+    id: JobId,
+    kind: JobKind,
+    user_id: UserId,
+    bot: BotCtx,
 }
 
 impl Stream for State {
@@ -52,58 +87,473 @@ impl Stream for State {
 }
 
 impl State {
-    pub fn new() -> Self {
+    pub fn new(
+        config: Arc<Config>,
+        bot_name: String,
+        storage: Box<dyn Storage>,
+        vault: Option<CredentialVault>,
+        events: UnboundedSender<StateEvent>,
+    ) -> Self {
         Self {
             jobs: FuturesUnordered::new(),
             users: HashMap::new(),
+            paused: Vec::new(),
+            config,
+            bot_name,
+            storage: storage.into(),
+            dirty: false,
+            saving: None,
+            vault,
+            events,
         }
     }
 
-    pub fn current_jobs(&self, user_id: UserId) -> String {
-        let mut r = String::from("Current Jobs:");
-        for job in self.jobs.iter().filter(|job| job.user_id == user_id) {
-            match &job.kind {
-                JobKind::Notify(id) => {
-                    r.push_str("\nNotify ");
-                    r.push_str(id.as_str());
+    /// Loads the users and restarts their jobs from the last saved snapshot.
+    #[instrument(skip(self, bot))]
+    pub fn restore(&mut self, bot: Bot) -> Result<(), StorageError> {
+        let snapshot = self.storage.load()?;
+        trace!(
+            "restoring {} users and {} jobs",
+            snapshot.users.len(),
+            snapshot.jobs.len()
+        );
+        for stored_user in snapshot.users {
+            let user_state = self
+                .users
+                .entry(stored_user.user_id)
+                .or_insert_with(UserState::new);
+            user_state.settings.send_replace(stored_user.settings);
+            user_state.next_job_id = user_state.next_job_id.max(stored_user.next_job_id);
+            if let Some(last_active) = stored_user.last_active {
+                user_state.last_active = last_active;
+            }
+            if let (Some(vault), Some(encrypted)) = (&self.vault, stored_user.encrypted_credentials)
+            {
+                user_state.credentials = match vault.decrypt(stored_user.user_id, &encrypted) {
+                    Ok(credentials) => Some(credentials),
+                    Err(err) => {
+                        error!("Dropping the credentials of a user: {}", err);
+                        None
+                    }
+                };
+            }
+        }
+
+        for stored_job in snapshot.jobs {
+            let StoredJob {
+                id,
+                user_id,
+                chat_id,
+                msg_id,
+                kind,
+                paused,
+                retry_count,
+            } = stored_job;
+            let lesson_id = kind.lesson_id().clone();
+            let user_state = self.users.entry(user_id).or_insert_with(UserState::new);
+            let bot_ctx = BotCtx::new(bot.clone(), self.config.clone(), chat_id, msg_id)
+                .with_settings(user_state.settings.subscribe());
+            user_state.next_job_id = user_state.next_job_id.max(id.0 + 1);
+            let job = if let Some(job_kind) = kind.into_job_kind(user_state.credentials.as_ref()) {
+                if paused {
+                    self.paused.push(PausedJob {
+                        id,
+                        kind: job_kind,
+                        user_id,
+                        bot: bot_ctx,
+                    });
+                    continue;
                 }
-                JobKind::NotifyWeekly(id) => {
-                    r.push_str("\nNotifyWeekly ");
-                    r.push_str(id.as_str());
+                let msg = format!("[{}] Resumed your job after a restart", lesson_id.as_str());
+                Job::builder(job_kind, user_id, bot_ctx)
+                    .id(id)
+                    .pre_msg(msg)
+                    .retry_count(retry_count)
+                    .build()
+            } else {
+                let msg = format!(
+                    "[{}] I was restarted and could not resume your enrollment job, \
+                    because your credentials are not stored. Please /login and start it again.",
+                    lesson_id.as_str()
+                );
+                Job::new(InternalJob::MsgUser(msg).into(), user_id, bot_ctx)
+            };
+            self.jobs.push(job);
+        }
+        Ok(())
+    }
+
+    /// Marks the users and jobs as changed, so the next [`State::flush`] writes them.
+    pub fn mark_dirty(&mut self) {
+        self.dirty = true;
+    }
+
+    /// Writes the current users and jobs to the storage on the blocking thread pool,
+    /// if they changed. Does nothing while the last write is still running,
+    /// the changes are written by a later flush.
+    pub async fn flush(&mut self) {
+        if let Some(saving) = &self.saving {
+            if !saving.is_finished() {
+                return;
+            }
+        }
+        if let Some(saving) = self.saving.take() {
+            match saving.await {
+                Ok(Ok(())) => (),
+                Ok(Err(err)) => {
+                    error!("Unable to save state: {}", err);
+                    self.dirty = true;
                 }
-                JobKind::Enroll(id, _, _) => {
-                    r.push_str("\nEnroll ");
-                    r.push_str(id.as_str());
+                Err(err) => {
+                    error!("Saving the state failed: {}", err);
+                    self.dirty = true;
                 }
-                JobKind::EnrollWeekly(id, _, _) => {
-                    r.push_str("\nEnrollWeekly ");
-                    r.push_str(id.as_str());
+            }
+        }
+        if !self.dirty {
+            return;
+        }
+        self.dirty = false;
+        let snapshot = self.snapshot();
+        let storage = self.storage.clone();
+        self.saving = Some(tokio::task::spawn_blocking(move || storage.save(&snapshot)));
+    }
+
+    /// Writes the current users and jobs to the storage right away,
+    /// after waiting for a running write.
+    pub async fn save(&mut self) {
+        if let Some(saving) = self.saving.take() {
+            // Its result doesn't matter, the snapshot is written again anyway
+            let _ = saving.await;
+        }
+        self.dirty = false;
+        if let Err(err) = self.storage.save(&self.snapshot()) {
+            error!("Unable to save state: {}", err);
+        }
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        let users = self
+            .users
+            .iter()
+            .map(|(user_id, user_state)| StoredUser {
+                user_id: *user_id,
+                settings: user_state.settings.borrow().clone(),
+                next_job_id: user_state.next_job_id,
+                last_active: Some(user_state.last_active),
+                encrypted_credentials: self.vault.as_ref().and_then(|vault| {
+                    let credentials = user_state.credentials.as_ref()?;
+                    vault
+                        .encrypt(*user_id, credentials)
+                        .map_err(|err| error!("Not storing the credentials of a user: {}", err))
+                        .ok()
+                }),
+            })
+            .collect();
+        let running = self
+            .jobs
+            .iter()
+            .filter(|job| job.is_active())
+            .filter_map(|job| {
+                Some(StoredJob {
+                    id: job.id?,
+                    user_id: job.user_id,
+                    chat_id: job.bot.chat_id(),
+                    msg_id: job.bot.msg_id(),
+                    kind: StoredJobKind::from_job_kind(&job.current_kind())?,
+                    paused: false,
+                    retry_count: job.retry_count,
+                })
+            });
+        let paused = self.paused.iter().filter_map(|paused| {
+            Some(StoredJob {
+                id: paused.id,
+                user_id: paused.user_id,
+                chat_id: paused.bot.chat_id(),
+                msg_id: paused.bot.msg_id(),
+                kind: StoredJobKind::from_job_kind(&paused.kind)?,
+                paused: true,
+                retry_count: 0,
+            })
+        });
+        let jobs = running.chain(paused).collect();
+        Snapshot { users, jobs }
+    }
+
+    /// Saves all jobs and tells their users that the bot is restarting.
+    /// The jobs are aborted afterwards and resumed from the snapshot on the next start.
+    /// Requests in progress, like checking the credentials of a `/login`, are dropped
+    /// and their users are asked to send them again.
+    #[instrument(skip(self))]
+    pub async fn shutdown(&mut self) {
+        self.save().await;
+        let mut affected: HashMap<ChatId, (BotCtx, Vec<JobId>, Vec<&str>)> = HashMap::new();
+        for job in self.jobs.iter().filter(|job| job.is_active()) {
+            job.abort();
+            let command = match &job.kind {
+                JobKind::Internal(internal) => internal.command(),
+                _ => None,
+            };
+            if job.id.is_none() && command.is_none() {
+                continue;
+            }
+            let (_, ids, commands) = affected
+                .entry(job.bot.chat_id())
+                .or_insert_with(|| (job.bot.clone(), Vec::new(), Vec::new()));
+            ids.extend(job.id);
+            commands.extend(command);
+        }
+        trace!("notifying {} chats", affected.len());
+
+        let persistent = self.storage.is_persistent();
+        let messages = affected.into_values().map(|(bot, mut ids, mut commands)| {
+            ids.sort_by_key(|id| id.0);
+            let ids: Vec<_> = ids.iter().map(JobId::to_string).collect();
+            commands.sort();
+            commands.dedup();
+            let mut lines = Vec::new();
+            if ids.is_empty() {
+                lines.push("I'm restarting.".to_string());
+            } else if persistent {
+                lines.push(format!(
+                    "I'm restarting. Your Jobs {} are saved and continue once I'm back.",
+                    ids.join(", ")
+                ));
+            } else {
+                lines.push(format!(
+                    "I'm restarting and can't save your Jobs {}. \
+                    Please start them again once I'm back.",
+                    ids.join(", ")
+                ));
+            }
+            if !commands.is_empty() {
+                lines.push(format!(
+                    "I couldn't finish your {}, please send it again once I'm back.",
+                    commands.join(", ")
+                ));
+            }
+            let msg = lines.join("\n");
+            async move {
+                if let Err(err) = bot.answer(msg).await {
+                    error!("Unable to notify about the restart: {}", err);
+                }
+            }
+        });
+        join_all(messages).await;
+    }
+
+    /// Marks the user as active, which keeps their credentials from being wiped.
+    fn touch(&mut self, user_id: UserId) {
+        self.users
+            .entry(user_id)
+            .or_insert_with(UserState::new)
+            .last_active = Utc::now();
+    }
+
+    /// Creates a builder for a new job and assigns it the next id of the user.
+    /// Internal jobs don't get an id, as they can't be cancelled.
+    fn job_builder(&mut self, kind: JobKind, user_id: UserId, bot: BotCtx) -> JobBuilder {
+        let user_state = self.users.entry(user_id).or_insert_with(UserState::new);
+        let bot = bot.with_settings(user_state.settings.subscribe());
+        if kind.is_internal() {
+            Job::builder(kind, user_id, bot)
+        } else {
+            Job::builder(kind, user_id, bot).id(user_state.new_job_id())
+        }
+    }
+
+    fn settings_menu(&self, user_id: UserId) -> (String, InlineKeyboardMarkup) {
+        let text = self
+            .users
+            .get(&user_id)
+            .map(|user_state| user_state.settings.borrow().describe())
+            .unwrap_or_else(|| Settings::new().describe());
+        let rows = SettingKind::ALL
+            .into_iter()
+            .map(|kind| vec![CallbackData::Setting(kind).button()]);
+        (text, InlineKeyboardMarkup::new(rows))
+    }
+
+    fn user_jobs(&self, user_id: UserId) -> impl Iterator<Item = &Job> {
+        self.jobs
+            .iter()
+            .filter(move |job| job.user_id == user_id && !job.kind.is_internal() && job.is_active())
+    }
+
+    fn find_job(&self, user_id: UserId, job_id: JobId) -> Option<&Job> {
+        self.user_jobs(user_id).find(|job| job.id == Some(job_id))
+    }
+
+    fn user_paused_jobs(&self, user_id: UserId) -> impl Iterator<Item = &PausedJob> {
+        self.paused
+            .iter()
+            .filter(move |paused| paused.user_id == user_id)
+    }
+
+    /// All jobs of a user sorted by their id. The bool is `true` if the job is paused.
+    fn job_entries(&self, user_id: UserId) -> Vec<(JobId, &JobKind, bool)> {
+        let mut entries: Vec<_> = self
+            .user_jobs(user_id)
+            .filter_map(|job| Some((job.id?, &job.kind, false)))
+            .chain(
+                self.user_paused_jobs(user_id)
+                    .map(|paused| (paused.id, &paused.kind, true)),
+            )
+            .collect();
+        entries.sort_by_key(|(id, _, _)| id.0);
+        entries
+    }
+
+    pub fn current_jobs(&self, user_id: UserId) -> String {
+        let mut r = String::from("Current Jobs:");
+        for (id, kind, paused) in self.job_entries(user_id) {
+            if let Some(lesson_id) = kind.lesson_id() {
+                r.push_str(&format!("\n{} {} {}", id, kind.name(), lesson_id.as_str()));
+                if paused {
+                    r.push_str(" (paused)");
                 }
-                JobKind::Internal(_) => (),
             }
         }
         r
     }
 
-    fn cancel_jobs(&self, user_id: UserId) -> usize {
+    /// One row of buttons per job to manage it without typing its id.
+    pub fn jobs_keyboard(&self, user_id: UserId) -> InlineKeyboardMarkup {
+        let rows = self
+            .job_entries(user_id)
+            .into_iter()
+            .map(|(id, _, paused)| {
+                let toggle = if paused {
+                    JobAction::Resume
+                } else {
+                    JobAction::Pause
+                };
+                [JobAction::Cancel, toggle, JobAction::Details]
+                    .into_iter()
+                    .map(|action| CallbackData::Job(action, id).button())
+                    .collect::<Vec<_>>()
+            });
+        InlineKeyboardMarkup::new(rows)
+    }
+
+    fn jobs_overview(&self, user_id: UserId) -> JobKind {
+        let text = self.current_jobs(user_id);
+        if self.job_entries(user_id).is_empty() {
+            InternalJob::MsgUser(text).into()
+        } else {
+            InternalJob::MsgUserKeyboard(text, self.jobs_keyboard(user_id)).into()
+        }
+    }
+
+    /// Cancels the job with the given id. If there is no such job,
+    /// all jobs of the lesson with this id are canceled instead.
+    fn cancel_target(&mut self, user_id: UserId, target: &CancelTarget) -> String {
+        if let Ok(job_id) = u32::try_from(target.as_u64()) {
+            if self.cancel_job(user_id, JobId(job_id)) {
+                return format!("Canceled Job {}.", JobId(job_id));
+            }
+        }
+
+        let lesson_id = target.as_u64().to_string();
+        let is_lesson =
+            |kind: &JobKind| kind.lesson_id().is_some_and(|id| id.as_str() == lesson_id);
         let mut count = 0;
-        for job in self
-            .jobs
+        for job in self.user_jobs(user_id).filter(|job| is_lesson(&job.kind)) {
+            job.abort();
+            count += 1;
+        }
+        let paused_count = self.paused.len();
+        self.paused
+            .retain(|paused| paused.user_id != user_id || !is_lesson(&paused.kind));
+        count += paused_count - self.paused.len();
+
+        if count == 0 {
+            format!(
+                "There is no Job or lesson with the id {}. See /jobs.",
+                lesson_id
+            )
+        } else {
+            format!("Canceled {} Jobs of lesson {}.", count, lesson_id)
+        }
+    }
+
+    /// Returns `false` if the user has no running or paused job with this id.
+    fn cancel_job(&mut self, user_id: UserId, job_id: JobId) -> bool {
+        if let Some(job) = self.find_job(user_id, job_id) {
+            job.abort();
+            true
+        } else if let Some(index) = self
+            .paused
             .iter()
-            .filter(|job| job.user_id == user_id && !job.kind.is_internal())
+            .position(|paused| paused.user_id == user_id && paused.id == job_id)
         {
-            job.handle.abort();
+            self.paused.remove(index);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn cancel_jobs(&mut self, user_id: UserId) -> usize {
+        let mut count = 0;
+        for job in self.user_jobs(user_id) {
+            job.abort();
             count += 1;
         }
-        count
+        let paused_count = self.paused.len();
+        self.paused.retain(|paused| paused.user_id != user_id);
+        count + paused_count - self.paused.len()
+    }
+
+    fn pause_job(&mut self, user_id: UserId, job_id: JobId) -> Option<String> {
+        let job = self.find_job(user_id, job_id)?;
+        job.abort();
+        let paused = PausedJob {
+            id: job_id,
+            kind: job.current_kind(),
+            user_id,
+            bot: job.bot.clone(),
+        };
+        self.paused.push(paused);
+        Some(format!("Paused Job {}.", job_id))
+    }
+
+    fn resume_job(&mut self, user_id: UserId, job_id: JobId) -> Option<String> {
+        let index = self
+            .paused
+            .iter()
+            .position(|paused| paused.user_id == user_id && paused.id == job_id)?;
+        let paused = self.paused.remove(index);
+        let job = Job::builder(paused.kind, user_id, paused.bot)
+            .id(job_id)
+            .build();
+        self.jobs.push(job);
+        Some(format!("Resumed Job {}.", job_id))
+    }
+
+    fn job_details(&self, user_id: UserId, job_id: JobId) -> Option<String> {
+        let (id, kind, paused) = self
+            .job_entries(user_id)
+            .into_iter()
+            .find(|(id, _, _)| *id == job_id)?;
+        let lesson_id = kind.lesson_id()?;
+        Some(format!(
+            "Job {}\n{}\nLesson: {}\nStatus: {}\n{}",
+            id,
+            kind.name(),
+            lesson_id.as_str(),
+            if paused { "paused" } else { "running" },
+            self.config.asvz.urls().lesson_page(lesson_id),
+        ))
     }
 
     pub fn handle_update(&mut self, bot: Bot, msg: Message) {
         let chat_id = msg.chat.id;
         let msg_id = msg.id;
-        let bot_ctx = BotCtx::new(bot, chat_id, msg_id);
+        let bot_ctx = BotCtx::new(bot, self.config.clone(), chat_id, msg_id);
         if let Some((msg, user_id)) = extract_id_text(&msg) {
-            let job = match Command::parse(msg, BOT_NAME) {
+            self.touch(user_id);
+            let job = match Command::parse(msg, &self.bot_name) {
                 Ok(cmd) => self.handle_cmd(cmd, user_id, bot_ctx),
                 Err(err) => {
                     if let Some(caps) = LESSON_URL_RE.captures(msg) {
@@ -115,31 +565,220 @@ impl State {
                 }
             };
             self.jobs.push(job);
+            self.mark_dirty();
+        }
+    }
+
+    pub fn handle_callback(&mut self, bot: Bot, query: CallbackQuery) {
+        let user_id = UserId(query.from.id.0);
+        self.touch(user_id);
+        let message = match &query.message {
+            Some(message) => message,
+            None => return,
+        };
+        let bot_ctx = BotCtx::new(bot, self.config.clone(), message.chat.id, message.id);
+        let kind = match query.data.as_deref().map(CallbackData::from_str) {
+            Some(Ok(data)) => self.handle_callback_data(data, query.id.clone(), user_id, &bot_ctx),
+            _ => InternalJob::AnswerCallback {
+                query_id: query.id.clone(),
+                text: Some("Unknown action".to_string()),
+                show_alert: false,
+                edit: None,
+            }
+            .into(),
+        };
+        self.jobs.push(Job::new(kind, user_id, bot_ctx));
+        self.mark_dirty();
+    }
+
+    #[instrument(skip(self, bot))]
+    fn handle_callback_data(
+        &mut self,
+        data: CallbackData,
+        query_id: String,
+        user_id: UserId,
+        bot: &BotCtx,
+    ) -> JobKind {
+        trace!("new callback");
+        let (text, show_alert, edit) = match data {
+            CallbackData::Job(action, job_id) => {
+                let text = match action {
+                    JobAction::Cancel => self
+                        .cancel_job(user_id, job_id)
+                        .then(|| format!("Canceled Job {}.", job_id)),
+                    JobAction::Pause => self.pause_job(user_id, job_id),
+                    JobAction::Resume => self.resume_job(user_id, job_id),
+                    JobAction::Details => self.job_details(user_id, job_id),
+                };
+                let text = text.unwrap_or_else(|| format!("Job {} no longer exists.", job_id));
+                let edit = Some((self.current_jobs(user_id), self.jobs_keyboard(user_id)));
+                (text, action == JobAction::Details, edit)
+            }
+            CallbackData::Setting(kind) => {
+                let user_state = self.users.entry(user_id).or_insert_with(UserState::new);
+                user_state
+                    .settings
+                    .send_modify(|settings| settings.cycle(kind));
+                (
+                    format!("Changed {}.", kind.label()),
+                    false,
+                    Some(self.settings_menu(user_id)),
+                )
+            }
+            CallbackData::Lesson(action, lesson_id) => {
+                let user_state = self.users.entry(user_id).or_insert_with(UserState::new);
+                let settings = user_state.settings.borrow().clone();
+                let kind = match (action, &user_state.credentials) {
+                    (LessonAction::Notify, _) => Some(settings.notify_job(lesson_id)),
+                    (LessonAction::Enroll, Some(cred)) => {
+                        Some(settings.enroll_job(lesson_id, cred.clone()))
+                    }
+                    (LessonAction::Enroll, None) => None,
+                };
+                match kind {
+                    Some(kind) => {
+                        let job = self.job_builder(kind, user_id, bot.clone()).build();
+                        let text = format!(
+                            "Started {} Job {}",
+                            job.kind.name(),
+                            job.id.expect("Non internal jobs have an id")
+                        );
+                        self.jobs.push(job);
+                        (text, false, None)
+                    }
+                    None => {
+                        let text = "You need to be logged in to directly enroll. See /help.";
+                        (text.to_string(), true, None)
+                    }
+                }
+            }
+        };
+        InternalJob::AnswerCallback {
+            query_id,
+            text: Some(text),
+            show_alert,
+            edit,
+        }
+        .into()
+    }
+
+    /// Removes the credentials of the user from memory, together with all jobs holding a copy.
+    /// Returns the number of canceled jobs, or `None` if the user had no credentials.
+    fn remove_credentials(&mut self, user_id: UserId) -> Option<usize> {
+        let credentials = self.users.get_mut(&user_id)?.credentials.take()?;
+        SESSIONS.remove(&credentials);
+
+        // Internal jobs are aborted as well, a pending login would store the credentials again
+        let mut count = 0;
+        for job in self.jobs.iter().filter(|job| {
+            job.user_id == user_id && job.is_active() && job.kind.credentials().is_some()
+        }) {
+            job.abort();
+            if !job.kind.is_internal() {
+                count += 1;
+            }
+        }
+        let paused_count = self.paused.len();
+        self.paused
+            .retain(|paused| paused.user_id != user_id || paused.kind.credentials().is_none());
+        count += paused_count - self.paused.len();
+        Some(count)
+    }
+
+    /// Deletes the credentials of users who have been inactive for longer than the timeout.
+    /// Running and paused enrollment jobs count as activity, as they still need the credentials.
+    #[instrument(skip(self))]
+    pub fn wipe_inactive_credentials(&mut self) {
+        let deadline = Utc::now() - self.config.credential_timeout();
+        let inactive: Vec<_> =
+            self.users
+                .iter()
+                .filter(|(_, user_state)| {
+                    user_state.credentials.is_some() && user_state.last_active < deadline
+                })
+                .map(|(user_id, _)| *user_id)
+                .filter(|user_id| {
+                    !self
+                        .user_jobs(*user_id)
+                        .any(|job| job.kind.credentials().is_some())
+                })
+                .filter(|user_id| {
+                    !self.paused.iter().any(|paused| {
+                        paused.user_id == *user_id && paused.kind.credentials().is_some()
+                    })
+                })
+                .collect();
+        for user_id in &inactive {
+            trace!("wiping the credentials of inactive user {:?}", user_id);
+            self.remove_credentials(*user_id);
+        }
+        if !inactive.is_empty() {
+            self.mark_dirty();
+        }
+    }
+
+    #[instrument(skip(self))]
+    pub fn handle_event(&mut self, event: StateEvent) {
+        trace!("new state event");
+        match event {
+            StateEvent::LoginVerified(user_id, credentials) => {
+                let user_state = self.users.entry(user_id).or_insert_with(UserState::new);
+                if let Some(old) = user_state.credentials.replace(credentials) {
+                    // The cached session belongs to the old credentials
+                    SESSIONS.remove(&old);
+                }
+            }
         }
     }
 
     #[instrument(skip(self))]
     pub fn handle_req_err(&mut self, err: RequestError) {
         error!("Got RequestError");
-        match err {
-            RequestError::RetryAfter(wait) => sleep(wait + Duration::from_secs(5)),
-            _ => (),
-        };
     }
 
+    /// Restarts the failed job after a wait that grows with every restart,
+    /// or gives up on it once it failed too often.
     #[instrument(skip(self))]
     pub fn handle_job_err(&mut self, err: JobError) {
         error!("Got JobError");
         let JobError {
             source,
+            job_id,
             user_id,
             job_kind,
             bot,
             retry_count,
         } = err;
-        self.handle_req_err(source);
-        let job = Job::builder(job_kind, user_id, bot)
+        let jobs = &self.config.jobs;
+        if retry_count >= jobs.max_restarts {
+            warn!("Giving up on the job after {} restarts", retry_count);
+            // Internal jobs are only dropped, telling the user could fail the same way
+            if !job_kind.is_internal() {
+                let job_name = match job_id {
+                    Some(id) => format!("Job {}", id),
+                    None => "Job".to_string(),
+                };
+                let msg = format!(
+                    "I gave up on your {} after {} unexpected errors. Please start it again.",
+                    job_name,
+                    retry_count + 1
+                );
+                self.jobs
+                    .push(Job::new(InternalJob::MsgUser(msg).into(), user_id, bot));
+            }
+            return;
+        }
+        let mut delay = jobs.restart_delay(retry_count);
+        if let RequestError::RetryAfter(wait) = source {
+            delay = delay.max(wait + self.config.telegram.retry_after_margin());
+        }
+        let mut builder = Job::builder(job_kind, user_id, bot);
+        if let Some(job_id) = job_id {
+            builder = builder.id(job_id);
+        }
+        let job = builder
             .pre_msg("An unexpected error occurred. Restarting your Job")
+            .delay(delay)
             .retry_count(retry_count + 1)
             .build();
         self.jobs.push(job)
@@ -151,12 +790,15 @@ impl State {
         let user_state = self.users.entry(user_id).or_insert_with(UserState::new);
         let job_kind = match cmd {
             Command::Start => InternalJob::MsgUser(START_MSG.to_string()).into(),
-            Command::Help => InternalJob::MsgUser(Command::descriptions()).into(),
-            Command::Notify { lesson_id } => JobKind::Notify(lesson_id),
+            Command::Help => InternalJob::MsgUser(cmd::help(&self.config)).into(),
+            Command::Notify { lesson_id } => user_state.settings.borrow().notify_job(lesson_id),
             Command::NotifyWeekly { lesson_id } => JobKind::NotifyWeekly(lesson_id),
             Command::Enroll { lesson_id } => {
                 if let Some(cred) = &user_state.credentials {
-                    JobKind::Enroll(lesson_id, cred.username.clone(), cred.password.clone())
+                    user_state
+                        .settings
+                        .borrow()
+                        .enroll_job(lesson_id, cred.clone())
                 } else {
                     let text = "You need to be logged in to directly enroll\
                     \nSee /help for more info.";
@@ -165,36 +807,68 @@ impl State {
             }
             Command::EnrollWeekly { lesson_id } => {
                 if let Some(cred) = &user_state.credentials {
-                    JobKind::EnrollWeekly(lesson_id, cred.username.clone(), cred.password.clone())
+                    JobKind::EnrollWeekly(lesson_id, cred.clone())
                 } else {
                     let text = "You need to be logged in to directly enroll\
                     \nSee /help for more info.";
                     InternalJob::MsgUser(text.to_string()).into()
                 }
             }
-            Command::Login { username, password } => {
-                let msg = if let Some(cred) = &mut user_state.credentials {
-                    cred.update(username, password);
-                    "Updated credentials"
+            Command::Unenroll { lesson_id } => {
+                if let Some(cred) = &user_state.credentials {
+                    InternalJob::Unenroll(lesson_id, cred.clone()).into()
                 } else {
-                    user_state.credentials = Some(LoginCredentials::new(username, password));
-                    "Stored credentials"
-                };
-                InternalJob::DeleteMsgUser(msg.to_string()).into()
+                    let text = "You need to be logged in to cancel an enrollment\
+                    \nSee /help for more info.";
+                    InternalJob::MsgUser(text.to_string()).into()
+                }
             }
-            Command::Logout => {
-                let msg = if user_state.credentials.is_some() {
-                    "Deleted your credentials"
+            Command::MyLessons => {
+                if let Some(cred) = &user_state.credentials {
+                    InternalJob::MyLessons(cred.clone()).into()
                 } else {
-                    "You have no credentials stored"
+                    let text = "You need to be logged in to see your lessons\
+                    \nSee /help for more info.";
+                    InternalJob::MsgUser(text.to_string()).into()
+                }
+            }
+            Command::Login {
+                username,
+                password,
+                method,
+            } => InternalJob::Login {
+                user_id,
+                credentials: LoginCredentials::new(username, password, method),
+                events: self.events.clone(),
+            }
+            .into(),
+            Command::Logout => {
+                let msg = match self.remove_credentials(user_id) {
+                    None => "You have no credentials stored".to_string(),
+                    Some(0) => "Deleted your credentials".to_string(),
+                    Some(count) => format!(
+                        "Deleted your credentials and canceled {} enrollment Jobs, \
+                        as they needed them.",
+                        count
+                    ),
                 };
-                user_state.credentials = None;
-                InternalJob::MsgUser(msg.to_string()).into()
+                InternalJob::MsgUser(msg).into()
             }
             Command::UrlAction { url_action } => {
+                user_state
+                    .settings
+                    .send_modify(|settings| settings.url_action = url_action);
                 InternalJob::MsgUser(format!("Changed your url_action to {:?}.", url_action)).into()
             }
-            Command::Jobs => InternalJob::MsgUser(self.current_jobs(user_id)).into(),
+            Command::Settings => {
+                let (text, keyboard) = self.settings_menu(user_id);
+                InternalJob::MsgUserKeyboard(text, keyboard).into()
+            }
+            Command::Search { sport, date } => InternalJob::Search(sport, date).into(),
+            Command::Jobs => self.jobs_overview(user_id),
+            Command::Cancel { target } => {
+                InternalJob::MsgUser(self.cancel_target(user_id, &target)).into()
+            }
             Command::CancelAll => {
                 let count = self.cancel_jobs(user_id);
                 let text = format!("Canceled {} Jobs.", count);
@@ -202,7 +876,7 @@ impl State {
             }
         };
 
-        Job::new(job_kind, user_id, bot)
+        self.job_builder(job_kind, user_id, bot).build()
     }
 
     #[instrument(skip(self, bot), fields(user_state = ?self.users.get(&user_id)))]
@@ -243,20 +917,21 @@ impl State {
         trace!("new lesson url");
         let user_state = self.users.entry(user_id).or_insert_with(UserState::new);
 
-        match (user_state.settings.url_action, &user_state.credentials) {
+        let settings = user_state.settings.borrow().clone();
+        match (settings.url_action, &user_state.credentials) {
             (UrlAction::Default | UrlAction::Enroll, Some(cred)) => {
-                let kind = JobKind::Enroll(lesson_id, cred.username.clone(), cred.password.clone());
+                let kind = settings.enroll_job(lesson_id, cred.clone());
                 let msg = "Found lesson url. Starting an enrollment job. \
                 If you wanted to get notified you can change \
-                the default behavior. See /help.";
-                Job::builder(kind, user_id, bot).pre_msg(msg).build()
+                the default behavior. See /settings.";
+                self.job_builder(kind, user_id, bot).pre_msg(msg).build()
             }
             (UrlAction::Default | UrlAction::Notify, None) | (UrlAction::Notify, Some(_)) => {
-                let kind = JobKind::Notify(lesson_id);
+                let kind = settings.notify_job(lesson_id);
                 let msg = "Found lesson url. Starting a notification job. \
                     If you wanted to enroll you can change \
-                    the default behavior. See /help.";
-                Job::builder(kind, user_id, bot).pre_msg(msg).build()
+                    the default behavior. See /settings.";
+                self.job_builder(kind, user_id, bot).pre_msg(msg).build()
             }
             (UrlAction::Enroll, None) => {
                 let msg =
@@ -279,3 +954,326 @@ fn extract_id_text(msg: &Message) -> Option<(&str, UserId)> {
         _ => None,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use asvz::login::LoginMethod;
+    use teloxide::types::MessageId;
+    use tokio::sync::mpsc;
+
+    use asvz_mock::MockTelegram;
+
+    use crate::cmd::{Password, Username};
+    use crate::storage::{JsonStorage, NoStorage};
+
+    use super::*;
+
+    const VAULT_KEY: &str = "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+
+    fn state(storage: impl Storage + 'static) -> State {
+        state_with_vault(storage, None)
+    }
+
+    fn state_with_vault(storage: impl Storage + 'static, vault: Option<CredentialVault>) -> State {
+        let (events, _) = mpsc::unbounded_channel();
+        State::new(
+            Arc::new(Config::default()),
+            "asvz_bot".to_string(),
+            Box::new(storage),
+            vault,
+            events,
+        )
+    }
+
+    fn bot() -> BotCtx {
+        BotCtx::new(
+            Bot::new("token"),
+            Arc::new(Config::default()),
+            ChatId(1),
+            MessageId(1),
+        )
+    }
+
+    fn credentials() -> LoginCredentials {
+        LoginCredentials::new(
+            Username::from_str("user").unwrap(),
+            Password::from_str("password").unwrap(),
+            LoginMethod::default(),
+        )
+    }
+
+    fn lesson_id() -> LessonID {
+        LessonID::from_str("236310").unwrap()
+    }
+
+    /// A running job that waits before doing anything, so it never talks to a server.
+    fn waiting_job(state: &mut State, user_id: UserId, id: u32, lesson: &str) {
+        let kind = JobKind::Notify(LessonID::from_str(lesson).unwrap());
+        let job = Job::builder(kind, user_id, bot())
+            .id(JobId(id))
+            .delay(Duration::from_secs(60 * 60))
+            .build();
+        state.jobs.push(job);
+    }
+
+    fn paused_job(state: &mut State, user_id: UserId, id: u32, lesson: &str) {
+        state.paused.push(PausedJob {
+            id: JobId(id),
+            kind: JobKind::Notify(LessonID::from_str(lesson).unwrap()),
+            user_id,
+            bot: bot(),
+        });
+    }
+
+    fn active_ids(state: &State) -> Vec<u32> {
+        let mut ids: Vec<_> = state
+            .jobs
+            .iter()
+            .filter(|job| job.is_active())
+            .filter_map(|job| Some(job.id?.0))
+            .chain(state.paused.iter().map(|paused| paused.id.0))
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    fn inactive_user() -> UserState {
+        let mut user_state = UserState::with_credentials(credentials());
+        user_state.last_active = Utc::now() - chrono::Duration::days(60);
+        user_state
+    }
+
+    /// A path in the temp dir that is unique to the test.
+    fn storage_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("asvz-bot-{}-{}.json", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[tokio::test]
+    async fn flush_only_writes_changes() {
+        let path = storage_path("flush");
+        let mut state = state(JsonStorage::new(&path));
+
+        state.flush().await;
+        assert!(state.saving.is_none());
+        assert!(!path.exists());
+
+        state.users.insert(UserId(1), UserState::new());
+        state.mark_dirty();
+        state.flush().await;
+        state.saving.take().unwrap().await.unwrap().unwrap();
+
+        let snapshot = JsonStorage::new(&path).load().unwrap();
+        assert_eq!(snapshot.users.len(), 1);
+        assert!(!state.dirty);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn shutdown_saves_changes_that_were_not_flushed() {
+        let path = storage_path("shutdown");
+        let mut state = state(JsonStorage::new(&path));
+
+        state.users.insert(UserId(1), UserState::new());
+        state.mark_dirty();
+        state.shutdown().await;
+
+        let snapshot = JsonStorage::new(&path).load().unwrap();
+        assert_eq!(snapshot.users.len(), 1);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn wipe_keeps_the_credentials_of_paused_enrollments() {
+        let mut state = state(NoStorage);
+        state.users.insert(UserId(1), inactive_user());
+        state.users.insert(UserId(2), inactive_user());
+        state.paused.push(PausedJob {
+            id: JobId(0),
+            kind: JobKind::Enroll(lesson_id(), credentials()),
+            user_id: UserId(1),
+            bot: bot(),
+        });
+        state.paused.push(PausedJob {
+            id: JobId(0),
+            kind: JobKind::Notify(lesson_id()),
+            user_id: UserId(2),
+            bot: bot(),
+        });
+
+        state.wipe_inactive_credentials();
+
+        assert!(state.users[&UserId(1)].credentials.is_some());
+        assert!(state.users[&UserId(2)].credentials.is_none());
+        assert_eq!(state.paused.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn credentials_are_restored_from_the_storage() {
+        let path = storage_path("restore");
+        let vault = || Some(CredentialVault::from_base64(VAULT_KEY).unwrap());
+        let mut state = state_with_vault(JsonStorage::new(&path), vault());
+        state
+            .users
+            .insert(UserId(1), UserState::with_credentials(credentials()));
+        state.paused.push(PausedJob {
+            id: JobId(3),
+            kind: JobKind::Enroll(lesson_id(), credentials()),
+            user_id: UserId(1),
+            bot: bot(),
+        });
+        state.save().await;
+        let stored = std::fs::read_to_string(&path).unwrap();
+        assert!(!stored.contains("password"));
+
+        let mut restored = state_with_vault(JsonStorage::new(&path), vault());
+        restored.restore(Bot::new("token")).unwrap();
+
+        let credentials = restored.users[&UserId(1)].credentials.as_ref().unwrap();
+        assert_eq!(credentials.username.as_str(), "user");
+        assert_eq!(credentials.password.as_str_dangerous(), "password");
+        let paused = &restored.paused[0];
+        assert_eq!(paused.id, JobId(3));
+        assert!(paused.kind.credentials().is_some());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn cancel_target_prefers_the_job_id() {
+        let mut state = state(NoStorage);
+        // Job 7 and the lesson with the id 7 both exist
+        waiting_job(&mut state, UserId(1), 7, "236310");
+        waiting_job(&mut state, UserId(1), 3, "7");
+
+        let msg = state.cancel_target(UserId(1), &CancelTarget::from_str("7").unwrap());
+
+        assert_eq!(msg, "Canceled Job #7.");
+        assert_eq!(active_ids(&state), [3]);
+    }
+
+    #[tokio::test]
+    async fn cancel_target_falls_back_to_the_lesson() {
+        let mut state = state(NoStorage);
+        waiting_job(&mut state, UserId(1), 0, "236310");
+        paused_job(&mut state, UserId(1), 1, "236310");
+        waiting_job(&mut state, UserId(1), 2, "236311");
+        // Jobs of other users are never touched
+        waiting_job(&mut state, UserId(2), 3, "236310");
+
+        let msg = state.cancel_target(UserId(1), &CancelTarget::from_str("236310").unwrap());
+
+        assert_eq!(msg, "Canceled 2 Jobs of lesson 236310.");
+        assert_eq!(active_ids(&state), [2, 3]);
+    }
+
+    #[tokio::test]
+    async fn cancel_target_finds_paused_jobs_and_other_users_ids_are_lessons() {
+        let mut state = state(NoStorage);
+        paused_job(&mut state, UserId(1), 4, "236310");
+        waiting_job(&mut state, UserId(2), 5, "236310");
+
+        let msg = state.cancel_target(UserId(1), &CancelTarget::from_str("#4").unwrap());
+        assert_eq!(msg, "Canceled Job #4.");
+
+        let msg = state.cancel_target(UserId(1), &CancelTarget::from_str("5").unwrap());
+        assert_eq!(msg, "There is no Job or lesson with the id 5. See /jobs.");
+        assert_eq!(active_ids(&state), [5]);
+    }
+
+    #[tokio::test]
+    async fn paused_job_keeps_its_id_across_a_restart() {
+        let path = storage_path("pause");
+        let mut state = state(JsonStorage::new(&path));
+        state.users.insert(UserId(1), UserState::new());
+        let job_id = state.users.get_mut(&UserId(1)).unwrap().new_job_id();
+        waiting_job(&mut state, UserId(1), job_id.0, "236310");
+
+        let msg = state.pause_job(UserId(1), job_id).unwrap();
+        assert_eq!(msg, format!("Paused Job {}.", job_id));
+        assert!(state.find_job(UserId(1), job_id).is_none());
+        assert_eq!(active_ids(&state), [job_id.0]);
+        state.save().await;
+
+        let mut restored = self::state(JsonStorage::new(&path));
+        restored.restore(Bot::new("token")).unwrap();
+        assert_eq!(restored.paused.len(), 1);
+        assert_eq!(restored.paused[0].id, job_id);
+        assert!(restored.users[&UserId(1)].next_job_id > job_id.0);
+
+        let msg = restored.resume_job(UserId(1), job_id).unwrap();
+        assert_eq!(msg, format!("Resumed Job {}.", job_id));
+        let job = restored.find_job(UserId(1), job_id).unwrap();
+        assert_eq!(job.kind.lesson_id(), Some(&lesson_id()));
+        // Aborted before it runs, so it never talks to a server
+        job.abort();
+        assert!(restored.paused.is_empty());
+        assert!(restored.resume_job(UserId(1), job_id).is_none());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn shutdown_asks_to_send_unfinished_requests_again() {
+        let telegram = MockTelegram::start().await;
+        let bot = |chat_id| {
+            let bot = Bot::new("token").set_api_url(telegram.url());
+            BotCtx::new(
+                bot,
+                Arc::new(Config::default()),
+                ChatId(chat_id),
+                MessageId(1),
+            )
+        };
+        let hour = Duration::from_secs(60 * 60);
+        let mut state = state(NoStorage);
+        let (events, _) = mpsc::unbounded_channel();
+        let login = InternalJob::Login {
+            user_id: UserId(1),
+            credentials: credentials(),
+            events,
+        };
+        let notify = JobKind::Notify(lesson_id());
+        let search = InternalJob::Search("Spinning".to_string(), None);
+        let message = InternalJob::MsgUser("Hello".to_string());
+        state.jobs.push(
+            Job::builder(notify, UserId(1), bot(1))
+                .id(JobId(0))
+                .delay(hour)
+                .build(),
+        );
+        state.jobs.push(
+            Job::builder(login.into(), UserId(1), bot(1))
+                .delay(hour)
+                .build(),
+        );
+        state.jobs.push(
+            Job::builder(search.into(), UserId(2), bot(2))
+                .delay(hour)
+                .build(),
+        );
+        state.jobs.push(
+            Job::builder(message.into(), UserId(3), bot(3))
+                .delay(hour)
+                .build(),
+        );
+
+        state.shutdown().await;
+
+        let mut messages = telegram.messages();
+        messages.sort();
+        assert_eq!(
+            messages,
+            [
+                "I'm restarting and can't save your Jobs #0. Please start them again once I'm back.\n\
+                I couldn't finish your /login, please send it again once I'm back.",
+                "I'm restarting.\n\
+                I couldn't finish your /search, please send it again once I'm back.",
+            ]
+        );
+        assert!(state.jobs.iter().all(|job| !job.is_active()));
+    }
+}
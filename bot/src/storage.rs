@@ -0,0 +1,160 @@
+use std::fmt::Debug;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use teloxide::types::{ChatId, MessageId};
+use thiserror::Error;
+use tracing::trace;
+
+use asvz::lesson::LessonID;
+
+use crate::job::{JobId, JobKind};
+use crate::user::{LoginCredentials, Settings, UserId};
+use crate::vault::EncryptedCredentials;
+
+#[derive(Error, Debug)]
+pub enum StorageError {
+    #[error("Io error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Json error: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+/// Everything the bot needs to pick up where it left off after a restart.
+#[derive(Default, Debug, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub users: Vec<StoredUser>,
+    pub jobs: Vec<StoredJob>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StoredUser {
+    pub user_id: UserId,
+    pub settings: Settings,
+    #[serde(default)]
+    pub next_job_id: u32,
+    #[serde(default)]
+    pub last_active: Option<DateTime<Utc>>,
+    /// Only present if the operator configured a vault key.
+    #[serde(default)]
+    pub encrypted_credentials: Option<EncryptedCredentials>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StoredJob {
+    pub id: JobId,
+    pub user_id: UserId,
+    pub chat_id: ChatId,
+    pub msg_id: MessageId,
+    pub kind: StoredJobKind,
+    #[serde(default)]
+    pub paused: bool,
+    /// How often the job was restarted after an error.
+    #[serde(default)]
+    pub retry_count: usize,
+}
+
+/// A [`JobKind`] without the credentials, these are taken from the user on restore.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum StoredJobKind {
+    Notify(LessonID),
+    NotifyWeekly(LessonID),
+    Enroll(LessonID),
+    EnrollWeekly(LessonID),
+}
+
+impl StoredJobKind {
+    pub fn from_job_kind(kind: &JobKind) -> Option<Self> {
+        match kind {
+            JobKind::Notify(id) => Some(Self::Notify(id.clone())),
+            JobKind::NotifyWeekly(id) => Some(Self::NotifyWeekly(id.clone())),
+            JobKind::Enroll(id, _) => Some(Self::Enroll(id.clone())),
+            JobKind::EnrollWeekly(id, _) => Some(Self::EnrollWeekly(id.clone())),
+            JobKind::Internal(_) => None,
+        }
+    }
+
+    /// Returns `None` if the job needs credentials, but none were given.
+    pub fn into_job_kind(self, credentials: Option<&LoginCredentials>) -> Option<JobKind> {
+        match (self, credentials) {
+            (Self::Notify(id), _) => Some(JobKind::Notify(id)),
+            (Self::NotifyWeekly(id), _) => Some(JobKind::NotifyWeekly(id)),
+            (Self::Enroll(id), Some(cred)) => Some(JobKind::Enroll(id, cred.clone())),
+            (Self::EnrollWeekly(id), Some(cred)) => Some(JobKind::EnrollWeekly(id, cred.clone())),
+            (Self::Enroll(_) | Self::EnrollWeekly(_), None) => None,
+        }
+    }
+
+    pub fn lesson_id(&self) -> &LessonID {
+        match self {
+            Self::Notify(id)
+            | Self::NotifyWeekly(id)
+            | Self::Enroll(id)
+            | Self::EnrollWeekly(id) => id,
+        }
+    }
+}
+
+pub trait Storage: Debug + Send + Sync {
+    fn load(&self) -> Result<Snapshot, StorageError>;
+    fn save(&self, snapshot: &Snapshot) -> Result<(), StorageError>;
+    /// Whether saved jobs survive a restart.
+    fn is_persistent(&self) -> bool;
+}
+
+/// Keeps nothing, every restart starts from scratch.
+#[derive(Debug)]
+pub struct NoStorage;
+
+impl Storage for NoStorage {
+    fn load(&self) -> Result<Snapshot, StorageError> {
+        Ok(Snapshot::default())
+    }
+
+    fn save(&self, _snapshot: &Snapshot) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    fn is_persistent(&self) -> bool {
+        false
+    }
+}
+
+/// Stores the snapshot as a single json file.
+#[derive(Debug)]
+pub struct JsonStorage {
+    path: PathBuf,
+}
+
+impl JsonStorage {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+impl Storage for JsonStorage {
+    fn load(&self) -> Result<Snapshot, StorageError> {
+        trace!("loading snapshot from {:?}", &self.path);
+        match fs::read(&self.path) {
+            Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Snapshot::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn save(&self, snapshot: &Snapshot) -> Result<(), StorageError> {
+        trace!("saving snapshot to {:?}", &self.path);
+        // Write to a temporary file first, so a crash never leaves a half written snapshot
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, serde_json::to_vec_pretty(snapshot)?)?;
+        fs::rename(tmp_path, &self.path)?;
+        Ok(())
+    }
+
+    fn is_persistent(&self) -> bool {
+        true
+    }
+}
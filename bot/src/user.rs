@@ -1,75 +1,374 @@
+use std::fmt;
+use std::fmt::Formatter;
 use std::str::FromStr;
+use std::sync::Arc;
+
+use chrono::{DateTime, Local, Timelike, Utc};
+use serde::{Deserialize, Serialize};
 use teloxide::prelude::*;
-use teloxide::types::MessageId;
-use teloxide::Bot;
+use teloxide::types::{InlineKeyboardMarkup, MessageId};
+use teloxide::{Bot, RequestError};
+use tokio::sync::watch;
+use tracing::warn;
+
+use asvz::api::lesson::Language;
+use asvz::lesson::LessonID;
+use asvz::login::LoginMethod;
 
 use crate::cmd::{Password, Username};
+use crate::config::Config;
+use crate::job::{JobId, JobKind};
+use crate::metrics;
 
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub struct BotCtx {
     bot: Bot,
+    config: Arc<Config>,
     chat_id: ChatId,
     msg_id: MessageId,
+    settings: Option<watch::Receiver<Settings>>,
 }
 
 impl BotCtx {
-    pub fn new(bot: Bot, chat_id: ChatId, msg_id: MessageId) -> Self {
+    pub fn new(bot: Bot, config: Arc<Config>, chat_id: ChatId, msg_id: MessageId) -> Self {
         Self {
             bot,
+            config,
             chat_id,
             msg_id,
+            settings: None,
+        }
+    }
+
+    /// Lets the jobs using this context follow the settings of the user.
+    pub fn with_settings(mut self, settings: watch::Receiver<Settings>) -> Self {
+        self.settings = Some(settings);
+        self
+    }
+
+    /// The current settings of the user, or the defaults if none are attached.
+    pub fn settings(&self) -> Settings {
+        self.settings
+            .as_ref()
+            .map(|settings| settings.borrow().clone())
+            .unwrap_or_default()
+    }
+
+    pub fn bot(&self) -> &Bot {
+        &self.bot
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn chat_id(&self) -> ChatId {
+        self.chat_id
+    }
+
+    pub fn msg_id(&self) -> MessageId {
+        self.msg_id
+    }
+
+    /// Sends the request, waiting as long as Telegram asks for if we sent too many.
+    /// Only this request waits, other jobs keep sending in the meantime.
+    async fn send<R>(&self, request: R) -> ResponseResult<()>
+    where
+        R: Request<Err = RequestError>,
+    {
+        let telegram = &self.config.telegram;
+        let mut retries = 0;
+        loop {
+            match request.send_ref().await {
+                Err(err @ RequestError::RetryAfter(wait))
+                    if retries < telegram.max_send_retries =>
+                {
+                    metrics::record_telegram_error(&err);
+                    warn!("Telegram asked to wait {:?} before sending again", wait);
+                    retries += 1;
+                    tokio::time::sleep(wait + telegram.retry_after_margin()).await;
+                }
+                Err(err) => {
+                    metrics::record_telegram_error(&err);
+                    return Err(err);
+                }
+                Ok(_) => return Ok(()),
+            }
         }
     }
 
     pub async fn answer(&self, text: String) -> ResponseResult<()> {
-        self.bot.send_message(self.chat_id, text).await?;
-        Ok(())
+        self.send(
+            self.bot
+                .send_message(self.chat_id, text)
+                .disable_notification(self.settings().is_quiet()),
+        )
+        .await
+    }
+
+    pub async fn answer_with_keyboard(
+        &self,
+        text: String,
+        keyboard: InlineKeyboardMarkup,
+    ) -> ResponseResult<()> {
+        self.send(
+            self.bot
+                .send_message(self.chat_id, text)
+                .disable_notification(self.settings().is_quiet())
+                .reply_markup(keyboard),
+        )
+        .await
+    }
+
+    /// Replaces the text and keyboard of the message this context belongs to.
+    pub async fn edit_message(
+        &self,
+        text: String,
+        keyboard: InlineKeyboardMarkup,
+    ) -> ResponseResult<()> {
+        self.send(
+            self.bot
+                .edit_message_text(self.chat_id, self.msg_id, text)
+                .reply_markup(keyboard),
+        )
+        .await
+    }
+
+    pub async fn answer_callback_query(
+        &self,
+        query_id: String,
+        text: Option<String>,
+        show_alert: bool,
+    ) -> ResponseResult<()> {
+        let mut request = self.bot.answer_callback_query(query_id);
+        if let Some(text) = text {
+            request = request.text(text).show_alert(show_alert);
+        }
+        self.send(request).await
     }
 
     pub async fn delete_message(&self) -> ResponseResult<()> {
-        self.bot.delete_message(self.chat_id, self.msg_id).await?;
-        Ok(())
+        self.send(self.bot.delete_message(self.chat_id, self.msg_id))
+            .await
     }
 }
-#[derive(Copy, Clone, Hash, PartialEq, Eq, Debug)]
+#[derive(Copy, Clone, Hash, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub struct UserId(pub u64);
 
 #[derive(Debug)]
 pub struct UserState {
     pub credentials: Option<LoginCredentials>,
-    pub settings: Settings,
+    /// Running jobs of the user read the settings through receivers of this channel.
+    pub settings: watch::Sender<Settings>,
+    pub next_job_id: u32,
+    /// When the user last sent a command or pressed a button.
+    pub last_active: DateTime<Utc>,
 }
 
 impl UserState {
     pub fn new() -> Self {
         Self {
             credentials: None,
-            settings: Settings::new(),
+            settings: watch::channel(Settings::new()).0,
+            next_job_id: 1,
+            last_active: Utc::now(),
         }
     }
 
     pub fn with_credentials(credentials: LoginCredentials) -> Self {
         Self {
             credentials: Some(credentials),
-            settings: Settings::new(),
+            ..Self::new()
         }
     }
+
+    pub fn new_job_id(&mut self) -> JobId {
+        let id = JobId(self.next_job_id);
+        self.next_job_id += 1;
+        id
+    }
 }
 
-#[derive(Debug)]
+/// The settings of a user, see `/settings`.
+/// Missing fields are filled with the defaults, so older stored settings can still be read.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Settings {
     pub url_action: UrlAction,
+    /// How many minutes before the enrollment opens a notification job reminds the user.
+    pub lead_time_minutes: u32,
+    /// Lesson urls, `/notify`, `/enroll` and the search buttons start weekly jobs.
+    pub weekly_by_default: bool,
+    /// Only lessons held in this language are shown by `/search`.
+    /// It is the only place where the bot picks lessons, `/notify`, `/enroll` and lesson urls
+    /// name one lesson the user already chose. The replies of the bot are always in english,
+    /// there are no translations to switch to.
+    pub lesson_language: LessonLanguage,
+    /// Messages of jobs are sent silently during these hours.
+    pub quiet_hours: Option<QuietHours>,
 }
 
 impl Settings {
     pub fn new() -> Self {
         Self {
             url_action: UrlAction::Default,
+            lead_time_minutes: 1,
+            weekly_by_default: false,
+            lesson_language: LessonLanguage::Any,
+            quiet_hours: None,
+        }
+    }
+
+    /// Changes the setting to its next value, this is what the buttons of `/settings` do.
+    pub fn cycle(&mut self, kind: SettingKind) {
+        match kind {
+            SettingKind::UrlAction => {
+                self.url_action = match self.url_action {
+                    UrlAction::Default => UrlAction::Notify,
+                    UrlAction::Notify => UrlAction::Enroll,
+                    UrlAction::Enroll => UrlAction::Default,
+                }
+            }
+            SettingKind::LeadTime => {
+                self.lead_time_minutes = next_in(&LEAD_TIMES, &self.lead_time_minutes)
+            }
+            SettingKind::Weekly => self.weekly_by_default = !self.weekly_by_default,
+            SettingKind::LessonLanguage => {
+                self.lesson_language = match self.lesson_language {
+                    LessonLanguage::Any => LessonLanguage::German,
+                    LessonLanguage::German => LessonLanguage::English,
+                    LessonLanguage::English => LessonLanguage::Any,
+                }
+            }
+            SettingKind::QuietHours => {
+                self.quiet_hours = next_in(&QUIET_HOURS, &self.quiet_hours);
+            }
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        let url_action = match self.url_action {
+            UrlAction::Default => "enroll if you are logged in, otherwise notify",
+            UrlAction::Notify => "always notify",
+            UrlAction::Enroll => "always enroll",
+        };
+        let quiet_hours = match &self.quiet_hours {
+            Some(quiet_hours) => format!(
+                "{:02}:00 - {:02}:00, messages are sent silently",
+                quiet_hours.start, quiet_hours.end
+            ),
+            None => "off".to_string(),
+        };
+        format!(
+            "Your settings:\n\
+            Lesson urls: {}\n\
+            Reminder: {} min before the enrollment opens\n\
+            Weekly Jobs by default: {}\n\
+            Lesson language: {}\n\
+            Quiet hours: {}",
+            url_action,
+            self.lead_time_minutes,
+            if self.weekly_by_default { "on" } else { "off" },
+            self.lesson_language,
+            quiet_hours,
+        )
+    }
+
+    /// Whether messages should be sent without a sound right now.
+    pub fn is_quiet(&self) -> bool {
+        self.quiet_hours
+            .is_some_and(|quiet_hours| quiet_hours.contains(Local::now().hour()))
+    }
+
+    pub fn notify_job(&self, lesson_id: LessonID) -> JobKind {
+        if self.weekly_by_default {
+            JobKind::NotifyWeekly(lesson_id)
+        } else {
+            JobKind::Notify(lesson_id)
+        }
+    }
+
+    pub fn enroll_job(&self, lesson_id: LessonID, credentials: LoginCredentials) -> JobKind {
+        if self.weekly_by_default {
+            JobKind::EnrollWeekly(lesson_id, credentials)
+        } else {
+            JobKind::Enroll(lesson_id, credentials)
         }
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+impl Default for Settings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+const LEAD_TIMES: [u32; 5] = [1, 5, 15, 30, 60];
+const QUIET_HOURS: [Option<QuietHours>; 4] = [
+    None,
+    Some(QuietHours { start: 22, end: 7 }),
+    Some(QuietHours { start: 23, end: 8 }),
+    Some(QuietHours { start: 0, end: 9 }),
+];
+
+/// The value after `current`, or the first one if `current` isn't one of the values.
+fn next_in<T: Copy + PartialEq>(values: &[T], current: &T) -> T {
+    let index = values.iter().position(|value| value == current);
+    values[index.map_or(0, |index| (index + 1) % values.len())]
+}
+
+/// Which setting a button of `/settings` changes.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SettingKind {
+    UrlAction,
+    LeadTime,
+    Weekly,
+    LessonLanguage,
+    QuietHours,
+}
+
+impl SettingKind {
+    pub const ALL: [SettingKind; 5] = [
+        Self::UrlAction,
+        Self::LeadTime,
+        Self::Weekly,
+        Self::LessonLanguage,
+        Self::QuietHours,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::UrlAction => "url_action",
+            Self::LeadTime => "lead_time",
+            Self::Weekly => "weekly",
+            Self::LessonLanguage => "lesson_language",
+            Self::QuietHours => "quiet_hours",
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::UrlAction => "Lesson urls",
+            Self::LeadTime => "Reminder",
+            Self::Weekly => "Weekly Jobs",
+            Self::LessonLanguage => "Lesson language",
+            Self::QuietHours => "Quiet hours",
+        }
+    }
+}
+
+impl FromStr for SettingKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|kind| kind.as_str() == s)
+            .ok_or_else(|| format!("Unknown setting: {}", s))
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum UrlAction {
     Default,
     Notify,
@@ -89,18 +388,112 @@ impl FromStr for UrlAction {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LessonLanguage {
+    Any,
+    German,
+    English,
+}
+
+impl LessonLanguage {
+    pub fn matches(&self, language: &Language) -> bool {
+        let code = language.code.to_lowercase();
+        match self {
+            Self::Any => true,
+            Self::German => code.starts_with("de"),
+            Self::English => code.starts_with("en"),
+        }
+    }
+}
+
+impl fmt::Display for LessonLanguage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Any => write!(f, "any"),
+            Self::German => write!(f, "German"),
+            Self::English => write!(f, "English"),
+        }
+    }
+}
+
+/// Hours in local time, `start` is inclusive and `end` exclusive. They may wrap around midnight.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuietHours {
+    pub start: u32,
+    pub end: u32,
+}
+
+impl QuietHours {
+    pub fn contains(&self, hour: u32) -> bool {
+        if self.start <= self.end {
+            self.start <= hour && hour < self.end
+        } else {
+            self.start <= hour || hour < self.end
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LoginCredentials {
     pub username: Username,
     pub password: Password,
+    #[serde(default)]
+    pub method: LoginMethod,
 }
 
 impl LoginCredentials {
-    pub fn new(username: Username, password: Password) -> Self {
-        Self { username, password }
+    pub fn new(username: Username, password: Password, method: LoginMethod) -> Self {
+        Self {
+            username,
+            password,
+            method,
+        }
     }
-    pub fn update(&mut self, username: Username, password: Password) {
-        self.username = username;
-        self.password = password;
+}
+
+#[cfg(test)]
+mod tests {
+    use asvz_mock::MockTelegram;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn rate_limited_sends_are_retried_and_counted() {
+        let telegram = MockTelegram::start().await;
+        let mut config = Config::default();
+        config.telegram.retry_after_margin_secs = 0;
+        config.telegram.max_send_retries = 2;
+        let bot = Bot::new("token").set_api_url(telegram.url());
+        let bot = BotCtx::new(bot, Arc::new(config), ChatId(1), MessageId(1));
+        let before = metrics::telegram_errors("retry_after");
+
+        telegram.rate_limit(2, 0);
+        bot.answer("sent".to_string()).await.unwrap();
+        assert_eq!(metrics::telegram_errors("retry_after"), before + 2);
+
+        telegram.rate_limit(3, 0);
+        assert!(bot.answer("dropped".to_string()).await.is_err());
+        assert_eq!(metrics::telegram_errors("retry_after"), before + 5);
+        assert_eq!(telegram.messages(), ["sent"]);
+    }
+
+    #[test]
+    fn missing_settings_are_the_defaults() {
+        let settings: Settings = serde_json::from_str(r#"{"weekly_by_default": true}"#).unwrap();
+
+        assert!(settings.weekly_by_default);
+        assert_eq!(settings.lesson_language, Settings::new().lesson_language);
+        assert_eq!(
+            settings.lead_time_minutes,
+            Settings::new().lead_time_minutes
+        );
+    }
+
+    #[test]
+    fn setting_kinds_parse_their_names() {
+        for kind in SettingKind::ALL {
+            assert_eq!(SettingKind::from_str(kind.as_str()), Ok(kind));
+        }
+        assert!(SettingKind::from_str("language").is_err());
     }
 }
@@ -0,0 +1,194 @@
+use std::fs;
+use std::path::PathBuf;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+use crate::user::{LoginCredentials, UserId};
+
+#[derive(Error, Debug)]
+pub enum VaultError {
+    #[error("Unable to read the key file: {0}")]
+    KeyFile(#[from] std::io::Error),
+    #[error("The key has to be 32 bytes encoded as base64")]
+    InvalidKey,
+    #[error("Unable to decrypt the credentials, was the key changed?")]
+    Decrypt,
+    #[error("Unable to encrypt the credentials")]
+    Encrypt,
+}
+
+/// Credentials as they are written to the storage.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncryptedCredentials {
+    nonce: String,
+    ciphertext: String,
+}
+
+/// Encrypts credentials with a key of the operator, so they never hit the disk in the clear.
+///
+/// The key is read from `ASVZ_VAULT_KEY` or from the file at `ASVZ_VAULT_KEY_FILE`,
+/// as base64 of 32 random bytes, e.g. from `head -c 32 /dev/urandom | base64`.
+pub struct CredentialVault {
+    cipher: ChaCha20Poly1305,
+}
+
+impl std::fmt::Debug for CredentialVault {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CredentialVault").finish_non_exhaustive()
+    }
+}
+
+impl CredentialVault {
+    /// Returns `None` if no key is configured.
+    pub fn from_env() -> Result<Option<Self>, VaultError> {
+        let key = match (
+            std::env::var("ASVZ_VAULT_KEY").ok(),
+            std::env::var_os("ASVZ_VAULT_KEY_FILE"),
+        ) {
+            (Some(key), _) => Zeroizing::new(key),
+            (None, Some(path)) => Zeroizing::new(fs::read_to_string(PathBuf::from(path))?),
+            (None, None) => return Ok(None),
+        };
+        Self::from_base64(&key).map(Some)
+    }
+
+    pub fn from_base64(key: &str) -> Result<Self, VaultError> {
+        let key = Zeroizing::new(
+            STANDARD
+                .decode(key.trim())
+                .map_err(|_| VaultError::InvalidKey)?,
+        );
+        if key.len() != 32 {
+            return Err(VaultError::InvalidKey);
+        }
+        Ok(Self {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&key)),
+        })
+    }
+
+    /// The credentials are bound to the user, so they can't be swapped between users.
+    pub fn encrypt(
+        &self,
+        user_id: UserId,
+        credentials: &LoginCredentials,
+    ) -> Result<EncryptedCredentials, VaultError> {
+        let plaintext =
+            Zeroizing::new(serde_json::to_vec(credentials).map_err(|_| VaultError::Encrypt)?);
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: &plaintext,
+            aad: &user_id.0.to_le_bytes(),
+        };
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, payload)
+            .map_err(|_| VaultError::Encrypt)?;
+        Ok(EncryptedCredentials {
+            nonce: STANDARD.encode(nonce),
+            ciphertext: STANDARD.encode(ciphertext),
+        })
+    }
+
+    pub fn decrypt(
+        &self,
+        user_id: UserId,
+        encrypted: &EncryptedCredentials,
+    ) -> Result<LoginCredentials, VaultError> {
+        let nonce = STANDARD
+            .decode(&encrypted.nonce)
+            .map_err(|_| VaultError::Decrypt)?;
+        if nonce.len() != 12 {
+            return Err(VaultError::Decrypt);
+        }
+        let ciphertext = STANDARD
+            .decode(&encrypted.ciphertext)
+            .map_err(|_| VaultError::Decrypt)?;
+        let payload = Payload {
+            msg: &ciphertext,
+            aad: &user_id.0.to_le_bytes(),
+        };
+        let plaintext = Zeroizing::new(
+            self.cipher
+                .decrypt(Nonce::from_slice(&nonce), payload)
+                .map_err(|_| VaultError::Decrypt)?,
+        );
+        serde_json::from_slice(&plaintext).map_err(|_| VaultError::Decrypt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use asvz::login::LoginMethod;
+
+    use crate::cmd::{Password, Username};
+
+    use super::*;
+
+    const KEY: &str = "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+
+    fn credentials() -> LoginCredentials {
+        LoginCredentials::new(
+            Username::from_str("user").unwrap(),
+            Password::from_str("password").unwrap(),
+            LoginMethod::default(),
+        )
+    }
+
+    #[test]
+    fn round_trip() {
+        let vault = CredentialVault::from_base64(KEY).unwrap();
+
+        let encrypted = vault.encrypt(UserId(1), &credentials()).unwrap();
+        let decrypted = vault.decrypt(UserId(1), &encrypted).unwrap();
+
+        assert!(!encrypted.ciphertext.contains("password"));
+        assert_eq!(decrypted.username.as_str(), "user");
+        assert_eq!(decrypted.password.as_str_dangerous(), "password");
+        assert_eq!(decrypted.method, LoginMethod::default());
+    }
+
+    #[test]
+    fn credentials_of_another_user_are_rejected() {
+        let vault = CredentialVault::from_base64(KEY).unwrap();
+
+        let encrypted = vault.encrypt(UserId(1), &credentials()).unwrap();
+
+        assert!(matches!(
+            vault.decrypt(UserId(2), &encrypted),
+            Err(VaultError::Decrypt)
+        ));
+    }
+
+    #[test]
+    fn credentials_of_another_key_are_rejected() {
+        let vault = CredentialVault::from_base64(KEY).unwrap();
+        let other = CredentialVault::from_base64(&STANDARD.encode([7; 32])).unwrap();
+
+        let encrypted = vault.encrypt(UserId(1), &credentials()).unwrap();
+
+        assert!(matches!(
+            other.decrypt(UserId(1), &encrypted),
+            Err(VaultError::Decrypt)
+        ));
+    }
+
+    #[test]
+    fn key_must_be_32_bytes() {
+        assert!(matches!(
+            CredentialVault::from_base64(&STANDARD.encode([7; 16])),
+            Err(VaultError::InvalidKey)
+        ));
+        assert!(matches!(
+            CredentialVault::from_base64("not base64"),
+            Err(VaultError::InvalidKey)
+        ));
+    }
+}
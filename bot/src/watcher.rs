@@ -0,0 +1,250 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use chrono::{DateTime, FixedOffset, Local};
+use lazy_static::lazy_static;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tracing::{instrument, trace, warn};
+
+use asvz::api::lesson::LessonData;
+use asvz::client::AsvzClient;
+use asvz::lesson::LessonID;
+
+use crate::config::Config;
+use crate::job_fns::utils::build_client;
+
+lazy_static! {
+    pub static ref LESSON_WATCHER: LessonWatcher = LessonWatcher::new();
+}
+
+/// Polling faster would only get the bot rate limited.
+const MIN_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Polls every watched lesson once, no matter how many jobs are waiting for it.
+///
+/// A poller runs as long as there is a [`Subscription`] to its lesson.
+/// Dropping the last one, e.g. because the job was canceled, stops it.
+/// Failed polls are only logged, the jobs keep waiting for the next successful one.
+#[derive(Debug)]
+pub struct LessonWatcher {
+    pollers: Mutex<HashMap<LessonID, Weak<Poller>>>,
+}
+
+impl LessonWatcher {
+    pub fn new() -> Self {
+        Self {
+            pollers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The config is only used if the lesson isn't polled yet.
+    #[instrument(skip(self, config))]
+    pub fn subscribe(&self, id: &LessonID, config: &Config) -> Subscription {
+        let mut pollers = self.pollers.lock().unwrap();
+        pollers.retain(|_, poller| poller.strong_count() > 0);
+        let poller = match pollers.get(id).and_then(Weak::upgrade) {
+            Some(poller) => poller,
+            None => {
+                trace!("starting new poller");
+                let poller = Arc::new(Poller::start(
+                    build_client(config),
+                    id.clone(),
+                    config.jobs.poll_interval().max(MIN_POLL_INTERVAL),
+                ));
+                pollers.insert(id.clone(), Arc::downgrade(&poller));
+                poller
+            }
+        };
+        Subscription {
+            receiver: poller.receiver.clone(),
+            _poller: poller,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Poller {
+    /// Never marked as seen, so clones of it see the latest poll as new.
+    receiver: watch::Receiver<Option<Arc<LessonData>>>,
+    handle: JoinHandle<()>,
+}
+
+impl Poller {
+    fn start(client: AsvzClient, id: LessonID, interval: Duration) -> Self {
+        let (sender, receiver) = watch::channel(None);
+        let handle = tokio::spawn(poll(client, id, sender, interval));
+        Self { receiver, handle }
+    }
+}
+
+impl Drop for Poller {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+#[instrument(skip(client, sender))]
+async fn poll(
+    client: AsvzClient,
+    id: LessonID,
+    sender: watch::Sender<Option<Arc<LessonData>>>,
+    interval: Duration,
+) {
+    loop {
+        match client.lesson_data(&id).await {
+            Ok(data) => {
+                sender.send_if_modified(|current| {
+                    let changed = current.as_deref().map(availability) != Some(availability(&data));
+                    if changed {
+                        trace!("availability changed");
+                        *current = Some(Arc::new(data));
+                    }
+                    changed
+                });
+            }
+            Err(err) => warn!("unable to poll the lesson: {}", err),
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// The parts of a poll the jobs react to.
+fn availability(lesson: &LessonData) -> (i64, i64, bool) {
+    (
+        lesson.data.participants_max,
+        lesson.data.participant_count,
+        lesson.data.is_cancelled(),
+    )
+}
+
+/// A job's interest in a lesson.
+#[derive(Debug)]
+pub struct Subscription {
+    receiver: watch::Receiver<Option<Arc<LessonData>>>,
+    _poller: Arc<Poller>,
+}
+
+impl Subscription {
+    /// Waits until the availability of the lesson changes.
+    /// The first call returns right away if the lesson was already polled.
+    /// Returns `None` if the lesson is no longer polled.
+    pub async fn next(&mut self) -> Option<Arc<LessonData>> {
+        loop {
+            self.receiver.changed().await.ok()?;
+            if let Some(data) = &*self.receiver.borrow_and_update() {
+                return Some(data.clone());
+            }
+        }
+    }
+
+    /// Like [`Subscription::next`], but also returns `None` once `deadline` passed.
+    pub async fn next_until(&mut self, deadline: DateTime<FixedOffset>) -> Option<Arc<LessonData>> {
+        let timeout = (deadline - Local::now().fixed_offset()).to_std().ok()?;
+        tokio::time::timeout(timeout, self.next()).await.ok()?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use asvz_mock::{MockLesson, MockServer};
+
+    use super::*;
+
+    fn config(server: &MockServer) -> Config {
+        let mut config = Config::default();
+        config.asvz.www = server.url();
+        config.asvz.schalter = server.url();
+        config.asvz.auth = server.url();
+        config.jobs.poll_interval_secs = 1;
+        config
+    }
+
+    fn lesson_id(id: u64) -> LessonID {
+        LessonID::from_str(&id.to_string()).unwrap()
+    }
+
+    fn is_polled(watcher: &LessonWatcher, id: u64) -> bool {
+        let pollers = watcher.pollers.lock().unwrap();
+        pollers
+            .get(&lesson_id(id))
+            .is_some_and(|poller| poller.strong_count() > 0)
+    }
+
+    #[tokio::test]
+    async fn subscriptions_share_one_poller() {
+        let server = MockServer::start().await;
+        server.add_lesson(MockLesson::new(1).participants([5, 5, 6]));
+        let watcher = LessonWatcher::new();
+        let config = config(&server);
+
+        let mut first = watcher.subscribe(&lesson_id(1), &config);
+        let mut second = watcher.subscribe(&lesson_id(1), &config);
+
+        assert_eq!(first.next().await.unwrap().data.participant_count, 5);
+        assert_eq!(second.next().await.unwrap().data.participant_count, 5);
+        assert_eq!(server.lesson_polls(1), 1);
+        // Both see the change of the third poll
+        assert_eq!(first.next().await.unwrap().data.participant_count, 6);
+        assert_eq!(second.next().await.unwrap().data.participant_count, 6);
+        assert_eq!(server.lesson_polls(1), 3);
+    }
+
+    #[tokio::test]
+    async fn dropping_the_last_subscription_stops_polling() {
+        let server = MockServer::start().await;
+        server.add_lesson(MockLesson::new(2));
+        let watcher = LessonWatcher::new();
+        let config = config(&server);
+
+        let mut first = watcher.subscribe(&lesson_id(2), &config);
+        let second = watcher.subscribe(&lesson_id(2), &config);
+        first.next().await.unwrap();
+        drop(first);
+        assert!(is_polled(&watcher, 2));
+        drop(second);
+        assert!(!is_polled(&watcher, 2));
+
+        let polls = server.lesson_polls(2);
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert_eq!(server.lesson_polls(2), polls);
+
+        // A new subscription starts polling again
+        let mut third = watcher.subscribe(&lesson_id(2), &config);
+        third.next().await.unwrap();
+        assert_eq!(server.lesson_polls(2), polls + 1);
+    }
+
+    #[tokio::test]
+    async fn failed_polls_are_not_passed_on() {
+        let server = MockServer::start().await;
+        let watcher = LessonWatcher::new();
+        let config = config(&server);
+
+        // The mock doesn't know the lesson yet, so polling it fails
+        let mut subscription = watcher.subscribe(&lesson_id(3), &config);
+        let deadline = Local::now().fixed_offset() + chrono::Duration::milliseconds(1500);
+        assert!(subscription.next_until(deadline).await.is_none());
+
+        server.add_lesson(MockLesson::new(3));
+        assert_eq!(subscription.next().await.unwrap().data.id, 3);
+    }
+
+    #[tokio::test]
+    async fn poll_interval_is_at_least_a_second() {
+        let server = MockServer::start().await;
+        server.add_lesson(MockLesson::new(4));
+        let watcher = LessonWatcher::new();
+        let mut config = config(&server);
+        config.jobs.poll_interval_secs = 0;
+
+        let mut subscription = watcher.subscribe(&lesson_id(4), &config);
+        subscription.next().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        assert_eq!(server.lesson_polls(4), 1);
+    }
+}
@@ -0,0 +1,333 @@
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::Local;
+use teloxide::types::{ChatId, MessageId};
+use teloxide::Bot;
+use tokio::sync::watch;
+
+use asvz::api::lesson::RegistrationType;
+use asvz::lesson::LessonID;
+use asvz::login::LoginMethod;
+use asvz_bot::cmd::{Password, Username};
+use asvz_bot::config::Config;
+use asvz_bot::job_fns::{self, ExistStatus};
+use asvz_bot::job_update_cx::JobUpdateCx;
+use asvz_bot::session::SESSIONS;
+use asvz_bot::user::{BotCtx, LessonLanguage, LoginCredentials, Settings};
+use asvz_mock::{MockLesson, MockServer, MockTelegram, StatusCode};
+
+// The lesson watcher and the sessions are shared by all tests,
+// so every test uses its own lesson ids and usernames.
+
+fn config(server: &MockServer) -> Arc<Config> {
+    let mut config = Config::default();
+    config.asvz.www = server.url();
+    config.asvz.schalter = server.url();
+    config.asvz.auth = server.url();
+    config.jobs.poll_interval_secs = 1;
+    Arc::new(config)
+}
+
+fn job_cx(telegram: &MockTelegram, config: Arc<Config>, lesson: u64) -> JobUpdateCx {
+    let bot = Bot::new("token").set_api_url(telegram.url());
+    let bot = BotCtx::new(bot, config, ChatId(1), MessageId(1));
+    JobUpdateCx::new(bot, watch::channel(lesson_id(lesson)).0)
+}
+
+fn lesson_id(id: u64) -> LessonID {
+    LessonID::from_str(&id.to_string()).unwrap()
+}
+
+fn credentials(username: &str) -> LoginCredentials {
+    LoginCredentials::new(
+        Username::from_str(username).unwrap(),
+        Password::from_str("password").unwrap(),
+        LoginMethod::default(),
+    )
+}
+
+#[tokio::test]
+async fn enroll_when_the_enrollment_opens_after_being_rate_limited_twice() {
+    let server = MockServer::start().await;
+    let telegram = MockTelegram::start().await;
+    server.add_user("opening", "password");
+    // The server clock is estimated before enrolling, which takes a few seconds
+    server.add_lesson(
+        MockLesson::new(101)
+            .enrollment_opens_in(Duration::from_secs(6))
+            .enroll_responses([StatusCode::TOO_MANY_REQUESTS, StatusCode::TOO_MANY_REQUESTS]),
+    );
+    let opens = Local::now().fixed_offset() + chrono::Duration::seconds(6);
+    let cx = job_cx(&telegram, config(&server), 101);
+
+    let status = job_fns::enroll(&cx, lesson_id(101), credentials("opening"))
+        .await
+        .unwrap();
+
+    assert!(matches!(status, ExistStatus::Success(_)), "{:?}", status);
+    assert_eq!(server.enrolled(101), ["opening"]);
+    // Attempts shortly before the opening are rejected and don't count
+    let attempts = server.enroll_attempts(101);
+    let after_opening: Vec<_> = attempts
+        .iter()
+        .filter(|attempt| attempt.status != StatusCode::UNPROCESSABLE_ENTITY)
+        .collect();
+    assert!(after_opening
+        .iter()
+        .all(|attempt| attempt.at >= opens - chrono::Duration::milliseconds(50)));
+    let statuses: Vec<_> = after_opening.iter().map(|attempt| attempt.status).collect();
+    assert_eq!(
+        statuses,
+        [
+            StatusCode::TOO_MANY_REQUESTS,
+            StatusCode::TOO_MANY_REQUESTS,
+            StatusCode::CREATED
+        ]
+    );
+    assert!(attempts[0].at > opens - chrono::Duration::seconds(1));
+    assert!(attempts.last().unwrap().at - opens < chrono::Duration::seconds(2));
+    assert!(telegram.messages()[0].starts_with("[101] I will enroll you in"));
+}
+
+#[tokio::test]
+async fn notify_when_a_place_frees_after_three_polls() {
+    let server = MockServer::start().await;
+    let telegram = MockTelegram::start().await;
+    // The job fetches the lesson once itself before the watcher polls it
+    server.add_lesson(
+        MockLesson::new(102)
+            .places(20)
+            .participants([20, 20, 20, 19]),
+    );
+    let cx = job_cx(&telegram, config(&server), 102);
+
+    let status = job_fns::notify(&cx, lesson_id(102)).await.unwrap();
+
+    match status {
+        ExistStatus::Success(msg) => assert_eq!(msg, "There are currently 1 free spots."),
+        status => panic!("Unexpected status {:?}", status),
+    }
+    assert_eq!(server.lesson_polls(102), 4);
+    assert_eq!(
+        telegram.messages(),
+        ["[102] This lesson is already full. I will notify you, when a spot opens up."]
+    );
+}
+
+#[tokio::test]
+async fn enroll_when_a_place_frees_after_three_polls() {
+    let server = MockServer::start().await;
+    let telegram = MockTelegram::start().await;
+    server.add_user("waiting", "password");
+    server.add_lesson(
+        MockLesson::new(103)
+            .places(20)
+            .participants([20, 20, 20, 19]),
+    );
+    let cx = job_cx(&telegram, config(&server), 103);
+
+    let status = job_fns::enroll(&cx, lesson_id(103), credentials("waiting"))
+        .await
+        .unwrap();
+
+    assert!(matches!(status, ExistStatus::Success(_)), "{:?}", status);
+    assert_eq!(server.enrolled(103), ["waiting"]);
+    let statuses: Vec<_> = server
+        .enroll_attempts(103)
+        .into_iter()
+        .map(|attempt| attempt.status)
+        .collect();
+    assert_eq!(
+        statuses,
+        [StatusCode::UNPROCESSABLE_ENTITY, StatusCode::CREATED]
+    );
+    assert_eq!(
+        telegram.messages(),
+        ["[103] It's already full. I will try to enroll you, when something opens up"]
+    );
+}
+
+#[tokio::test]
+async fn enroll_with_a_new_token_after_the_cached_one_was_rejected() {
+    let server = MockServer::start().await;
+    let telegram = MockTelegram::start().await;
+    server.add_user("stale", "password");
+    server.add_lesson(MockLesson::new(105));
+    let config = config(&server);
+    // Another job left a token in the cache, which the server no longer accepts
+    let session = SESSIONS.session(&credentials("stale"), &config);
+    let stale = session.token().await.unwrap();
+    server.expire_tokens();
+    let cx = job_cx(&telegram, config, 105);
+
+    let status = job_fns::enroll(&cx, lesson_id(105), credentials("stale"))
+        .await
+        .unwrap();
+
+    assert!(matches!(status, ExistStatus::Success(_)), "{:?}", status);
+    assert_eq!(server.enrolled(105), ["stale"]);
+    let statuses: Vec<_> = server
+        .enroll_attempts(105)
+        .into_iter()
+        .map(|attempt| attempt.status)
+        .collect();
+    assert_eq!(statuses, [StatusCode::UNAUTHORIZED, StatusCode::CREATED]);
+    assert_ne!(session.token().await.unwrap(), stale);
+}
+
+#[tokio::test]
+async fn enroll_ends_right_away_when_the_membership_expired() {
+    let server = MockServer::start().await;
+    let telegram = MockTelegram::start().await;
+    server.add_user("expired", "password");
+    server.expire_membership("expired");
+    server.add_lesson(MockLesson::new(106));
+    let cx = job_cx(&telegram, config(&server), 106);
+
+    let status = job_fns::enroll(&cx, lesson_id(106), credentials("expired"))
+        .await
+        .unwrap();
+
+    match status {
+        ExistStatus::Failure(msg) => assert_eq!(
+            msg,
+            "Your ASVZ membership expired, please renew it to enroll"
+        ),
+        status => panic!("Unexpected status {:?}", status),
+    }
+    assert_eq!(server.enroll_attempts(106).len(), 1);
+}
+
+#[tokio::test]
+async fn enroll_ends_right_away_when_already_enrolled() {
+    let server = MockServer::start().await;
+    let telegram = MockTelegram::start().await;
+    server.add_user("enrolled", "password");
+    server.add_lesson(MockLesson::new(107));
+    let cx = job_cx(&telegram, config(&server), 107);
+    job_fns::enroll(&cx, lesson_id(107), credentials("enrolled"))
+        .await
+        .unwrap();
+
+    let status = job_fns::enroll(&cx, lesson_id(107), credentials("enrolled"))
+        .await
+        .unwrap();
+
+    match status {
+        ExistStatus::Success(msg) => assert_eq!(msg, "You are already enrolled in this lesson"),
+        status => panic!("Unexpected status {:?}", status),
+    }
+    let statuses: Vec<_> = server
+        .enroll_attempts(107)
+        .into_iter()
+        .map(|attempt| attempt.status)
+        .collect();
+    assert_eq!(
+        statuses,
+        [StatusCode::CREATED, StatusCode::UNPROCESSABLE_ENTITY]
+    );
+}
+
+#[tokio::test]
+async fn unknown_registration_type_does_not_end_the_job() {
+    let server = MockServer::start().await;
+    let telegram = MockTelegram::start().await;
+    server.add_lesson(MockLesson::new(104).registration(RegistrationType::Unknown(8)));
+    let cx = job_cx(&telegram, config(&server), 104);
+
+    let status = job_fns::notify(&cx, lesson_id(104)).await.unwrap();
+
+    match status {
+        ExistStatus::Success(msg) => assert_eq!(msg, "There are currently 15 free spots."),
+        status => panic!("Unexpected status {:?}", status),
+    }
+}
+
+#[tokio::test]
+async fn enroll_ends_right_away_when_registration_is_on_site() {
+    let server = MockServer::start().await;
+    let telegram = MockTelegram::start().await;
+    server.add_user("onsite", "password");
+    server.add_lesson(MockLesson::new(108).registration(RegistrationType::OnSite));
+    let cx = job_cx(&telegram, config(&server), 108);
+
+    let status = job_fns::enroll(&cx, lesson_id(108), credentials("onsite"))
+        .await
+        .unwrap();
+
+    match status {
+        ExistStatus::Failure(msg) => assert_eq!(
+            msg,
+            "This lesson only takes registrations on site, I can't enroll you."
+        ),
+        status => panic!("Unexpected status {:?}", status),
+    }
+    assert!(server.enroll_attempts(108).is_empty());
+}
+
+#[tokio::test]
+async fn notify_ends_right_away_when_no_registration_is_needed() {
+    let server = MockServer::start().await;
+    let telegram = MockTelegram::start().await;
+    server.add_lesson(MockLesson::new(109).registration(RegistrationType::NotRequired));
+    let cx = job_cx(&telegram, config(&server), 109);
+
+    let status = job_fns::notify(&cx, lesson_id(109)).await.unwrap();
+
+    match status {
+        ExistStatus::Success(msg) => assert_eq!(
+            msg,
+            "This lesson needs no registration, you can just show up."
+        ),
+        status => panic!("Unexpected status {:?}", status),
+    }
+}
+
+#[tokio::test]
+async fn search_skips_lessons_in_other_languages_before_limiting_the_results() {
+    let server = MockServer::start().await;
+    let telegram = MockTelegram::start().await;
+    for (id, language) in [
+        (110, "de"),
+        (111, "de"),
+        (112, "en"),
+        (113, "en"),
+        (114, "en"),
+    ] {
+        server.add_lesson(
+            MockLesson::new(id)
+                .sport(2, "Yoga")
+                .language(language)
+                .starts_in(Duration::from_secs(60 * 60 * id)),
+        );
+    }
+    let mut config = (*config(&server)).clone();
+    config.jobs.max_search_results = 2;
+    let settings = Settings {
+        lesson_language: LessonLanguage::English,
+        ..Settings::new()
+    };
+    let bot = Bot::new("token").set_api_url(telegram.url());
+    let bot = BotCtx::new(bot, Arc::new(config), ChatId(1), MessageId(1))
+        .with_settings(watch::channel(settings).1);
+
+    job_fns::search(&bot, "Yoga".to_string(), None)
+        .await
+        .unwrap();
+
+    let messages = telegram.messages();
+    let text = messages.last().unwrap();
+    assert!(
+        text.starts_with("Found these Yoga lessons in English:"),
+        "{}",
+        text
+    );
+    let shown: Vec<_> = [110, 111, 112, 113, 114]
+        .into_iter()
+        .filter(|id| text.contains(&format!("\n{}: ", id)))
+        .collect();
+    assert_eq!(shown, [112, 113]);
+    assert_eq!(server.lesson_polls(114), 0);
+}
@@ -10,6 +10,7 @@ pub struct Command {
     pub description: Option<String>,
     pub parser: Option<ParserType>,
     pub name: String,
+    #[allow(dead_code)]
     pub renamed: bool,
 }
 